@@ -9,6 +9,7 @@ mod tests {
     use std::{
         ffi::{c_char, c_int, c_void, CStr, CString},
         ptr,
+        sync::atomic::{AtomicUsize, Ordering},
         thread::sleep,
         time::Duration,
     };
@@ -30,6 +31,20 @@ mod tests {
         );
     }
 
+    unsafe extern "C" fn on_record(_: *const c_char, bufsize: usize, userdata: *mut c_void) {
+        (*(userdata as *const AtomicUsize)).fetch_add(bufsize, Ordering::Relaxed);
+    }
+
+    unsafe extern "C" fn on_playback(
+        buf: *mut c_char,
+        bufsize: usize,
+        userdata: *mut c_void,
+    ) -> usize {
+        ptr::write_bytes(buf, 0, bufsize);
+        (*(userdata as *const AtomicUsize)).fetch_add(bufsize, Ordering::Relaxed);
+        bufsize
+    }
+
     #[test]
     fn start_stop_stream() {
         unsafe {
@@ -45,6 +60,8 @@ mod tests {
 
             let record_name = CString::new("record-test").unwrap();
             let playback_name = CString::new("playback-test").unwrap();
+            let recorded = AtomicUsize::new(0);
+            let played = AtomicUsize::new(0);
 
             assert_aw_result(aw_initialize());
             assert_aw_result(aw_start_record(
@@ -53,7 +70,8 @@ mod tests {
                 record_name.as_ptr(),
                 config,
                 Some(on_error),
-                ptr::null_mut(),
+                Some(on_record),
+                &recorded as *const AtomicUsize as *mut c_void,
             ));
             assert_aw_result(aw_start_playback(
                 &mut playback,
@@ -61,7 +79,8 @@ mod tests {
                 playback_name.as_ptr(),
                 config,
                 Some(on_error),
-                ptr::null_mut(),
+                Some(on_playback),
+                &played as *const AtomicUsize as *mut c_void,
             ));
 
             assert!(!aw_device_name(record).is_null());
@@ -70,17 +89,9 @@ mod tests {
             assert!(!aw_device_name(playback).is_null());
             assert!(aw_sample_rate(playback) > 0);
 
-            let mut buf_arr = [0u8; 65536];
-            let bufsize = buf_arr.len();
-            let buf = buf_arr.as_mut_ptr() as *mut c_char;
-
-            loop {
+            // Audio is only handed over from the audio threads
+            while recorded.load(Ordering::Relaxed) == 0 || played.load(Ordering::Relaxed) == 0 {
                 sleep(Duration::from_millis(20));
-                let read = aw_record_read(record, buf, bufsize);
-                if read > 0 {
-                    assert_eq!(aw_playback_write(playback, buf, read), read);
-                    break;
-                }
             }
 
             assert_aw_result(aw_stop(playback));
@@ -88,4 +99,27 @@ mod tests {
             assert_aw_result(aw_terminate());
         }
     }
+
+    #[test]
+    fn list_devices() {
+        unsafe {
+            let mut list = aw_device_list {
+                devices: ptr::null_mut(),
+                count: 0,
+            };
+            assert_aw_result(aw_initialize());
+            assert_aw_result(aw_list_devices(&mut list));
+            for i in 0..list.count {
+                let info = &*list.devices.add(i);
+                assert!(!info.name.is_null());
+                assert!(!info.description.is_null());
+                assert!(info.channels > 0);
+                assert!(info.sample_rate > 0);
+            }
+            aw_free_devices(&mut list);
+            assert!(list.devices.is_null());
+            assert_eq!(list.count, 0);
+            assert_aw_result(aw_terminate());
+        }
+    }
 }
@@ -1,2 +1 @@
 #include "../libaudiowire/include/audiowire.h"
-#include "../libaudiowire/include/ringbuf.h"
\ No newline at end of file
@@ -0,0 +1,55 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::Notify;
+
+use super::{config::Config, file, memory, native, result::Result, stream::ErrorHandler};
+
+pub(crate) trait Backend: Sync {
+    fn start_record(
+        &self,
+        name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>>;
+
+    fn start_playback(
+        &self,
+        name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>>;
+}
+
+pub(crate) trait BackendStream: Send + Sync {
+    fn capacity(&self) -> usize;
+    fn device_name(&self) -> Option<&str>;
+    fn sample_rate(&self) -> u32;
+    fn record_peek(&self) -> usize;
+    fn record_read(&mut self, buf: &mut [u8]) -> usize;
+    fn playback_peek(&self) -> usize;
+    fn playback_write(&mut self, buf: &[u8]) -> usize;
+    fn stop(&mut self) -> Result<()>;
+
+    // Streams paced by a clock rather than an audio thread have nobody to
+    // notify them, they report how long until the next period is due instead.
+    #[inline]
+    fn next_period(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Device names carrying a known prefix are routed to the matching backend with
+// the prefix stripped, everything else goes to libaudiowire as-is.
+pub(crate) fn find_backend(device: Option<&str>) -> (&'static dyn Backend, Option<&str>) {
+    if let Some(name) = device.and_then(|s| s.strip_prefix(memory::DEVICE_PREFIX)) {
+        (&memory::MemoryBackend, Some(name))
+    } else if let Some(path) = device.and_then(|s| s.strip_prefix(file::DEVICE_PREFIX)) {
+        (&file::FileBackend, Some(path))
+    } else {
+        (&native::NativeBackend, device)
+    }
+}
@@ -1,20 +1,137 @@
-use std::time::Duration;
+use std::{fmt::Display, str::FromStr, time::Duration};
 
 use audiowire_sys::{
-    aw_config, aw_sample_format_AW_SAMPLE_FORMAT_F32, aw_sample_format_AW_SAMPLE_FORMAT_S16,
-    aw_sample_size,
+    aw_config, aw_sample_format_AW_SAMPLE_FORMAT_F32, aw_sample_format_AW_SAMPLE_FORMAT_F64,
+    aw_sample_format_AW_SAMPLE_FORMAT_S16, aw_sample_format_AW_SAMPLE_FORMAT_S24,
+    aw_sample_format_AW_SAMPLE_FORMAT_S24_32, aw_sample_format_AW_SAMPLE_FORMAT_S32,
+    aw_sample_format_AW_SAMPLE_FORMAT_U8, aw_sample_size,
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy)]
+// Samples are little-endian whatever the format. S24 is packed into 3 bytes,
+// S24_32 is a 24-bit sample in the low 3 bytes of 4.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SampleFormat {
     S16 = aw_sample_format_AW_SAMPLE_FORMAT_S16 as isize,
     F32 = aw_sample_format_AW_SAMPLE_FORMAT_F32 as isize,
+    S24 = aw_sample_format_AW_SAMPLE_FORMAT_S24 as isize,
+    S24_32 = aw_sample_format_AW_SAMPLE_FORMAT_S24_32 as isize,
+    S32 = aw_sample_format_AW_SAMPLE_FORMAT_S32 as isize,
+    U8 = aw_sample_format_AW_SAMPLE_FORMAT_U8 as isize,
+    F64 = aw_sample_format_AW_SAMPLE_FORMAT_F64 as isize,
 }
 
+pub const SAMPLE_FORMATS: [SampleFormat; 7] = [
+    SampleFormat::S16,
+    SampleFormat::F32,
+    SampleFormat::S24,
+    SampleFormat::S24_32,
+    SampleFormat::S32,
+    SampleFormat::U8,
+    SampleFormat::F64,
+];
+
 impl SampleFormat {
     pub fn size(self) -> usize {
         unsafe { aw_sample_size(self as u32) }
     }
+
+    #[inline]
+    pub fn from_u8(value: u8) -> Option<Self> {
+        SAMPLE_FORMATS
+            .into_iter()
+            .find(|&format| format as u8 == value)
+    }
+
+    #[inline]
+    pub fn is_float(self) -> bool {
+        matches!(self, Self::F32 | Self::F64)
+    }
+
+    // Decodes one sample into a float within [-1, 1]
+    #[inline]
+    pub fn decode(self, bytes: &[u8]) -> f32 {
+        match self {
+            Self::S16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            Self::F32 => f32::from_le_bytes(bytes.try_into().unwrap()),
+            // Shifted up to the top of an i32 so that the sign comes along
+            Self::S24 => {
+                i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2147483648.0
+            }
+            Self::S24_32 => {
+                i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2147483648.0
+            }
+            Self::S32 => {
+                (i32::from_le_bytes(bytes.try_into().unwrap()) as f64 / 2147483648.0) as f32
+            }
+            Self::U8 => (bytes[0] as f32 - 128.0) / 128.0,
+            Self::F64 => f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+        }
+    }
+
+    // Encodes one sample, values past full scale are clipped
+    #[inline]
+    pub fn encode(self, value: f32, dst: &mut [u8]) {
+        let value = value.clamp(-1.0, 1.0);
+        let integer = |bits: i32| {
+            let scale = (1i64 << (bits - 1)) as f64;
+            (value as f64 * scale).round().clamp(-scale, scale - 1.0) as i32
+        };
+        match self {
+            Self::S16 => dst.copy_from_slice(&(integer(16) as i16).to_le_bytes()),
+            Self::F32 => dst.copy_from_slice(&value.to_le_bytes()),
+            Self::S24 => dst.copy_from_slice(&integer(24).to_le_bytes()[..3]),
+            Self::S24_32 => dst.copy_from_slice(&integer(24).to_le_bytes()),
+            Self::S32 => dst.copy_from_slice(&integer(32).to_le_bytes()),
+            Self::U8 => dst[0] = (integer(8) + 128) as u8,
+            Self::F64 => dst.copy_from_slice(&(value as f64).to_le_bytes()),
+        }
+    }
+
+    // Appends the input samples converted to another format, a trailing
+    // partial sample is ignored
+    pub fn convert(self, to: SampleFormat, input: &[u8], output: &mut Vec<u8>) {
+        let (from_size, to_size) = (self.size(), to.size());
+        let samples = input.len() / from_size;
+        if self == to {
+            output.extend_from_slice(&input[..samples * from_size]);
+            return;
+        }
+        let start = output.len();
+        output.resize(start + samples * to_size, 0);
+        for (src, dst) in input
+            .chunks_exact(from_size)
+            .zip(output[start..].chunks_exact_mut(to_size))
+        {
+            to.encode(self.decode(src), dst);
+        }
+    }
+}
+
+impl FromStr for SampleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SAMPLE_FORMATS
+            .into_iter()
+            .find(|format| format.to_string() == s)
+            .ok_or_else(|| format!("Unknown sample format: {}", s))
+    }
+}
+
+impl Display for SampleFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::S16 => write!(f, "s16"),
+            Self::F32 => write!(f, "f32"),
+            Self::S24 => write!(f, "s24"),
+            Self::S24_32 => write!(f, "s24_32"),
+            Self::S32 => write!(f, "s32"),
+            Self::U8 => write!(f, "u8"),
+            Self::F64 => write!(f, "f64"),
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -24,6 +141,9 @@ pub struct Config {
     pub sample_format: SampleFormat,
     pub buffer_frames: usize,
     pub max_buffer_frames: usize,
+    // Rate devices are run at when it differs from sample_rate, streams
+    // resample between the two
+    pub device_rate: Option<u32>,
 }
 
 impl Config {
@@ -52,6 +172,28 @@ impl Config {
         self.frame_count_to_duration(self.max_buffer_frames)
     }
 
+    // Playing time of that many bytes, not rounded like the buffer durations
+    #[inline]
+    pub fn bytes_to_duration(&self, bytes: usize) -> Duration {
+        let frames = (bytes / self.frame_size()) as f64;
+        Duration::from_secs_f64(frames / self.sample_rate as f64)
+    }
+
+    // Same config at the device rate, buffers keep their duration
+    pub fn device_config(&self) -> Config {
+        let Some(rate) = self.device_rate.filter(|&rate| rate != self.sample_rate) else {
+            return *self;
+        };
+        let scale = |frames: usize| (frames as u64 * rate as u64).div_ceil(self.sample_rate as u64);
+        Config {
+            sample_rate: rate,
+            buffer_frames: scale(self.buffer_frames) as usize,
+            max_buffer_frames: scale(self.max_buffer_frames) as usize,
+            device_rate: None,
+            ..*self
+        }
+    }
+
     #[inline]
     fn frame_count_to_duration(&self, count: usize) -> Duration {
         let ms = count * 1000 / (self.sample_rate as usize);
@@ -59,14 +201,73 @@ impl Config {
     }
 }
 
-impl Into<aw_config> for Config {
-    fn into(self) -> aw_config {
+impl From<Config> for aw_config {
+    fn from(value: Config) -> Self {
         aw_config {
-            channels: self.channels,
-            sample_rate: self.sample_rate,
-            sample_format: self.sample_format as u32,
-            buffer_frames: self.buffer_frames as u32,
-            max_buffer_frames: self.max_buffer_frames as u32,
+            channels: value.channels,
+            sample_rate: value.sample_rate,
+            sample_format: value.sample_format as u32,
+            buffer_frames: value.buffer_frames as u32,
+            max_buffer_frames: value.max_buffer_frames as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_wire_values() {
+        for format in SAMPLE_FORMATS {
+            assert_eq!(format.to_string().parse::<SampleFormat>(), Ok(format));
+            assert_eq!(SampleFormat::from_u8(format as u8), Some(format));
+        }
+        assert!("s8".parse::<SampleFormat>().is_err());
+        assert_eq!(SampleFormat::from_u8(SAMPLE_FORMATS.len() as u8), None);
+    }
+
+    #[test]
+    fn sizes() {
+        let sizes: Vec<_> = SAMPLE_FORMATS.iter().map(|format| format.size()).collect();
+        assert_eq!(sizes, [2, 4, 3, 4, 4, 1, 8]);
+    }
+
+    #[test]
+    fn full_scale_and_silence() {
+        let encode = |format: SampleFormat, value: f32| {
+            let mut dst = vec![0u8; format.size()];
+            format.encode(value, &mut dst);
+            dst
+        };
+        assert_eq!(encode(SampleFormat::S16, 1.0), [0xff, 0x7f]);
+        assert_eq!(encode(SampleFormat::S24, -1.0), [0x00, 0x00, 0x80]);
+        assert_eq!(encode(SampleFormat::S24, 1.0), [0xff, 0xff, 0x7f]);
+        assert_eq!(encode(SampleFormat::S24_32, -1.0), [0x00, 0x00, 0x80, 0xff]);
+        assert_eq!(encode(SampleFormat::S32, 1.0), [0xff, 0xff, 0xff, 0x7f]);
+        assert_eq!(encode(SampleFormat::U8, 0.0), [0x80]);
+        assert_eq!(encode(SampleFormat::U8, -1.0), [0x00]);
+        assert_eq!(encode(SampleFormat::U8, 2.0), [0xff]);
+
+        assert_eq!(SampleFormat::S24.decode(&[0x00, 0x00, 0x80]), -1.0);
+        assert_eq!(SampleFormat::S24_32.decode(&[0x00, 0x00, 0xc0, 0xff]), -0.5);
+        assert_eq!(SampleFormat::U8.decode(&[0xc0]), 0.5);
+    }
+
+    #[test]
+    fn converts_between_every_format() {
+        let values = [0.0, 0.5, -0.5, 0.25, -1.0];
+        let mut source = vec![0u8; values.len() * 4];
+        for (value, dst) in values.iter().zip(source.chunks_exact_mut(4)) {
+            SampleFormat::F32.encode(*value, dst);
+        }
+        for format in SAMPLE_FORMATS {
+            let mut converted = Vec::new();
+            SampleFormat::F32.convert(format, &source, &mut converted);
+            assert_eq!(converted.len(), values.len() * format.size());
+            let mut back = Vec::new();
+            format.convert(SampleFormat::F32, &converted, &mut back);
+            assert_eq!(back, source, "{}", format);
         }
     }
 }
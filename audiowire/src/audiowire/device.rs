@@ -0,0 +1,98 @@
+use std::{
+    ffi::{c_char, CStr},
+    fmt::Display,
+    ptr, slice,
+};
+
+use audiowire_sys::{
+    aw_device_direction_AW_DEVICE_DIRECTION_PLAYBACK,
+    aw_device_direction_AW_DEVICE_DIRECTION_RECORD, aw_device_info, aw_device_list,
+    aw_free_devices, aw_list_devices,
+};
+
+use super::result::{parse_result, Result};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceDirection {
+    Record = aw_device_direction_AW_DEVICE_DIRECTION_RECORD as isize,
+    Playback = aw_device_direction_AW_DEVICE_DIRECTION_PLAYBACK as isize,
+}
+
+impl Display for DeviceDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Record => f.pad("record"),
+            Self::Playback => f.pad("playback"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    // What to pass as the device to open it
+    pub name: String,
+    pub description: String,
+    pub direction: DeviceDirection,
+    pub is_default: bool,
+    pub channels: u8,
+    // Rate the device runs at natively
+    pub sample_rate: u32,
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<8} {} ({}, {} ch, {} Hz){}",
+            self.direction,
+            self.name,
+            self.description,
+            self.channels,
+            self.sample_rate,
+            if self.is_default { " [default]" } else { "" }
+        )
+    }
+}
+
+impl From<&aw_device_info> for DeviceInfo {
+    fn from(info: &aw_device_info) -> Self {
+        let string = |s: *const c_char| unsafe {
+            if s.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(s).to_string_lossy().to_string()
+            }
+        };
+        Self {
+            name: string(info.name),
+            description: string(info.description),
+            direction: if info.direction == aw_device_direction_AW_DEVICE_DIRECTION_RECORD {
+                DeviceDirection::Record
+            } else {
+                DeviceDirection::Playback
+            },
+            is_default: info.is_default != 0,
+            channels: info.channels,
+            sample_rate: info.sample_rate,
+        }
+    }
+}
+
+// Lists the devices libaudiowire can open, record devices first
+pub fn devices() -> Result<Vec<DeviceInfo>> {
+    let mut list = aw_device_list {
+        devices: ptr::null_mut(),
+        count: 0,
+    };
+    parse_result(unsafe { aw_list_devices(&mut list) })?;
+    let devices = if list.devices.is_null() {
+        Vec::new()
+    } else {
+        unsafe { slice::from_raw_parts(list.devices, list.count) }
+            .iter()
+            .map(DeviceInfo::from)
+            .collect()
+    };
+    unsafe { aw_free_devices(&mut list) };
+    Ok(devices)
+}
@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{fmt::Display, io};
 
 #[derive(Debug)]
 pub struct Error {
@@ -23,3 +23,9 @@ impl Error {
         Self { code, message }
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::new(value.raw_os_error().unwrap_or(-1), Some(value.to_string()))
+    }
+}
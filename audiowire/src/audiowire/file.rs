@@ -0,0 +1,406 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use tokio::sync::Notify;
+
+use super::{
+    backend::{Backend, BackendStream},
+    config::{Config, SampleFormat},
+    errors::Error,
+    paced::{PacedPlaybackStream, PacedRecordStream, Sink, Source},
+    result::Result,
+    stream::ErrorHandler,
+};
+
+pub const DEVICE_PREFIX: &str = "file:";
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// Size of a WAVE_FORMAT_EXTENSIBLE fmt chunk, the largest there is
+const MAX_FMT_SIZE: u32 = 40;
+
+const HEADER_SIZE: u32 = 44;
+
+pub(crate) struct FileBackend;
+
+impl Backend for FileBackend {
+    fn start_record(
+        &self,
+        _name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        _notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>> {
+        let path = device.unwrap_or_default();
+        let reader = WavReader::open(path, &config)?;
+        let stream = PacedRecordStream::new(path, reader, config, error_handler);
+        Ok(Box::new(stream))
+    }
+
+    fn start_playback(
+        &self,
+        _name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        _notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>> {
+        let path = device.unwrap_or_default();
+        let writer = WavWriter::create(path, &config)?;
+        let stream = PacedPlaybackStream::new(path, writer, config, error_handler);
+        Ok(Box::new(stream))
+    }
+}
+
+// WAV has no plain way to tell 24 bits padded to 32 from 32 bits
+fn format_tag(format: SampleFormat) -> Result<u16> {
+    match format {
+        SampleFormat::S24_32 => Err(Error::new(
+            -1,
+            Some(format!(
+                "Sample format {} can't be stored in WAV files",
+                format
+            )),
+        )),
+        _ if format.is_float() => Ok(WAVE_FORMAT_IEEE_FLOAT),
+        _ => Ok(WAVE_FORMAT_PCM),
+    }
+}
+
+fn invalid_file(message: &str) -> Error {
+    Error::new(-1, Some(format!("Invalid WAV file: {}", message)))
+}
+
+struct WavReader {
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+impl WavReader {
+    fn open(path: &str, config: &Config) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+            return Err(invalid_file("missing RIFF/WAVE header"));
+        }
+
+        let mut fmt_found = false;
+        loop {
+            let mut chunk = [0u8; 8];
+            reader.read_exact(&mut chunk)?;
+            let size = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+            match &chunk[..4] {
+                b"fmt " => {
+                    if size > MAX_FMT_SIZE {
+                        return Err(invalid_file("fmt chunk is too long"));
+                    }
+                    let mut fmt = vec![0u8; size as usize];
+                    reader.read_exact(&mut fmt)?;
+                    Self::check_format(&fmt, config)?;
+                    fmt_found = true;
+                }
+                b"data" if fmt_found => {
+                    return Ok(Self {
+                        reader,
+                        remaining: size as u64,
+                    })
+                }
+                b"data" => return Err(invalid_file("data chunk before fmt chunk")),
+                _ => {
+                    reader.seek_relative(size as i64)?;
+                }
+            }
+            // Chunks are padded to an even size
+            if size % 2 != 0 {
+                reader.seek_relative(1)?;
+            }
+        }
+    }
+
+    fn check_format(fmt: &[u8], config: &Config) -> Result<()> {
+        if fmt.len() < 16 {
+            return Err(invalid_file("fmt chunk is too short"));
+        }
+        let read_u16 = |off: usize| u16::from_le_bytes(fmt[off..off + 2].try_into().unwrap());
+        let read_u32 = |off: usize| u32::from_le_bytes(fmt[off..off + 4].try_into().unwrap());
+
+        let mut tag = read_u16(0);
+        if tag == WAVE_FORMAT_EXTENSIBLE && fmt.len() >= 26 {
+            // The first two bytes of the sub-format GUID hold the actual format tag
+            tag = read_u16(24);
+        }
+        let channels = read_u16(2);
+        let sample_rate = read_u32(4);
+        let bits = read_u16(14) as usize;
+
+        let format = config.sample_format;
+        let expected = format_tag(format)?;
+        if tag != expected || bits != format.size() * 8 {
+            return Err(invalid_file(&format!(
+                "expected format tag {} with {} bits per sample, got format tag {} with {} bits per sample",
+                expected,
+                format.size() * 8,
+                tag,
+                bits
+            )));
+        }
+        if channels != config.channels as u16 || sample_rate != config.sample_rate {
+            return Err(invalid_file(&format!(
+                "expected {} channel(s) at {} Hz, got {} channel(s) at {} Hz",
+                config.channels, config.sample_rate, channels, sample_rate
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Source for WavReader {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = buf.len().min(self.remaining as usize);
+        let mut off = 0;
+        while off < length {
+            match self.reader.read(&mut buf[off..length])? {
+                0 => break,
+                read => off += read,
+            }
+        }
+        self.remaining -= off as u64;
+        Ok(off)
+    }
+
+    #[inline]
+    fn remaining(&self) -> Option<usize> {
+        Some(self.remaining as usize)
+    }
+}
+
+struct WavWriter {
+    writer: BufWriter<File>,
+    data_size: u64,
+    // Most data the 32 bit chunk sizes can describe, in whole frames
+    max_data_size: u64,
+    full: bool,
+}
+
+impl WavWriter {
+    fn create(path: &str, config: &Config) -> Result<Self> {
+        let tag = format_tag(config.sample_format)?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        let channels = config.channels as u16;
+        let block_align = config.frame_size() as u16;
+        let bits = (config.sample_format.size() * 8) as u16;
+
+        // Chunk sizes are left empty until the writer is finished
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&tag.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&config.sample_rate.to_le_bytes())?;
+        writer.write_all(&(config.sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        let max_data_size = (u32::MAX - (HEADER_SIZE - 8)) as u64;
+        Ok(Self {
+            writer,
+            data_size: 0,
+            max_data_size: max_data_size - max_data_size % block_align as u64,
+            full: false,
+        })
+    }
+}
+
+impl Sink for WavWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let room = (self.max_data_size - self.data_size) as usize;
+        let length = buf.len().min(room);
+        self.writer.write_all(&buf[..length])?;
+        self.data_size += length as u64;
+        if length == buf.len() || self.full {
+            return Ok(());
+        }
+        // Said once, everything after is dropped the same way
+        self.full = true;
+        Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            "WAV file reached its size limit, dropping the rest",
+        ))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Both fit, the data never grows past max_data_size
+        let data_size = self.data_size as u32;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.writer.write_all(&data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, thread::sleep};
+
+    use crate::{PlaybackStream, RecordStream, Stream};
+
+    use super::*;
+
+    const CONFIG: Config = Config {
+        channels: 2,
+        sample_rate: 48000,
+        sample_format: SampleFormat::F32,
+        buffer_frames: 480,
+        max_buffer_frames: 4800,
+        device_rate: None,
+    };
+
+    fn temp_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("audiowire-{}-{}.wav", process::id(), name));
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn stops_at_size_limit() {
+        let path = temp_path("size-limit");
+        let mut writer = WavWriter::create(&path, &CONFIG).unwrap();
+        assert_eq!(writer.max_data_size % CONFIG.frame_size() as u64, 0);
+        assert!(writer.max_data_size + (HEADER_SIZE - 8) as u64 <= u32::MAX as u64);
+
+        writer.max_data_size = 16;
+        writer.write(&[1; 12]).unwrap();
+        let err = writer.write(&[2; 12]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        writer.write(&[3; 12]).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), HEADER_SIZE as usize + 16);
+        assert_eq!(&written[4..8], &(HEADER_SIZE - 8 + 16).to_le_bytes());
+        assert_eq!(&written[40..44], &16u32.to_le_bytes());
+        // Whatever fit of the write that hit the limit is kept
+        assert_eq!(
+            written[HEADER_SIZE as usize..],
+            [[1u8; 12].as_slice(), &[2; 4]].concat()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_then_read() {
+        let path = temp_path("write-then-read");
+        let device = format!("file:{}", path);
+        let data: Vec<u8> = (0..CONFIG.buffer_size() * 3).map(|i| i as u8).collect();
+
+        let mut playback = PlaybackStream::start("playback-test", Some(&device), CONFIG)
+            .expect("Failed to start playback stream");
+        assert_eq!(playback.device_name(), Some(path.as_str()));
+        playback.write(&data);
+        playback.stop().unwrap();
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), HEADER_SIZE as usize + data.len());
+        assert_eq!(&written[HEADER_SIZE as usize..], data.as_slice());
+
+        let mut record = RecordStream::start("record-test", Some(&device), CONFIG)
+            .expect("Failed to start record stream");
+        let mut buf = vec![0u8; CONFIG.max_buffer_size()];
+        let mut read = Vec::new();
+        while read.len() < data.len() {
+            sleep(CONFIG.buffer_duration());
+            let length = record.read(&mut buf);
+            read.extend_from_slice(&buf[..length]);
+        }
+        assert_eq!(read, data);
+        sleep(CONFIG.buffer_duration() * 2);
+        assert_eq!(record.peek(), 0);
+        record.stop().unwrap();
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn format_mismatch() {
+        let path = temp_path("format-mismatch");
+        let device = format!("file:{}", path);
+        let mut playback = PlaybackStream::start("playback-test", Some(&device), CONFIG)
+            .expect("Failed to start playback stream");
+        playback.stop().unwrap();
+
+        let config = Config {
+            sample_format: SampleFormat::S16,
+            ..CONFIG
+        };
+        assert!(RecordStream::start("record-test", Some(&device), config).is_err());
+        let config = Config {
+            sample_rate: 44100,
+            ..CONFIG
+        };
+        assert!(RecordStream::start("record-test", Some(&device), config).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_oversized_fmt_chunk() {
+        let path = temp_path("oversized-fmt");
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        fs::write(&path, &header).unwrap();
+
+        let err = WavReader::open(&path, &CONFIG).err().unwrap();
+        assert!(err.to_string().contains("fmt chunk is too long"), "{}", err);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn integer_formats() {
+        let path = temp_path("integer-formats");
+        let device = format!("file:{}", path);
+        let config = Config {
+            sample_format: SampleFormat::S24,
+            ..CONFIG
+        };
+        let mut playback = PlaybackStream::start("playback-test", Some(&device), config)
+            .expect("Failed to start playback stream");
+        playback.stop().unwrap();
+
+        let written = fs::read(&path).unwrap();
+        let read_u16 = |off: usize| u16::from_le_bytes([written[off], written[off + 1]]);
+        assert_eq!(read_u16(20), WAVE_FORMAT_PCM);
+        assert_eq!(read_u16(32), 6);
+        assert_eq!(read_u16(34), 24);
+        let config32 = Config {
+            sample_format: SampleFormat::S32,
+            ..CONFIG
+        };
+        assert!(RecordStream::start("record-test", Some(&device), config32).is_err());
+        let mut record = RecordStream::start("record-test", Some(&device), config).unwrap();
+        record.stop().unwrap();
+
+        let config = Config {
+            sample_format: SampleFormat::S24_32,
+            ..CONFIG
+        };
+        assert!(PlaybackStream::start("playback-test", Some(&device), config).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
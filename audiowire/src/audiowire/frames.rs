@@ -0,0 +1,282 @@
+use std::{iter::StepBy, mem::size_of, slice::ChunksExact};
+
+use super::{config::SampleFormat, errors::Error, result::Result};
+
+// Sample is a type audio can be handled in directly, the sample format it
+// stands for is converted to and from without loss. Every other format goes
+// through a float and is rounded to the nearest value.
+pub trait Sample: Copy + Default + PartialEq + Send + Sync + 'static {
+    const FORMAT: SampleFormat;
+
+    fn from_le(bytes: &[u8]) -> Self;
+    fn write_le(self, dst: &mut [u8]);
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        let mut bytes = [0u8; 8];
+        let bytes = &mut bytes[..size_of::<Self>()];
+        Self::FORMAT.encode(value, bytes);
+        Self::from_le(bytes)
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        let mut bytes = [0u8; 8];
+        let bytes = &mut bytes[..size_of::<Self>()];
+        self.write_le(bytes);
+        Self::FORMAT.decode(bytes)
+    }
+
+    #[inline]
+    fn decode(format: SampleFormat, bytes: &[u8]) -> Self {
+        if format == Self::FORMAT {
+            Self::from_le(bytes)
+        } else {
+            Self::from_f32(format.decode(bytes))
+        }
+    }
+
+    #[inline]
+    fn encode(self, format: SampleFormat, dst: &mut [u8]) {
+        if format == Self::FORMAT {
+            self.write_le(dst)
+        } else {
+            format.encode(self.to_f32(), dst)
+        }
+    }
+}
+
+macro_rules! impl_sample {
+    ($type:ty, $format:ident) => {
+        impl Sample for $type {
+            const FORMAT: SampleFormat = SampleFormat::$format;
+
+            #[inline]
+            fn from_le(bytes: &[u8]) -> Self {
+                Self::from_le_bytes(bytes.try_into().unwrap())
+            }
+
+            #[inline]
+            fn write_le(self, dst: &mut [u8]) {
+                dst.copy_from_slice(&self.to_le_bytes())
+            }
+        }
+    };
+}
+
+impl_sample!(u8, U8);
+impl_sample!(i16, S16);
+impl_sample!(i32, S32);
+impl_sample!(f32, F32);
+impl_sample!(f64, F64);
+
+fn invalid_frames(message: String) -> Error {
+    Error::new(-1, Some(message))
+}
+
+// Frames holds whole frames of audio for a fixed number of channels. The
+// samples are stored interleaved the way devices and codecs take them, a
+// channel on its own can be looked at through the planar views.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Frames<S: Sample> {
+    channels: usize,
+    samples: Vec<S>,
+}
+
+impl<S: Sample> Frames<S> {
+    pub fn new(channels: u8) -> Self {
+        assert!(channels > 0, "Frames need at least one channel");
+        Self {
+            channels: channels as usize,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn silence(channels: u8, frames: usize) -> Self {
+        let mut result = Self::new(channels);
+        result.resize(frames);
+        result
+    }
+
+    pub fn from_interleaved(channels: u8, samples: Vec<S>) -> Result<Self> {
+        if channels == 0 || !samples.len().is_multiple_of(channels as usize) {
+            return Err(invalid_frames(format!(
+                "{} sample(s) don't make whole frames of {} channel(s)",
+                samples.len(),
+                channels
+            )));
+        }
+        Ok(Self {
+            channels: channels as usize,
+            samples,
+        })
+    }
+
+    pub fn from_planar(planes: &[&[S]]) -> Result<Self> {
+        let channels = u8::try_from(planes.len())
+            .ok()
+            .filter(|&channels| channels > 0)
+            .ok_or_else(|| invalid_frames(format!("{} planes", planes.len())))?;
+        let frames = planes[0].len();
+        if planes.iter().any(|plane| plane.len() != frames) {
+            return Err(invalid_frames("planes differ in length".to_owned()));
+        }
+        let mut result = Self::new(channels);
+        result
+            .samples
+            .extend((0..frames).flat_map(|i| planes.iter().map(move |plane| plane[i])));
+        Ok(result)
+    }
+
+    #[inline]
+    pub fn channels(&self) -> u8 {
+        self.channels as u8
+    }
+
+    // Number of frames, not samples
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    // New frames are silent
+    #[inline]
+    pub fn resize(&mut self, frames: usize) {
+        self.samples.resize(frames * self.channels, S::default());
+    }
+
+    #[inline]
+    pub fn interleaved(&self) -> &[S] {
+        &self.samples
+    }
+
+    #[inline]
+    pub fn interleaved_mut(&mut self) -> &mut [S] {
+        &mut self.samples
+    }
+
+    #[inline]
+    pub fn frames(&self) -> ChunksExact<'_, S> {
+        self.samples.chunks_exact(self.channels)
+    }
+
+    // Every sample of one channel, in order
+    #[inline]
+    pub fn channel(&self, index: usize) -> StepBy<std::slice::Iter<'_, S>> {
+        assert!(index < self.channels, "No channel {}", index);
+        self.samples[index..].iter().step_by(self.channels)
+    }
+
+    pub fn to_planar(&self) -> Vec<Vec<S>> {
+        (0..self.channels)
+            .map(|index| self.channel(index).copied().collect())
+            .collect()
+    }
+
+    // Replaces the frames with the ones encoded in bytes, which have to hold
+    // whole frames
+    pub fn decode(&mut self, format: SampleFormat, bytes: &[u8]) -> Result<()> {
+        let frame_size = self.channels * format.size();
+        if !bytes.len().is_multiple_of(frame_size) {
+            return Err(invalid_frames(format!(
+                "{} byte(s) don't make whole frames of {} channel(s) {}",
+                bytes.len(),
+                self.channels,
+                format
+            )));
+        }
+        self.samples.clear();
+        self.samples.extend(
+            bytes
+                .chunks_exact(format.size())
+                .map(|sample| S::decode(format, sample)),
+        );
+        Ok(())
+    }
+
+    // Appends the frames to the output in the given format
+    pub fn encode(&self, format: SampleFormat, output: &mut Vec<u8>) {
+        let size = format.size();
+        let start = output.len();
+        output.resize(start + self.samples.len() * size, 0);
+        for (sample, dst) in self
+            .samples
+            .iter()
+            .zip(output[start..].chunks_exact_mut(size))
+        {
+            sample.encode(format, dst);
+        }
+    }
+
+    pub fn convert<T: Sample>(&self) -> Frames<T> {
+        Frames {
+            channels: self.channels,
+            samples: self
+                .samples
+                .iter()
+                .map(|&sample| T::from_f32(sample.to_f32()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaved_and_planar() {
+        let frames = Frames::from_interleaved(2, vec![1i16, -1, 2, -2, 3, -3]).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames.frames().nth(1), Some(&[2i16, -2][..]));
+        assert_eq!(frames.channel(1).copied().collect::<Vec<_>>(), [-1, -2, -3]);
+        let planes = frames.to_planar();
+        assert_eq!(planes, [vec![1, 2, 3], vec![-1, -2, -3]]);
+
+        let planes: Vec<&[i16]> = planes.iter().map(Vec::as_slice).collect();
+        assert_eq!(Frames::from_planar(&planes).unwrap(), frames);
+    }
+
+    #[test]
+    fn rejects_partial_frames() {
+        assert!(Frames::from_interleaved(2, vec![0f32; 3]).is_err());
+        assert!(Frames::<f32>::from_interleaved(0, Vec::new()).is_err());
+        assert!(Frames::from_planar(&[&[0i16, 1][..], &[0][..]]).is_err());
+        assert!(Frames::<i16>::from_planar(&[]).is_err());
+
+        let mut frames = Frames::<i16>::new(2);
+        assert!(frames.decode(SampleFormat::S16, &[0; 6]).is_err());
+        assert!(frames.decode(SampleFormat::S24, &[0; 6]).is_ok());
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn decodes_any_format() {
+        let mut bytes = Vec::new();
+        let source = Frames::from_interleaved(1, vec![0.5f32, -0.25, 0.0]).unwrap();
+        source.encode(SampleFormat::S24, &mut bytes);
+        assert_eq!(bytes.len(), 9);
+
+        let mut frames = Frames::<i16>::new(1);
+        frames.decode(SampleFormat::S24, &bytes).unwrap();
+        assert_eq!(frames.interleaved(), [16384, -8192, 0]);
+        assert_eq!(frames.convert::<f32>(), source);
+
+        // The format of the sample type itself is taken as is
+        let mut frames = Frames::<i32>::new(1);
+        frames
+            .decode(SampleFormat::S32, &i32::MAX.to_le_bytes())
+            .unwrap();
+        assert_eq!(frames.interleaved(), [i32::MAX]);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use tokio::sync::Notify;
+
+use super::{
+    backend::{Backend, BackendStream},
+    config::Config,
+    errors::Error,
+    paced::{PacedPlaybackStream, PacedRecordStream, Sink, Source},
+    result::Result,
+    stream::ErrorHandler,
+};
+
+pub const DEVICE_PREFIX: &str = "memory:";
+
+type Generator = Box<dyn FnMut(&mut [u8]) + Send>;
+
+enum MemoryDevice {
+    Source(Arc<Mutex<Generator>>),
+    Sink(Arc<Mutex<Vec<u8>>>),
+}
+
+fn devices() -> &'static Mutex<HashMap<String, MemoryDevice>> {
+    static DEVICES: OnceLock<Mutex<HashMap<String, MemoryDevice>>> = OnceLock::new();
+    DEVICES.get_or_init(Default::default)
+}
+
+// Registers a record device whose samples are produced by the generator. The
+// generator is handed raw interleaved bytes in the stream's sample format and
+// is called at the pace of the configured sample rate, just like a sound card.
+pub fn add_source<F>(name: &str, generator: F)
+where
+    F: FnMut(&mut [u8]) + Send + 'static,
+{
+    let generator: Generator = Box::new(generator);
+    devices().lock().unwrap().insert(
+        name.to_owned(),
+        MemoryDevice::Source(Arc::new(Mutex::new(generator))),
+    );
+}
+
+// Registers a record device that plays the buffer once followed by silence.
+pub fn add_buffer_source(name: &str, data: Vec<u8>) {
+    let mut offset = 0;
+    add_source(name, move |buf| {
+        let length = buf.len().min(data.len() - offset);
+        buf[..length].copy_from_slice(&data[offset..offset + length]);
+        buf[length..].fill(0);
+        offset += length;
+    });
+}
+
+// Registers a playback device that captures everything written into it.
+pub fn add_sink(name: &str) -> MemorySink {
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    devices()
+        .lock()
+        .unwrap()
+        .insert(name.to_owned(), MemoryDevice::Sink(Arc::clone(&captured)));
+    MemorySink { captured }
+}
+
+pub fn remove_device(name: &str) -> bool {
+    devices().lock().unwrap().remove(name).is_some()
+}
+
+#[derive(Clone)]
+pub struct MemorySink {
+    captured: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemorySink {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.captured.lock().unwrap().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.captured.lock().unwrap().clone()
+    }
+
+    #[inline]
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.captured.lock().unwrap())
+    }
+}
+
+impl Source for Arc<Mutex<Generator>> {
+    #[inline]
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (self.lock().unwrap())(buf);
+        Ok(buf.len())
+    }
+}
+
+impl Sink for Arc<Mutex<Vec<u8>>> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.lock().unwrap().extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+fn device_not_found() -> Error {
+    Error::new(-1, Some("Device not found".to_owned()))
+}
+
+pub(crate) struct MemoryBackend;
+
+impl Backend for MemoryBackend {
+    fn start_record(
+        &self,
+        _name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        _notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>> {
+        let devname = device.unwrap_or_default();
+        let generator = match devices().lock().unwrap().get(devname) {
+            Some(MemoryDevice::Source(generator)) => Arc::clone(generator),
+            _ => return Err(device_not_found()),
+        };
+        let stream = PacedRecordStream::new(devname, generator, config, error_handler);
+        Ok(Box::new(stream))
+    }
+
+    fn start_playback(
+        &self,
+        _name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        _notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>> {
+        let devname = device.unwrap_or_default();
+        let captured = match devices().lock().unwrap().get(devname) {
+            Some(MemoryDevice::Sink(captured)) => Arc::clone(captured),
+            _ => return Err(device_not_found()),
+        };
+        let stream = PacedPlaybackStream::new(devname, captured, config, error_handler);
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread::sleep, time::Instant};
+
+    use tokio::time::timeout;
+
+    use crate::{Frames, PlaybackStream, RecordStream, SampleFormat, Stream};
+
+    use super::*;
+
+    const CONFIG: Config = Config {
+        channels: 2,
+        sample_rate: 48000,
+        sample_format: SampleFormat::S16,
+        buffer_frames: 480,
+        max_buffer_frames: 4800,
+        device_rate: None,
+    };
+
+    #[test]
+    fn unknown_device() {
+        assert!(RecordStream::start("record-test", Some("memory:unknown"), CONFIG).is_err());
+        assert!(PlaybackStream::start("playback-test", Some("memory:unknown"), CONFIG).is_err());
+    }
+
+    #[test]
+    fn record_is_paced_in_real_time() {
+        add_source("record-paced", |buf| buf.fill(1));
+        let mut stream = RecordStream::start("record-test", Some("memory:record-paced"), CONFIG)
+            .expect("Failed to start record stream");
+        assert_eq!(stream.device_name(), Some("record-paced"));
+        assert_eq!(stream.sample_rate(), CONFIG.sample_rate);
+
+        sleep(CONFIG.buffer_duration() * 2);
+        assert!(stream.peek() >= CONFIG.buffer_size());
+        let mut buf = vec![0u8; CONFIG.max_buffer_size()];
+        let read = stream.read(&mut buf);
+        assert!(read >= CONFIG.buffer_size() && read < CONFIG.max_buffer_size());
+        assert!(buf[..read].iter().all(|&b| b == 1));
+
+        sleep(CONFIG.max_buffer_duration() * 2);
+        assert_eq!(stream.peek(), CONFIG.max_buffer_size());
+        assert_eq!(stream.read(&mut buf), CONFIG.max_buffer_size());
+
+        stream.stop().unwrap();
+        remove_device("record-paced");
+    }
+
+    #[test]
+    fn playback_captures_into_memory() {
+        let sink = add_sink("playback-capture");
+        let mut stream =
+            PlaybackStream::start("playback-test", Some("memory:playback-capture"), CONFIG)
+                .expect("Failed to start playback stream");
+        assert_eq!(stream.peek(), CONFIG.max_buffer_size());
+
+        let data: Vec<u8> = (0..CONFIG.buffer_size()).map(|i| i as u8).collect();
+        assert_eq!(stream.write(&data), data.len());
+        assert!(stream.peek() < CONFIG.max_buffer_size());
+        sleep(CONFIG.buffer_duration() * 2);
+        assert_eq!(stream.peek(), CONFIG.max_buffer_size());
+
+        stream.write(&data);
+        stream.stop().unwrap();
+        assert_eq!(sink.take(), [data.as_slice(), data.as_slice()].concat());
+        assert!(sink.is_empty());
+        remove_device("playback-capture");
+    }
+
+    #[test]
+    fn streams_convert_frames() {
+        let config = Config {
+            sample_format: SampleFormat::F32,
+            ..CONFIG
+        };
+        let sink = add_sink("frames-capture");
+        let mut playback =
+            PlaybackStream::start("playback-test", Some("memory:frames-capture"), config)
+                .expect("Failed to start playback stream");
+        let frames = Frames::from_interleaved(2, vec![16384i16, -16384, 8192, 0]).unwrap();
+        assert_eq!(playback.write_frames(&frames).unwrap(), 2);
+        assert!(playback
+            .write_frames(&Frames::<i16>::silence(1, 2))
+            .is_err());
+        playback.stop().unwrap();
+        let mut written = Frames::<f32>::new(2);
+        written.decode(SampleFormat::F32, &sink.take()).unwrap();
+        assert_eq!(written.interleaved(), [0.5, -0.5, 0.25, 0.0]);
+        remove_device("frames-capture");
+
+        add_source("frames-source", |buf| {
+            for sample in buf.chunks_exact_mut(4) {
+                sample.copy_from_slice(&(-0.25f32).to_le_bytes());
+            }
+        });
+        let mut record = RecordStream::start("record-test", Some("memory:frames-source"), config)
+            .expect("Failed to start record stream");
+        sleep(config.buffer_duration() * 2);
+        let mut frames = Frames::<i16>::new(2);
+        assert_eq!(record.read_frames(&mut frames, 100).unwrap(), 100);
+        assert!(frames.interleaved().iter().all(|&s| s == -8192));
+        assert!(record.read_frames(&mut Frames::<i16>::new(1), 100).is_err());
+        record.stop().unwrap();
+        remove_device("frames-source");
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_the_device_clock() {
+        add_source("record-ready", |buf| buf.fill(1));
+        let mut record = RecordStream::start("record-test", Some("memory:record-ready"), CONFIG)
+            .expect("Failed to start record stream");
+        let started = Instant::now();
+        timeout(
+            CONFIG.buffer_duration() * 4,
+            record.readable(CONFIG.buffer_size()),
+        )
+        .await
+        .expect("Record stream never became readable");
+        assert!(started.elapsed() >= CONFIG.buffer_duration());
+        assert!(record.peek() >= CONFIG.buffer_size());
+        record.stop().unwrap();
+        remove_device("record-ready");
+
+        add_sink("playback-ready");
+        let mut playback =
+            PlaybackStream::start("playback-test", Some("memory:playback-ready"), CONFIG)
+                .expect("Failed to start playback stream");
+        playback.write(&vec![0u8; CONFIG.max_buffer_size()]);
+        assert!(playback.peek() < CONFIG.buffer_size());
+        timeout(
+            CONFIG.buffer_duration() * 4,
+            playback.writable(CONFIG.buffer_size()),
+        )
+        .await
+        .expect("Playback stream never became writable");
+        assert!(playback.peek() >= CONFIG.buffer_size());
+        playback.stop().unwrap();
+        remove_device("playback-ready");
+    }
+}
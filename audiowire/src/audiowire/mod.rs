@@ -1,15 +1,29 @@
+mod backend;
 mod config;
+mod device;
 mod errors;
+mod file;
+mod frames;
+mod native;
+mod paced;
+mod resampler;
 mod result;
+mod ringbuf;
 mod stream;
 
+pub mod memory;
+
 use audiowire_sys::{aw_initialize, aw_terminate};
 
 use result::parse_result;
 
 pub use config::*;
+pub use device::{devices, DeviceDirection, DeviceInfo};
 pub use errors::Error;
+pub use frames::{Frames, Sample};
+pub use resampler::Resampler;
 pub use result::Result;
+pub use ringbuf::{ring_buffer, Consumer, Producer};
 pub use stream::*;
 
 #[inline]
@@ -0,0 +1,286 @@
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    ptr, slice,
+    sync::Arc,
+};
+
+use audiowire_sys::*;
+use tokio::sync::Notify;
+
+use super::{
+    backend::{Backend, BackendStream},
+    config::Config,
+    errors::Error,
+    result::{parse_result, parse_result_value, Result},
+    ringbuf::{ring_buffer, Consumer, Producer},
+    stream::ErrorHandler,
+};
+
+pub(crate) struct NativeBackend;
+
+// libaudiowire hands every chunk of audio over to the callbacks on its own
+// thread, a ring buffer carries it between that thread and the stream.
+impl Backend for NativeBackend {
+    #[inline]
+    fn start_record(
+        &self,
+        name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>> {
+        let (producer, consumer) = ring_buffer(config.max_buffer_frames, config.frame_size());
+        let mut callbacks = Callbacks::new(error_handler, notify);
+        callbacks.record = Some(producer);
+        let (handle, callbacks) = unsafe {
+            start_stream(
+                device,
+                name,
+                config,
+                callbacks,
+                |stream, devname, name, cfg, userdata| {
+                    aw_start_record(
+                        stream,
+                        devname,
+                        name,
+                        cfg,
+                        Some(on_error),
+                        Some(on_record),
+                        userdata,
+                    )
+                },
+            )?
+        };
+        let mut stream = NativeStream::new(handle, callbacks, config);
+        stream.record = Some(consumer);
+        Ok(Box::new(stream))
+    }
+
+    #[inline]
+    fn start_playback(
+        &self,
+        name: &str,
+        device: Option<&str>,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+        notify: Arc<Notify>,
+    ) -> Result<Box<dyn BackendStream>> {
+        let (producer, consumer) = ring_buffer(config.max_buffer_frames, config.frame_size());
+        let mut callbacks = Callbacks::new(error_handler, notify);
+        callbacks.playback = Some(consumer);
+        let (handle, callbacks) = unsafe {
+            start_stream(
+                device,
+                name,
+                config,
+                callbacks,
+                |stream, devname, name, cfg, userdata| {
+                    aw_start_playback(
+                        stream,
+                        devname,
+                        name,
+                        cfg,
+                        Some(on_error),
+                        Some(on_playback),
+                        userdata,
+                    )
+                },
+            )?
+        };
+        let mut stream = NativeStream::new(handle, callbacks, config);
+        stream.playback = Some(producer);
+        Ok(Box::new(stream))
+    }
+}
+
+struct NativeStream {
+    handle: *mut aw_stream,
+    devname: Option<String>,
+    frame_size: usize,
+    record: Option<Consumer<u8>>,
+    playback: Option<Producer<u8>>,
+    stopped: bool,
+    // Handed to libaudiowire as userdata, it must outlive the stream
+    callbacks: *mut Callbacks,
+}
+
+impl NativeStream {
+    fn new(handle: *mut aw_stream, callbacks: *mut Callbacks, config: Config) -> Self {
+        let devname = unsafe {
+            let cstr = aw_device_name(handle);
+            if !cstr.is_null() {
+                Some(CStr::from_ptr(cstr).to_string_lossy().to_string())
+            } else {
+                None
+            }
+        };
+        Self {
+            handle,
+            devname,
+            frame_size: config.frame_size(),
+            record: None,
+            playback: None,
+            stopped: false,
+            callbacks,
+        }
+    }
+}
+
+impl BackendStream for NativeStream {
+    #[inline]
+    fn capacity(&self) -> usize {
+        let capacity = match (&self.record, &self.playback) {
+            (Some(consumer), _) => consumer.capacity(),
+            (_, Some(producer)) => producer.capacity(),
+            _ => 0,
+        };
+        capacity * self.frame_size
+    }
+
+    #[inline]
+    fn device_name(&self) -> Option<&str> {
+        self.devname.as_deref()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        unsafe { aw_sample_rate(self.handle) }
+    }
+
+    #[inline]
+    fn record_peek(&self) -> usize {
+        self.record.as_ref().map_or(0, |consumer| consumer.len()) * self.frame_size
+    }
+
+    #[inline]
+    fn record_read(&mut self, buf: &mut [u8]) -> usize {
+        let frames = self
+            .record
+            .as_mut()
+            .map_or(0, |consumer| consumer.pop_frames(buf));
+        frames * self.frame_size
+    }
+
+    #[inline]
+    fn playback_peek(&self) -> usize {
+        self.playback.as_ref().map_or(0, |producer| producer.free()) * self.frame_size
+    }
+
+    #[inline]
+    fn playback_write(&mut self, buf: &[u8]) -> usize {
+        let frames = self
+            .playback
+            .as_mut()
+            .map_or(0, |producer| producer.push_frames(buf));
+        frames * self.frame_size
+    }
+
+    #[inline]
+    fn stop(&mut self) -> Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        unsafe { parse_result(aw_stop(self.handle))? };
+        self.stopped = true;
+        Ok(())
+    }
+}
+
+impl Drop for NativeStream {
+    fn drop(&mut self) {
+        // The audio thread may only go away along with the stream, callbacks
+        // are freed once nothing can call them anymore
+        if self.stop().is_ok() {
+            drop(unsafe { Box::from_raw(self.callbacks) });
+        }
+    }
+}
+
+unsafe impl Sync for NativeStream {}
+unsafe impl Send for NativeStream {}
+
+// Only ever touched from the audio thread once the stream is running
+struct Callbacks {
+    error_handler: Option<ErrorHandler>,
+    notify: Arc<Notify>,
+    record: Option<Producer<u8>>,
+    playback: Option<Consumer<u8>>,
+}
+
+impl Callbacks {
+    fn new(error_handler: Option<ErrorHandler>, notify: Arc<Notify>) -> Self {
+        Self {
+            error_handler,
+            notify,
+            record: None,
+            playback: None,
+        }
+    }
+}
+
+unsafe extern "C" fn on_error(err: c_int, message: *const c_char, userdata: *mut c_void) {
+    let callbacks = &*(userdata as *const Callbacks);
+    if let Some(error_handler) = &callbacks.error_handler {
+        let message =
+            (!message.is_null()).then(|| CStr::from_ptr(message).to_string_lossy().to_string());
+        error_handler(Error::new(err, message));
+    }
+}
+
+// Recorded audio that doesn't fit into the buffer anymore is dropped
+unsafe extern "C" fn on_record(buf: *const c_char, bufsize: usize, userdata: *mut c_void) {
+    let callbacks = &mut *(userdata as *mut Callbacks);
+    if let Some(producer) = callbacks.record.as_mut() {
+        producer.push_frames(slice::from_raw_parts(buf as *const u8, bufsize));
+    }
+    callbacks.notify.notify_one();
+}
+
+// The device is only handed complete periods, it plays silence until enough
+// audio has been queued
+unsafe extern "C" fn on_playback(buf: *mut c_char, bufsize: usize, userdata: *mut c_void) -> usize {
+    let callbacks = &mut *(userdata as *mut Callbacks);
+    let mut filled = 0;
+    if let Some(consumer) = callbacks.playback.as_mut() {
+        if consumer.len() * consumer.frame_len() >= bufsize {
+            let frames = consumer.pop_frames(slice::from_raw_parts_mut(buf as *mut u8, bufsize));
+            filled = frames * consumer.frame_len();
+        }
+    }
+    callbacks.notify.notify_one();
+    filled
+}
+
+unsafe fn start_stream(
+    device: Option<&str>,
+    name: &str,
+    config: Config,
+    callbacks: Callbacks,
+    start_fn: impl FnOnce(
+        *mut *mut aw_stream,
+        *const c_char,
+        *const c_char,
+        aw_config,
+        *mut c_void,
+    ) -> aw_result,
+) -> Result<(*mut aw_stream, *mut Callbacks)> {
+    let mut stream: *mut aw_stream = ptr::null_mut();
+    let cdev = device.map(|s| CString::new(s).unwrap());
+    let cname = CString::new(name).unwrap();
+    let callbacks = Box::into_raw(Box::new(callbacks));
+    let result = start_fn(
+        &mut stream,
+        cdev.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+        cname.as_ptr(),
+        config.into(),
+        callbacks as *mut c_void,
+    );
+    match parse_result_value(result, stream) {
+        Ok(handle) => Ok((handle, callbacks)),
+        Err(err) => {
+            drop(Box::from_raw(callbacks));
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::VecDeque,
+    io,
+    time::{Duration, Instant},
+};
+
+use super::{backend::BackendStream, config::Config, result::Result, stream::ErrorHandler};
+
+// Source produces the samples of a virtual record device.
+pub(crate) trait Source: Send + Sync {
+    // Returns how many bytes were filled, anything short of the buffer length
+    // means the source has been exhausted.
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    // Number of bytes left in the source, None if it never runs out.
+    #[inline]
+    fn remaining(&self) -> Option<usize> {
+        None
+    }
+}
+
+// Sink consumes the samples of a virtual playback device.
+pub(crate) trait Sink: Send + Sync {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    #[inline]
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ErrorHandle(Option<ErrorHandler>);
+
+impl ErrorHandle {
+    fn report(&self, err: io::Error) {
+        if let Some(error_handler) = &self.0 {
+            error_handler(err.into());
+        }
+    }
+}
+
+// Clock keeps track of how many frames the virtual device has processed
+// compared to how many it should have processed in real time. Frames become
+// due one buffer period at a time, the same way a sound card hands out
+// fragments of Config::buffer_duration.
+struct Clock {
+    started: Instant,
+    sample_rate: u32,
+    period: u64,
+    frames: u64,
+}
+
+impl Clock {
+    fn new(config: &Config) -> Self {
+        Self {
+            started: Instant::now(),
+            sample_rate: config.sample_rate,
+            period: config.buffer_frames.max(1) as u64,
+            frames: 0,
+        }
+    }
+
+    #[inline]
+    fn due(&self) -> usize {
+        let elapsed = self.started.elapsed().as_nanos();
+        let total = (elapsed * self.sample_rate as u128 / 1_000_000_000) as u64;
+        (total - total % self.period - self.frames) as usize
+    }
+
+    #[inline]
+    fn advance(&mut self, frames: usize) {
+        self.frames += frames as u64;
+    }
+
+    // Time left until the next buffer period becomes due
+    fn next_period(&self) -> Duration {
+        let rate = self.sample_rate as u128;
+        let elapsed = self.started.elapsed().as_nanos();
+        let total = elapsed * rate / 1_000_000_000;
+        let next = (total / self.period as u128 + 1) * self.period as u128;
+        let nanos = (next * 1_000_000_000).div_ceil(rate) - elapsed;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+pub(crate) struct PacedRecordStream<S: Source> {
+    devname: String,
+    source: S,
+    exhausted: bool,
+    buffer: VecDeque<u8>,
+    clock: Clock,
+    frame_size: usize,
+    max_bufsize: usize,
+    error: ErrorHandle,
+}
+
+impl<S: Source> PacedRecordStream<S> {
+    pub(crate) fn new(
+        devname: &str,
+        source: S,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+    ) -> Self {
+        Self {
+            devname: devname.to_owned(),
+            source,
+            exhausted: false,
+            buffer: VecDeque::new(),
+            clock: Clock::new(&config),
+            frame_size: config.frame_size(),
+            max_bufsize: config.max_buffer_size(),
+            error: ErrorHandle(error_handler),
+        }
+    }
+
+    fn fill(&mut self) {
+        let due = self.clock.due();
+        // Frames that would overflow the buffer are dropped without producing them
+        let count = due.min(self.max_bufsize / self.frame_size);
+        if count > 0 && !self.exhausted {
+            let mut chunk = vec![0u8; count * self.frame_size];
+            let length = match self.source.fill(&mut chunk) {
+                Ok(length) => length,
+                Err(err) => {
+                    self.error.report(err);
+                    0
+                }
+            };
+            self.exhausted = length < chunk.len();
+            self.buffer.extend(&chunk[..length]);
+            let overflow = self.buffer.len().saturating_sub(self.max_bufsize);
+            self.buffer.drain(..overflow);
+        }
+        self.clock.advance(due);
+    }
+}
+
+impl<S: Source> BackendStream for PacedRecordStream<S> {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.max_bufsize
+    }
+
+    #[inline]
+    fn device_name(&self) -> Option<&str> {
+        Some(&self.devname)
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.clock.sample_rate
+    }
+
+    #[inline]
+    fn record_peek(&self) -> usize {
+        if self.exhausted {
+            return self.buffer.len();
+        }
+        let mut due = self.clock.due() * self.frame_size;
+        if let Some(remaining) = self.source.remaining() {
+            due = due.min(remaining);
+        }
+        (self.buffer.len() + due).min(self.max_bufsize)
+    }
+
+    fn record_read(&mut self, buf: &mut [u8]) -> usize {
+        self.fill();
+        let length = buf.len().min(self.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(self.buffer.drain(..length)) {
+            *dst = src;
+        }
+        length
+    }
+
+    #[inline]
+    fn playback_peek(&self) -> usize {
+        0
+    }
+
+    #[inline]
+    fn playback_write(&mut self, _buf: &[u8]) -> usize {
+        0
+    }
+
+    #[inline]
+    fn stop(&mut self) -> Result<()> {
+        self.buffer.clear();
+        Ok(())
+    }
+
+    #[inline]
+    fn next_period(&self) -> Option<Duration> {
+        Some(self.clock.next_period())
+    }
+}
+
+pub(crate) struct PacedPlaybackStream<S: Sink> {
+    devname: String,
+    sink: S,
+    pending: VecDeque<u8>,
+    clock: Clock,
+    frame_size: usize,
+    max_bufsize: usize,
+    error: ErrorHandle,
+}
+
+impl<S: Sink> PacedPlaybackStream<S> {
+    pub(crate) fn new(
+        devname: &str,
+        sink: S,
+        config: Config,
+        error_handler: Option<ErrorHandler>,
+    ) -> Self {
+        Self {
+            devname: devname.to_owned(),
+            sink,
+            pending: VecDeque::new(),
+            clock: Clock::new(&config),
+            frame_size: config.frame_size(),
+            max_bufsize: config.max_buffer_size(),
+            error: ErrorHandle(error_handler),
+        }
+    }
+
+    fn drain(&mut self, length: usize) {
+        let (head, tail) = self.pending.as_slices();
+        let result = if length <= head.len() {
+            self.sink.write(&head[..length])
+        } else {
+            self.sink
+                .write(head)
+                .and_then(|_| self.sink.write(&tail[..length - head.len()]))
+        };
+        if let Err(err) = result {
+            self.error.report(err);
+        }
+        self.pending.drain(..length);
+    }
+}
+
+impl<S: Sink> BackendStream for PacedPlaybackStream<S> {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.max_bufsize
+    }
+
+    #[inline]
+    fn device_name(&self) -> Option<&str> {
+        Some(&self.devname)
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.clock.sample_rate
+    }
+
+    #[inline]
+    fn record_peek(&self) -> usize {
+        0
+    }
+
+    #[inline]
+    fn record_read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    #[inline]
+    fn playback_peek(&self) -> usize {
+        let due = self.clock.due() * self.frame_size;
+        let pending = self.pending.len() - due.min(self.pending.len());
+        self.max_bufsize - pending
+    }
+
+    fn playback_write(&mut self, buf: &[u8]) -> usize {
+        let due = self.clock.due();
+        self.drain((due * self.frame_size).min(self.pending.len()));
+        self.clock.advance(due);
+
+        self.pending.extend(buf);
+        // Same as the native ring buffer, overflowing writes drop the oldest data
+        let overflow = self.pending.len().saturating_sub(self.max_bufsize);
+        self.pending.drain(..overflow);
+        buf.len()
+    }
+
+    // Whatever is still queued gets flushed so callers can inspect the full output
+    fn stop(&mut self) -> Result<()> {
+        self.drain(self.pending.len());
+        self.sink.finish()?;
+        Ok(())
+    }
+
+    #[inline]
+    fn next_period(&self) -> Option<Duration> {
+        Some(self.clock.next_period())
+    }
+}
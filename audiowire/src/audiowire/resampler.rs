@@ -0,0 +1,391 @@
+use std::{collections::VecDeque, f64::consts::PI, time::Duration};
+
+use super::{
+    backend::BackendStream,
+    config::{Config, SampleFormat},
+    result::Result,
+};
+
+// Taps of the filter applied for every output sample, half of them on
+// either side of it
+const TAPS: usize = 32;
+// Filter phases tabulated between two input samples, anything in between is
+// interpolated from the closest two
+const PHASES: usize = 256;
+const FRACTION_BITS: u32 = 32;
+const ONE: u64 = 1 << FRACTION_BITS;
+// Passband edge relative to the lower of the two Nyquist frequencies
+const CUTOFF: f64 = 0.95;
+const KAISER_BETA: f64 = 8.0;
+
+// Resampler converts interleaved audio between two sample rates with a
+// windowed sinc filter. The position in the input is kept in fixed point, so
+// however long it runs the number of frames it produces never drifts from
+// the ratio of the two rates.
+pub struct Resampler {
+    channels: usize,
+    // Input frames per output frame, as given by the two rates and as
+    // currently applied
+    ratio: f64,
+    step: u64,
+    filter: Vec<f32>,
+    // Input frames still needed by the filter, interleaved
+    history: Vec<f32>,
+    // Position of the next output frame within the history
+    position: u64,
+    samples: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, input_rate: u32, output_rate: u32) -> Self {
+        let ratio = input_rate as f64 / output_rate as f64;
+        let cutoff = CUTOFF * ratio.recip().min(1.0);
+        let half = (TAPS / 2) as f64;
+        let mut filter = Vec::with_capacity((PHASES + 1) * TAPS);
+        for phase in 0..=PHASES {
+            let offset = phase as f64 / PHASES as f64;
+            let start = filter.len();
+            for tap in 0..TAPS {
+                let x = tap as f64 - (half - 1.0) - offset;
+                filter.push((cutoff * sinc(cutoff * x) * kaiser(x / half)) as f32);
+            }
+            // Every phase passes DC at unity gain
+            let sum: f32 = filter[start..].iter().sum();
+            filter[start..].iter_mut().for_each(|c| *c /= sum);
+        }
+        Self {
+            channels,
+            ratio,
+            step: (ratio * ONE as f64).round() as u64,
+            filter,
+            // The first output frame lines up with the first input frame
+            history: vec![0.0; (TAPS / 2 - 1) * channels],
+            position: 0,
+            samples: Vec::new(),
+            resampled: Vec::new(),
+        }
+    }
+
+    // Scales the ratio of the two rates by a factor close to one, eg. to
+    // follow a clock that runs slightly faster or slower than its nominal
+    // rate. The filter is left as it is.
+    pub fn set_ratio(&mut self, factor: f64) {
+        self.step = (self.ratio * factor * ONE as f64).round() as u64;
+    }
+
+    // Output frames the next call to process would produce given that many
+    // more input frames
+    pub fn output_frames(&self, input_frames: usize) -> usize {
+        let frames = self.history.len() / self.channels + input_frames;
+        if frames < TAPS {
+            return 0;
+        }
+        let limit = (((frames - TAPS + 1) as u64) << FRACTION_BITS) - 1;
+        if self.position > limit {
+            0
+        } else {
+            ((limit - self.position) / self.step + 1) as usize
+        }
+    }
+
+    // Resamples interleaved frames, appending every output frame they
+    // complete. Input past the last of them is kept for the next call.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let channels = self.channels;
+        self.history.extend_from_slice(input);
+        let frames = self.history.len() / channels;
+        loop {
+            let index = (self.position >> FRACTION_BITS) as usize;
+            if index + TAPS > frames {
+                break;
+            }
+            let scaled = (self.position & (ONE - 1)) * PHASES as u64;
+            let phase = (scaled >> FRACTION_BITS) as usize;
+            let weight = (scaled & (ONE - 1)) as f32 / ONE as f32;
+            let lower = &self.filter[phase * TAPS..][..TAPS];
+            let upper = &self.filter[(phase + 1) * TAPS..][..TAPS];
+            let window = &self.history[index * channels..][..TAPS * channels];
+            for channel in 0..channels {
+                let mut sum = 0.0;
+                for tap in 0..TAPS {
+                    let coefficient = lower[tap] + (upper[tap] - lower[tap]) * weight;
+                    sum += window[tap * channels + channel] * coefficient;
+                }
+                output.push(sum);
+            }
+            self.position += self.step;
+        }
+
+        let consumed = ((self.position >> FRACTION_BITS) as usize).min(frames);
+        self.history.drain(..consumed * channels);
+        self.position -= (consumed as u64) << FRACTION_BITS;
+    }
+
+    // Same as process for samples encoded in the given format
+    pub fn process_encoded(&mut self, format: SampleFormat, input: &[u8], output: &mut Vec<u8>) {
+        let sample_size = format.size();
+        let mut samples = std::mem::take(&mut self.samples);
+        let mut resampled = std::mem::take(&mut self.resampled);
+        samples.clear();
+        samples.extend(
+            input
+                .chunks_exact(sample_size)
+                .map(|sample| format.decode(sample)),
+        );
+        resampled.clear();
+        self.process(&samples, &mut resampled);
+
+        let start = output.len();
+        output.resize(start + resampled.len() * sample_size, 0);
+        for (value, dst) in resampled
+            .iter()
+            .zip(output[start..].chunks_exact_mut(sample_size))
+        {
+            format.encode(*value, dst);
+        }
+        self.samples = samples;
+        self.resampled = resampled;
+    }
+}
+
+#[inline]
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[inline]
+fn kaiser(x: f64) -> f64 {
+    bessel_i0(KAISER_BETA * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+// ResampledStream sits between a stream and a device running at another
+// rate, everything above it sees audio at the rate of the config.
+pub(crate) struct ResampledStream {
+    inner: Box<dyn BackendStream>,
+    resampler: Resampler,
+    format: SampleFormat,
+    frame_size: usize,
+    rate: u32,
+    device_rate: u32,
+    encoded: Vec<u8>,
+    // Record audio resampled already but not read yet
+    pending: VecDeque<u8>,
+}
+
+impl ResampledStream {
+    pub(crate) fn record(inner: Box<dyn BackendStream>, config: Config) -> Self {
+        let device_rate = inner.sample_rate();
+        let resampler = Resampler::new(config.channels as usize, device_rate, config.sample_rate);
+        Self::new(inner, resampler, config, device_rate)
+    }
+
+    pub(crate) fn playback(inner: Box<dyn BackendStream>, config: Config) -> Self {
+        let device_rate = inner.sample_rate();
+        let resampler = Resampler::new(config.channels as usize, config.sample_rate, device_rate);
+        Self::new(inner, resampler, config, device_rate)
+    }
+
+    fn new(
+        inner: Box<dyn BackendStream>,
+        resampler: Resampler,
+        config: Config,
+        device_rate: u32,
+    ) -> Self {
+        Self {
+            inner,
+            resampler,
+            format: config.sample_format,
+            frame_size: config.frame_size(),
+            rate: config.sample_rate,
+            device_rate,
+            encoded: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Frames at the device rate converted to frames at the stream rate,
+    // rounded down
+    #[inline]
+    fn to_stream_frames(&self, frames: usize) -> usize {
+        (frames as u64 * self.rate as u64 / self.device_rate as u64) as usize
+    }
+
+    fn resample(&mut self, buf: &[u8]) {
+        self.encoded.clear();
+        self.resampler
+            .process_encoded(self.format, buf, &mut self.encoded);
+    }
+}
+
+impl BackendStream for ResampledStream {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.to_stream_frames(self.inner.capacity() / self.frame_size) * self.frame_size
+    }
+
+    #[inline]
+    fn device_name(&self) -> Option<&str> {
+        self.inner.device_name()
+    }
+
+    // Still the rate of the device, as that's what the stream runs at
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.device_rate
+    }
+
+    #[inline]
+    fn record_peek(&self) -> usize {
+        let frames = self.inner.record_peek() / self.frame_size;
+        self.pending.len() + self.resampler.output_frames(frames) * self.frame_size
+    }
+
+    fn record_read(&mut self, buf: &mut [u8]) -> usize {
+        if self.pending.len() < buf.len() {
+            let mut device = vec![0u8; self.inner.record_peek()];
+            let read = self.inner.record_read(&mut device);
+            self.resample(&device[..read]);
+            self.pending.extend(&self.encoded);
+        }
+        let length = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..length)) {
+            *dst = src;
+        }
+        length
+    }
+
+    // Leaves a frame of room for the filter to round up
+    #[inline]
+    fn playback_peek(&self) -> usize {
+        let frames = (self.inner.playback_peek() / self.frame_size).saturating_sub(1);
+        self.to_stream_frames(frames) * self.frame_size
+    }
+
+    fn playback_write(&mut self, buf: &[u8]) -> usize {
+        self.resample(buf);
+        self.inner.playback_write(&self.encoded);
+        buf.len()
+    }
+
+    #[inline]
+    fn stop(&mut self) -> Result<()> {
+        self.inner.stop()
+    }
+
+    #[inline]
+    fn next_period(&self) -> Option<Duration> {
+        self.inner.next_period()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use crate::{memory, RecordStream, SampleFormat, Stream, DEFAULT_CONFIG};
+
+    use super::*;
+
+    fn sine(rate: u32, frequency: f64, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * PI * frequency * i as f64 / rate as f64).sin() as f32 * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn converts_sine_between_rates() {
+        for (input_rate, output_rate) in [(44100, 48000), (48000, 44100), (48000, 16000)] {
+            let mut resampler = Resampler::new(1, input_rate, output_rate);
+            let input = sine(input_rate, 1000.0, input_rate as usize);
+            let mut output = Vec::new();
+            // Odd chunk sizes make sure nothing depends on how input is split
+            for chunk in input.chunks(333) {
+                let expected = resampler.output_frames(chunk.len());
+                let before = output.len();
+                resampler.process(chunk, &mut output);
+                assert_eq!(output.len() - before, expected);
+            }
+            // Only the tail still held back by the filter is missing
+            let missing = output_rate as usize - output.len();
+            assert!(missing <= TAPS * output_rate as usize / input_rate as usize);
+
+            let reference = sine(output_rate, 1000.0, output.len());
+            let error = output[TAPS..]
+                .iter()
+                .zip(&reference[TAPS..])
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                / (output.len() - TAPS) as f32;
+            assert!(
+                error.sqrt() < 1e-3,
+                "{} -> {}: {}",
+                input_rate,
+                output_rate,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn adjusted_ratio_consumes_input_faster() {
+        let mut resampler = Resampler::new(2, 48000, 48000);
+        resampler.set_ratio(1.001);
+        let input = vec![0.25f32; 2 * 48000];
+        let mut output = Vec::new();
+        resampler.process(&input, &mut output);
+        // A thousandth fewer frames come out, less the filter's tail
+        let frames = output.len() / 2;
+        assert!((47952 - TAPS..=47952).contains(&frames), "{}", frames);
+        assert!(output[TAPS * 2..].iter().all(|s| (s - 0.25).abs() < 1e-4));
+
+        // Encoded samples go through the same filter
+        let mut encoded = Vec::new();
+        let bytes: Vec<u8> = [8192i16; 2 * 480]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        resampler.process_encoded(SampleFormat::S16, &bytes, &mut encoded);
+        assert_eq!(encoded.len() % 4, 0);
+        assert!(encoded[TAPS * 4..]
+            .chunks_exact(2)
+            .all(|s| (i16::from_le_bytes([s[0], s[1]]) - 8192).abs() <= 2));
+    }
+
+    #[test]
+    fn record_at_device_rate() {
+        memory::add_source("resample-source", |buf| buf.fill(0));
+        let config = Config {
+            sample_format: SampleFormat::S16,
+            device_rate: Some(44100),
+            ..DEFAULT_CONFIG
+        };
+        let mut stream = RecordStream::start("record-test", Some("memory:resample-source"), config)
+            .expect("Failed to start record stream");
+        assert_eq!(stream.sample_rate(), 44100);
+
+        sleep(config.buffer_duration() * 5);
+        let peek = stream.peek();
+        assert!(peek >= config.buffer_size());
+        let mut buf = vec![0u8; peek];
+        assert_eq!(stream.read(&mut buf), peek);
+        stream.stop().unwrap();
+        memory::remove_device("resample-source");
+    }
+}
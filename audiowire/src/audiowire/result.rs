@@ -20,11 +20,6 @@ pub(super) fn parse_result_value<T>(res: aw_result, value: T) -> Result<T> {
     }
 }
 
-#[inline]
-pub(super) fn parse_result_lazy<T, F: FnOnce() -> T>(res: aw_result, f: F) -> Result<T> {
-    parse_result(res).map(|_| f())
-}
-
 #[allow(dead_code)]
 pub(super) trait CResult {
     fn is_ok(&self) -> bool;
@@ -0,0 +1,250 @@
+use std::{
+    cell::UnsafeCell,
+    ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+// Lock-free queue of frames between exactly one producer and one consumer,
+// eg. an audio thread and a tokio task. Each side only ever moves its own
+// index, publishing it with release ordering once the frames it covers have
+// been written or read, and the other side picks it up with acquire ordering
+// before touching those frames.
+struct Shared<T> {
+    data: Box<[UnsafeCell<T>]>,
+    frame_len: usize,
+    // Positions run over twice the capacity so a full buffer can be told
+    // apart from an empty one
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    #[inline]
+    fn len(&self, head: usize, tail: usize) -> usize {
+        let capacity = self.data.len();
+        if tail >= head {
+            tail - head
+        } else {
+            tail + 2 * capacity - head
+        }
+    }
+
+    #[inline]
+    fn advance(&self, position: usize, count: usize) -> usize {
+        let position = position + count;
+        if position >= 2 * self.data.len() {
+            position - 2 * self.data.len()
+        } else {
+            position
+        }
+    }
+
+    // Slot ranges covered by count elements from position, the second one
+    // being where they wrap around to the start of the buffer
+    #[inline]
+    fn slots(&self, position: usize, count: usize) -> (usize, usize, usize) {
+        let start = position % self.data.len();
+        let first = count.min(self.data.len() - start);
+        (start, first, count - first)
+    }
+
+    #[inline]
+    fn slot(&self, index: usize) -> *mut T {
+        UnsafeCell::raw_get(self.data.as_ptr().wrapping_add(index))
+    }
+}
+
+// Creates a ring buffer of frames, each made of frame_len elements
+pub fn ring_buffer<T: Copy + Default>(
+    frames: usize,
+    frame_len: usize,
+) -> (Producer<T>, Consumer<T>) {
+    assert!(frames > 0 && frame_len > 0, "Ring buffer can't be empty");
+    let shared = Arc::new(Shared {
+        data: (0..frames * frame_len)
+            .map(|_| UnsafeCell::new(T::default()))
+            .collect(),
+        frame_len,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            shared: Arc::clone(&shared),
+        },
+        Consumer { shared },
+    )
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Producer<T> {
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.shared.data.len() / self.shared.frame_len
+    }
+
+    #[inline]
+    pub fn frame_len(&self) -> usize {
+        self.shared.frame_len
+    }
+
+    // Frames that can be pushed without overflowing
+    #[inline]
+    pub fn free(&self) -> usize {
+        let head = self.shared.head.load(Ordering::Acquire);
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        self.capacity() - self.shared.len(head, tail) / self.shared.frame_len
+    }
+
+    // Pushes as many whole frames as there is room for, anything past that
+    // is dropped. Returns the number of frames pushed.
+    pub fn push_frames(&mut self, frames: &[T]) -> usize {
+        let shared = &*self.shared;
+        let count = (frames.len() / shared.frame_len).min(self.free());
+        let length = count * shared.frame_len;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let (start, first, second) = shared.slots(tail, length);
+        unsafe {
+            ptr::copy_nonoverlapping(frames.as_ptr(), shared.slot(start), first);
+            ptr::copy_nonoverlapping(frames.as_ptr().add(first), shared.slot(0), second);
+        }
+        shared
+            .tail
+            .store(shared.advance(tail, length), Ordering::Release);
+        count
+    }
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Consumer<T> {
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.shared.data.len() / self.shared.frame_len
+    }
+
+    #[inline]
+    pub fn frame_len(&self) -> usize {
+        self.shared.frame_len
+    }
+
+    // Frames ready to be popped
+    #[inline]
+    pub fn len(&self) -> usize {
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        self.shared.len(head, tail) / self.shared.frame_len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Pops as many whole frames as fit into the buffer, returns the number
+    // of frames popped
+    pub fn pop_frames(&mut self, frames: &mut [T]) -> usize {
+        let shared = &*self.shared;
+        let count = (frames.len() / shared.frame_len).min(self.len());
+        let length = count * shared.frame_len;
+        let head = shared.head.load(Ordering::Relaxed);
+        let (start, first, second) = shared.slots(head, length);
+        unsafe {
+            ptr::copy_nonoverlapping(shared.slot(start), frames.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(shared.slot(0), frames.as_mut_ptr().add(first), second);
+        }
+        shared
+            .head
+            .store(shared.advance(head, length), Ordering::Release);
+        count
+    }
+
+    // Drops up to count of the oldest frames, returns the number dropped
+    pub fn skip_frames(&mut self, count: usize) -> usize {
+        let shared = &*self.shared;
+        let count = count.min(self.len());
+        let head = shared.head.load(Ordering::Relaxed);
+        shared.head.store(
+            shared.advance(head, count * shared.frame_len),
+            Ordering::Release,
+        );
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn wraps_around_whole_frames() {
+        let (mut producer, mut consumer) = ring_buffer::<u8>(3, 2);
+        assert_eq!(producer.capacity(), 3);
+        assert_eq!(producer.free(), 3);
+        assert!(consumer.is_empty());
+
+        // Trailing partial frames are never pushed
+        assert_eq!(producer.push_frames(&[1, 2, 3, 4, 5]), 2);
+        assert_eq!(consumer.len(), 2);
+        let mut buf = [0u8; 3];
+        assert_eq!(consumer.pop_frames(&mut buf), 1);
+        assert_eq!(buf[..2], [1, 2]);
+
+        // Overflowing frames are dropped rather than overwriting queued ones
+        assert_eq!(producer.push_frames(&[5, 6, 7, 8, 9, 10]), 2);
+        assert_eq!(producer.free(), 0);
+        let mut buf = [0u8; 8];
+        assert_eq!(consumer.pop_frames(&mut buf), 3);
+        assert_eq!(buf[..6], [3, 4, 5, 6, 7, 8]);
+        assert!(consumer.is_empty());
+
+        assert_eq!(producer.push_frames(&[11, 12, 13, 14]), 2);
+        assert_eq!(consumer.skip_frames(5), 2);
+        assert_eq!(producer.free(), 3);
+    }
+
+    // The producer writes a running counter into every frame, anything
+    // torn, reordered or lost shows up as a gap on the consumer side.
+    #[test]
+    fn stress_across_threads() {
+        const FRAMES: u32 = 1_000_000;
+        let (mut producer, mut consumer) = ring_buffer::<u32>(61, 3);
+        let writer = thread::spawn(move || {
+            let mut next = 0;
+            while next < FRAMES {
+                let chunk: Vec<u32> = (next..FRAMES.min(next + 7))
+                    .flat_map(|i| [i, !i, i.wrapping_mul(31)])
+                    .collect();
+                next += producer.push_frames(&chunk) as u32;
+                thread::yield_now();
+            }
+        });
+
+        let mut expected = 0;
+        let mut buf = [0u32; 3 * 11];
+        while expected < FRAMES {
+            let count = consumer.pop_frames(&mut buf);
+            for frame in buf[..count * 3].chunks_exact(3) {
+                assert_eq!(frame, [expected, !expected, expected.wrapping_mul(31)]);
+                expected += 1;
+            }
+            if count == 0 {
+                thread::yield_now();
+            }
+        }
+        writer.join().unwrap();
+        assert!(consumer.is_empty());
+    }
+}
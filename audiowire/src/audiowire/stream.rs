@@ -1,14 +1,14 @@
-use std::{
-    ffi::{c_char, c_int, CStr, CString},
-    os::raw::c_void,
-    ptr,
-};
+use std::sync::Arc;
 
-use audiowire_sys::*;
+use tokio::{sync::Notify, time::timeout};
 
 use super::{
+    backend::{find_backend, BackendStream},
     config::Config,
-    result::{parse_result_lazy, parse_result_value, Result},
+    errors::Error,
+    frames::{Frames, Sample},
+    resampler::ResampledStream,
+    result::Result,
 };
 
 #[derive(Clone, Copy)]
@@ -56,82 +56,51 @@ impl From<[u8; 1]> for StreamType {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct StreamFlags(u8);
-
-impl StreamFlags {
-    pub fn new(stream_type: StreamType, opus_enabled: bool) -> Self {
-        if opus_enabled {
-            Self(stream_type.0 | (1 << 2))
-        } else {
-            Self(stream_type.0)
-        }
-    }
-
-    #[inline]
-    pub fn stream_type(self) -> StreamType {
-        StreamType(self.0)
-    }
-
-    #[inline]
-    pub fn opus_enabled(self) -> bool {
-        self.0 & (1 << 2) != 0
-    }
-
-    #[inline]
-    pub fn to_bytes(self) -> [u8; 1] {
-        [self.0]
-    }
-}
-
-impl Default for StreamFlags {
-    #[inline]
-    fn default() -> Self {
-        Self(Default::default())
-    }
-}
-
-impl Into<[u8; 1]> for StreamFlags {
-    #[inline]
-    fn into(self) -> [u8; 1] {
-        self.to_bytes()
-    }
-}
-
-impl From<&[u8]> for StreamFlags {
-    #[inline]
-    fn from(value: &[u8]) -> Self {
-        Self(value[0])
-    }
-}
-
-impl From<[u8; 1]> for StreamFlags {
-    #[inline]
-    fn from(value: [u8; 1]) -> Self {
-        Self(value[0])
-    }
-}
-
 pub struct BaseStream {
-    handle: *mut aw_stream,
-    devname: Option<String>,
+    handle: Box<dyn BackendStream>,
     running: bool,
+    // Signalled by the backend whenever the audio thread moved data
+    notify: Arc<Notify>,
+    config: Config,
+    // Bytes of the frames being read or written
+    encoded: Vec<u8>,
 }
 
 impl BaseStream {
-    fn new(handle: *mut aw_stream) -> Self {
-        let devname = unsafe {
-            let cstr = aw_device_name(handle);
-            if !cstr.is_null() {
-                Some(CStr::from_ptr(cstr).to_string_lossy().to_string())
-            } else {
-                None
-            }
-        };
+    fn new(handle: Box<dyn BackendStream>, notify: Arc<Notify>, config: Config) -> Self {
         Self {
             handle,
-            devname,
             running: true,
+            notify,
+            config,
+            encoded: Vec::new(),
+        }
+    }
+
+    fn check_channels<S: Sample>(&self, frames: &Frames<S>) -> Result<()> {
+        if frames.channels() == self.config.channels {
+            Ok(())
+        } else {
+            Err(Error::new(
+                -1,
+                Some(format!(
+                    "Stream has {} channel(s), frames have {}",
+                    self.config.channels,
+                    frames.channels()
+                )),
+            ))
+        }
+    }
+
+    // Backends without an audio thread of their own don't signal anything,
+    // they get checked again once their next period is due instead.
+    async fn wait_until(&self, ready: impl Fn(&dyn BackendStream) -> bool) {
+        while !ready(self.handle.as_ref()) {
+            let notified = self.notify.notified();
+            match self.handle.next_period() {
+                Some(period) => timeout(period, notified).await.unwrap_or_default(),
+                None => notified.await,
+            }
         }
     }
 }
@@ -146,17 +115,17 @@ pub trait Stream: StreamInternal + Sized {
 
     #[inline]
     fn capacity(&self) -> usize {
-        unsafe { aw_buffer_capacity(self.base().handle) }
+        self.base().handle.capacity()
     }
 
     #[inline]
     fn device_name(&self) -> Option<&str> {
-        self.base().devname.as_deref()
+        self.base().handle.device_name()
     }
 
     #[inline]
     fn sample_rate(&self) -> u32 {
-        unsafe { aw_sample_rate(self.base().handle) }
+        self.base().handle.sample_rate()
     }
 
     fn peek(&self) -> usize;
@@ -165,7 +134,7 @@ pub trait Stream: StreamInternal + Sized {
     fn stop(&mut self) -> Result<()> {
         let base = self.base_mut();
         if base.running {
-            unsafe { parse_result_lazy(aw_stop(base.handle), || base.running = false) }
+            base.handle.stop().map(|_| base.running = false)
         } else {
             Ok(())
         }
@@ -179,7 +148,30 @@ pub struct RecordStream {
 impl RecordStream {
     #[inline]
     pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        unsafe { aw_record_read(self.base.handle, buf.as_mut_ptr() as *mut c_char, buf.len()) }
+        self.base.handle.record_read(buf)
+    }
+
+    // Replaces the frames with up to count frames read from the stream,
+    // converted from the format of the config
+    pub fn read_frames<S: Sample>(
+        &mut self,
+        frames: &mut Frames<S>,
+        count: usize,
+    ) -> Result<usize> {
+        let base = &mut self.base;
+        base.check_channels(frames)?;
+        base.encoded.resize(count * base.config.frame_size(), 0);
+        let read = base.handle.record_read(&mut base.encoded);
+        frames.decode(base.config.sample_format, &base.encoded[..read])?;
+        Ok(frames.len())
+    }
+
+    // Resolves once at least len bytes, or a full buffer, can be read
+    pub async fn readable(&self, len: usize) {
+        let len = len.min(self.capacity());
+        self.base
+            .wait_until(|handle| handle.record_peek() >= len)
+            .await
     }
 }
 
@@ -203,13 +195,10 @@ impl Stream for RecordStream {
 
     #[inline]
     fn peek(&self) -> usize {
-        unsafe { aw_record_peek(self.base.handle) }
+        self.base.handle.record_peek()
     }
 }
 
-unsafe impl Sync for RecordStream {}
-unsafe impl Send for RecordStream {}
-
 pub struct PlaybackStream {
     base: BaseStream,
 }
@@ -217,7 +206,26 @@ pub struct PlaybackStream {
 impl PlaybackStream {
     #[inline]
     pub fn write(&mut self, buf: &[u8]) -> usize {
-        unsafe { aw_playback_write(self.base.handle, buf.as_ptr() as *mut c_char, buf.len()) }
+        self.base.handle.playback_write(buf)
+    }
+
+    // Writes as many of the frames as fit, converted to the format of the
+    // config, and returns how many that was
+    pub fn write_frames<S: Sample>(&mut self, frames: &Frames<S>) -> Result<usize> {
+        let base = &mut self.base;
+        base.check_channels(frames)?;
+        base.encoded.clear();
+        frames.encode(base.config.sample_format, &mut base.encoded);
+        let written = base.handle.playback_write(&base.encoded);
+        Ok(written / base.config.frame_size())
+    }
+
+    // Resolves once at least len bytes, or a full buffer, can be written
+    pub async fn writable(&self, len: usize) {
+        let len = len.min(self.capacity());
+        self.base
+            .wait_until(|handle| handle.playback_peek() >= len)
+            .await
     }
 }
 
@@ -241,78 +249,31 @@ impl Stream for PlaybackStream {
 
     #[inline]
     fn peek(&self) -> usize {
-        unsafe { aw_playback_peek(self.base.handle) }
+        self.base.handle.playback_peek()
     }
 }
 
-unsafe impl Sync for PlaybackStream {}
-unsafe impl Send for PlaybackStream {}
-
-pub type ErrorCallback = fn(err: i32, message: &str, userdata: *mut c_void);
-
-struct ErrorHandle {
-    error_cb: ErrorCallback,
-    userdata: *mut c_void,
-}
-
-unsafe extern "C" fn on_error(err: c_int, message: *const c_char, userdata: *mut c_void) {
-    let handle = &ptr::read(userdata as *mut ErrorHandle);
-    (handle.error_cb)(
-        err as i32,
-        CStr::from_ptr(message).to_str().unwrap_or_default(),
-        handle.userdata,
-    );
-}
-
-type StartStreamFn = unsafe extern "C" fn(
-    stream: *mut *mut aw_stream,
-    devname: *const c_char,
-    name: *const c_char,
-    cfg: aw_config,
-    error_cb: aw_error_callback_t,
-    userdata: *mut c_void,
-) -> aw_result;
-
-unsafe fn start_stream(
-    start_fn: StartStreamFn,
-    device: Option<&str>,
-    name: &str,
+// Whatever rate the device ends up running at, the stream hands out audio at
+// the rate of the config
+fn resample(
+    handle: Box<dyn BackendStream>,
     config: Config,
-    error_cb: Option<ErrorCallback>,
-    userdata: *mut c_void,
-) -> Result<*mut aw_stream> {
-    let mut stream: *mut aw_stream = ptr::null_mut();
-    let cdev = device
-        .map(|s| CString::new(s).unwrap().into_raw())
-        .unwrap_or(ptr::null_mut());
-    let cname = CString::new(name).unwrap().into_raw();
-    let result = if let Some(error_cb) = error_cb {
-        let handle = Box::into_raw(Box::new(ErrorHandle { error_cb, userdata }));
-        start_fn(
-            &mut stream,
-            cdev,
-            cname,
-            config.into(),
-            Some(on_error),
-            handle as *mut c_void,
-        )
+    wrap: fn(Box<dyn BackendStream>, Config) -> ResampledStream,
+) -> Box<dyn BackendStream> {
+    if handle.sample_rate() == config.sample_rate {
+        handle
     } else {
-        start_fn(
-            &mut stream,
-            cdev,
-            cname,
-            config.into(),
-            None,
-            ptr::null_mut(),
-        )
-    };
-    parse_result_value(result, stream)
+        Box::new(wrap(handle, config))
+    }
 }
 
+// Called with errors that happen while the stream runs, possibly from the
+// audio thread. The stream owns it and drops it along with itself.
+pub(crate) type ErrorHandler = Box<dyn Fn(Error) + Send + Sync>;
+
 pub struct StreamBuilder {
     config: Config,
-    error_cb: Option<ErrorCallback>,
-    userdata: *mut c_void,
+    error_handler: Option<ErrorHandler>,
 }
 
 impl StreamBuilder {
@@ -320,49 +281,122 @@ impl StreamBuilder {
     pub fn new(config: Config) -> Self {
         Self {
             config,
-            error_cb: None,
-            userdata: ptr::null_mut(),
+            error_handler: None,
         }
     }
 
     #[inline]
-    pub fn error_cb<T>(mut self, error_cb: ErrorCallback, userdata: Option<T>) -> Self {
-        self.error_cb = Some(error_cb);
-        self.userdata = userdata
-            .map(|v| Box::into_raw(Box::new(v)) as *mut c_void)
-            .unwrap_or_else(|| ptr::null_mut());
+    pub fn on_error(mut self, on_error: impl Fn(Error) + Send + Sync + 'static) -> Self {
+        self.error_handler = Some(Box::new(on_error));
         self
     }
 
     #[inline]
     pub fn start_record(self, name: &str, device: Option<&str>) -> Result<RecordStream> {
-        self.start_stream(aw_start_record, name, device)
-            .map(|base| RecordStream { base })
+        let (backend, device) = find_backend(device);
+        let notify = Arc::new(Notify::new());
+        backend
+            .start_record(
+                name,
+                device,
+                self.config.device_config(),
+                self.error_handler,
+                Arc::clone(&notify),
+            )
+            .map(|handle| RecordStream {
+                base: BaseStream::new(
+                    resample(handle, self.config, ResampledStream::record),
+                    notify,
+                    self.config,
+                ),
+            })
     }
 
     #[inline]
     pub fn start_playback(self, name: &str, device: Option<&str>) -> Result<PlaybackStream> {
-        self.start_stream(aw_start_playback, name, device)
-            .map(|base| PlaybackStream { base })
-    }
-
-    #[inline]
-    fn start_stream(
-        self,
-        start_fn: StartStreamFn,
-        name: &str,
-        device: Option<&str>,
-    ) -> Result<BaseStream> {
-        let result = unsafe {
-            start_stream(
-                start_fn,
-                device,
+        let (backend, device) = find_backend(device);
+        let notify = Arc::new(Notify::new());
+        backend
+            .start_playback(
                 name,
-                self.config,
-                self.error_cb,
-                self.userdata,
+                device,
+                self.config.device_config(),
+                self.error_handler,
+                Arc::clone(&notify),
             )
-        };
-        result.map(|s| BaseStream::new(s))
+            .map(|handle| PlaybackStream {
+                base: BaseStream::new(
+                    resample(handle, self.config, ResampledStream::playback),
+                    notify,
+                    self.config,
+                ),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread::sleep,
+    };
+
+    use crate::{memory, SampleFormat};
+
+    use super::*;
+
+    const CONFIG: Config = Config {
+        channels: 2,
+        sample_rate: 48000,
+        sample_format: SampleFormat::S16,
+        buffer_frames: 480,
+        max_buffer_frames: 4800,
+        device_rate: None,
+    };
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn errors_reach_the_handler() {
+        let errors = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&errors);
+        let mut stream = StreamBuilder::new(CONFIG)
+            .on_error(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .start_playback("playback-test", Some("file:/dev/full"))
+            .expect("Failed to start playback stream");
+        // Enough to make the writer flush to a device that has no room
+        let data = vec![0u8; CONFIG.max_buffer_size()];
+        for _ in 0..4 {
+            stream.write(&data);
+            sleep(CONFIG.max_buffer_duration());
+        }
+        stream.write(&data);
+        assert!(errors.load(Ordering::Relaxed) > 0);
+        drop(stream);
+        assert_eq!(Arc::strong_count(&errors), 1);
+    }
+
+    #[test]
+    fn handler_is_dropped_with_the_stream() {
+        let owned = Arc::new(());
+        let captured = Arc::clone(&owned);
+        memory::add_sink("error-handler");
+        let stream = StreamBuilder::new(CONFIG)
+            .on_error(move |_| drop(Arc::clone(&captured)))
+            .start_playback("playback-test", Some("memory:error-handler"))
+            .expect("Failed to start playback stream");
+        assert_eq!(Arc::strong_count(&owned), 2);
+        drop(stream);
+        assert_eq!(Arc::strong_count(&owned), 1);
+        memory::remove_device("error-handler");
+
+        // Same when the stream never starts
+        let captured = Arc::clone(&owned);
+        let result = StreamBuilder::new(CONFIG)
+            .on_error(move |_| drop(Arc::clone(&captured)))
+            .start_record("record-test", Some("memory:unknown"));
+        assert!(result.is_err());
+        assert_eq!(Arc::strong_count(&owned), 1);
     }
 }
@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use toml::Table;
+
+pub const NONCE_SIZE: usize = 32;
+pub const PROOF_SIZE: usize = 32;
+
+const PROOF_CONTEXT: &[u8] = b"audiowire auth v1";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+pub fn new_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn proof_mac(secret: &[u8], nonce: &[u8; NONCE_SIZE], identity: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(PROOF_CONTEXT);
+    mac.update(nonce);
+    mac.update(identity.as_bytes());
+    mac
+}
+
+// The secret itself never goes over the wire, only a MAC of the server's
+// challenge and the identity the client claims
+pub fn prove(secret: &[u8], nonce: &[u8; NONCE_SIZE], identity: &str) -> [u8; PROOF_SIZE] {
+    proof_mac(secret, nonce, identity)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+// Secrets are read as text with the surrounding whitespace left out
+pub fn load_secret(path: &Path) -> Result<Vec<u8>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read secret file {}: {}", path.display(), e))?;
+    let secret = content.trim();
+    if secret.is_empty() {
+        return Err(format!("Secret file {} is empty", path.display()).into());
+    }
+    Ok(secret.as_bytes().to_vec())
+}
+
+// Tokens are a TOML table of client identities and their tokens:
+//
+//   kitchen = "first token"
+//   "living room" = "second token"
+pub fn load_tokens(path: &Path) -> Result<HashMap<String, Vec<u8>>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read tokens file {}: {}", path.display(), e))?;
+    let table: Table = toml::from_str(&content)
+        .map_err(|e| format!("Invalid tokens file {}: {}", path.display(), e))?;
+    table
+        .into_iter()
+        .map(|(identity, token)| match token.as_str() {
+            Some(token) if !token.is_empty() => Ok((identity, token.as_bytes().to_vec())),
+            _ => Err(format!(
+                "Token of {} in {} has to be a non-empty string",
+                identity,
+                path.display()
+            )
+            .into()),
+        })
+        .collect()
+}
+
+// Decides which clients get in. A client proves it holds the token of the
+// identity it claims, or the pre-shared key when there's no token for it.
+// Without any secret every client gets in, as long as the identity it claims
+// passes the allow and deny lists.
+#[derive(Default)]
+pub struct Authenticator {
+    pub psk: Option<Vec<u8>>,
+    pub tokens: HashMap<String, Vec<u8>>,
+    // Only these identities get in when set
+    pub allow: Option<HashSet<String>>,
+    pub deny: HashSet<String>,
+}
+
+impl Authenticator {
+    #[inline]
+    pub fn is_required(&self) -> bool {
+        self.psk.is_some() || !self.tokens.is_empty()
+    }
+
+    // Checks the identity the client claims, returning why it was turned away
+    pub fn admit(&self, identity: &str) -> std::result::Result<(), String> {
+        if self.deny.contains(identity) {
+            Err(format!("client {:?} is denied", identity))
+        } else if self
+            .allow
+            .as_ref()
+            .is_some_and(|allow| !allow.contains(identity))
+        {
+            Err(format!("client {:?} is not allowed", identity))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn verify(
+        &self,
+        identity: &str,
+        nonce: &[u8; NONCE_SIZE],
+        proof: &[u8; PROOF_SIZE],
+    ) -> std::result::Result<(), String> {
+        let secret = self
+            .tokens
+            .get(identity)
+            .or(self.psk.as_ref())
+            .ok_or_else(|| format!("no token for client {:?}", identity))?;
+        proof_mac(secret, nonce, identity)
+            .verify_slice(proof)
+            .map_err(|_| format!("client {:?} failed the challenge", identity))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitConfig {
+    // Failed attempts an address may make within the window
+    pub max_failures: u32,
+    pub window: Duration,
+    // How long an address is turned away for once it runs out of attempts
+    pub lockout: Duration,
+}
+
+pub const DEFAULT_RATE_LIMIT: RateLimitConfig = RateLimitConfig {
+    max_failures: 5,
+    window: Duration::from_secs(60),
+    lockout: Duration::from_secs(300),
+};
+
+struct Failures {
+    count: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+// Counts failed attempts per address so nobody can keep guessing secrets
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    failures: HashMap<IpAddr, Failures>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            failures: HashMap::new(),
+        }
+    }
+
+    // Returns how much longer the address stays locked out, if it is
+    pub fn locked(&self, addr: IpAddr, now: Instant) -> Option<Duration> {
+        self.failures
+            .get(&addr)
+            .and_then(|failures| failures.locked_until)
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    // Records a failed attempt, returning true when it locks the address out
+    pub fn fail(&mut self, addr: IpAddr, now: Instant) -> bool {
+        let config = self.config;
+        // Forget whoever hasn't failed in a while so the map can't grow unbounded
+        self.failures.retain(|_, failures| {
+            failures.locked_until.map_or_else(
+                || now.duration_since(failures.since) < config.window,
+                |until| until > now,
+            )
+        });
+
+        let failures = self.failures.entry(addr).or_insert(Failures {
+            count: 0,
+            since: now,
+            locked_until: None,
+        });
+        if failures.locked_until.is_some() {
+            return false;
+        }
+        failures.count += 1;
+        if failures.count >= config.max_failures {
+            failures.locked_until = Some(now + config.lockout);
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    pub fn succeed(&mut self, addr: IpAddr) {
+        self.failures.remove(&addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn verify_proofs() {
+        let auth = Authenticator {
+            psk: Some(b"shared".to_vec()),
+            tokens: HashMap::from([("kitchen".to_owned(), b"token".to_vec())]),
+            ..Default::default()
+        };
+        let nonce = new_nonce();
+        assert!(auth
+            .verify("kitchen", &nonce, &prove(b"token", &nonce, "kitchen"))
+            .is_ok());
+        // Identities with a token of their own can't fall back on the key
+        assert!(auth
+            .verify("kitchen", &nonce, &prove(b"shared", &nonce, "kitchen"))
+            .is_err());
+        assert!(auth
+            .verify("attic", &nonce, &prove(b"shared", &nonce, "attic"))
+            .is_ok());
+        // Proofs are bound to the challenge and the identity
+        assert!(auth
+            .verify("attic", &new_nonce(), &prove(b"shared", &nonce, "attic"))
+            .is_err());
+        assert!(auth
+            .verify("cellar", &nonce, &prove(b"shared", &nonce, "attic"))
+            .is_err());
+
+        let auth = Authenticator {
+            tokens: HashMap::from([("kitchen".to_owned(), b"token".to_vec())]),
+            ..Default::default()
+        };
+        assert!(auth
+            .verify("attic", &nonce, &prove(b"", &nonce, "attic"))
+            .is_err());
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let auth = Authenticator {
+            allow: Some(HashSet::from(["kitchen".to_owned(), "attic".to_owned()])),
+            deny: HashSet::from(["attic".to_owned()]),
+            ..Default::default()
+        };
+        assert!(!auth.is_required());
+        assert!(auth.admit("kitchen").is_ok());
+        assert!(auth.admit("attic").unwrap_err().contains("denied"));
+        assert!(auth.admit("cellar").unwrap_err().contains("not allowed"));
+    }
+
+    #[test]
+    fn lock_out_after_failures() {
+        let config = RateLimitConfig {
+            max_failures: 3,
+            window: Duration::from_secs(10),
+            lockout: Duration::from_secs(30),
+        };
+        let mut limiter = RateLimiter::new(config);
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(!limiter.fail(addr, start));
+        assert!(!limiter.fail(addr, start + Duration::from_secs(1)));
+        assert!(limiter.locked(addr, start).is_none());
+        assert!(limiter.fail(addr, start + Duration::from_secs(2)));
+        assert_eq!(
+            limiter.locked(addr, start + Duration::from_secs(2)),
+            Some(config.lockout)
+        );
+        assert!(limiter.locked(other, start).is_none());
+        assert!(limiter
+            .locked(addr, start + Duration::from_secs(32))
+            .is_none());
+
+        // Failures spread wider than the window are forgiven
+        limiter.fail(other, start);
+        limiter.fail(other, start + Duration::from_secs(5));
+        assert!(!limiter.fail(other, start + Duration::from_secs(20)));
+        assert!(limiter
+            .locked(other, start + Duration::from_secs(20))
+            .is_none());
+
+        limiter.succeed(other);
+        assert!(!limiter.fail(other, start + Duration::from_secs(21)));
+    }
+
+    #[test]
+    fn read_tokens_file() {
+        let path = env::temp_dir().join(format!("audiowire-{}-tokens.toml", process::id()));
+        fs::write(&path, "kitchen = \"first\"\n\"living room\" = \"second\"\n").unwrap();
+        let tokens = load_tokens(&path).unwrap();
+        fs::write(&path, "kitchen = 1\n").unwrap();
+        let invalid = load_tokens(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens["kitchen"], b"first");
+        assert_eq!(tokens["living room"], b"second");
+        assert!(invalid.is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+#[derive(Clone, Copy, Debug)]
+pub struct BackoffConfig {
+    // Delay before the first retry, doubles with every one after it
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+// Exponential backoff with jitter. Only the upper half of every delay is
+// random, so clients that lost the server at the same time spread their
+// retries out while none of them retries sooner than half the delay.
+pub struct Backoff {
+    config: BackoffConfig,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self {
+            config,
+            attempts: 0,
+        }
+    }
+
+    // Retries since the last reset
+    #[inline]
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    #[inline]
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    // Longest the next delay can be
+    pub fn ceiling(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.attempts).unwrap_or(u32::MAX);
+        self.config
+            .initial
+            .saturating_mul(factor)
+            .min(self.config.max)
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        self.delay_with(OsRng.next_u32() as f64 / u32::MAX as f64)
+    }
+
+    // Same as next_delay with the random part given, from 0 to 1
+    pub fn delay_with(&mut self, random: f64) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempts = self.attempts.saturating_add(1);
+        let half = ceiling / 2;
+        half + (ceiling - half).mul_f64(random.clamp(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: BackoffConfig = BackoffConfig {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn doubles_up_to_max() {
+        let mut backoff = Backoff::new(CONFIG);
+        let delays: Vec<_> = (0..6).map(|_| backoff.delay_with(1.0).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.attempts(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.ceiling(), CONFIG.initial);
+    }
+
+    #[test]
+    fn jitter_stays_in_upper_half() {
+        let mut backoff = Backoff::new(CONFIG);
+        backoff.delay_with(0.0);
+        assert_eq!(backoff.delay_with(0.0), Duration::from_secs(1));
+        assert_eq!(backoff.delay_with(0.5), Duration::from_secs(3));
+        for _ in 0..100 {
+            let ceiling = backoff.ceiling();
+            let delay = backoff.next_delay();
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+        }
+    }
+
+    #[test]
+    fn survives_many_attempts() {
+        let mut backoff = Backoff::new(CONFIG);
+        for _ in 0..100 {
+            backoff.delay_with(1.0);
+        }
+        assert_eq!(backoff.delay_with(1.0), CONFIG.max);
+    }
+}
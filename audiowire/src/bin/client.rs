@@ -1,142 +1,516 @@
 use std::{
-    env,
     error::Error,
-    fmt::Display,
-    future::Future,
-    sync::{atomic::Ordering, Arc},
+    io::{self, BufRead},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
     time::Duration,
 };
 
 use audiowire::{
-    handlers::{check_audio, handle_playback, handle_record, handle_signal},
+    auth,
+    backoff::{Backoff, BackoffConfig},
+    cli::{self, AudioOptions, CodecOptions, HeartbeatOptions, DEFAULT_PORT},
+    control::{Control, StreamControl},
+    handlers::{
+        check_audio, handle_control, handle_playback, handle_record, handle_shutdown, PeerFormat,
+    },
+    handshake::{client_handshake, Codec, HandshakeError, Hello},
     logging,
-    peer::{PeerReadHalf, PeerWriteHalf},
-    Config, StreamFlags, StreamType, DEFAULT_CONFIG,
+    mux::{self, HeartbeatConfig},
+    peer::{PeerPacketRead, PeerPacketWrite, PeerReadHalf, PeerWriteHalf, Transport, UdpPeer},
+    secure,
+    session::{Session, SessionHandle},
+    StreamType, DEFAULT_CONFIG,
 };
-use slog::{error, info, o, Logger};
-use tokio::{net::TcpStream, time::sleep};
+use clap::Parser;
+use rustls::pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+use slog::{error, info, o, warn, Logger};
+use tokio::{
+    net::{lookup_host, TcpStream, UdpSocket},
+    time::{sleep, timeout},
+};
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
+use x25519_dalek::PublicKey;
+
+const DEFAULT_RETRY_DELAY: u64 = 3;
+const DEFAULT_MAX_RETRY_DELAY: u64 = 60;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const UDP_BACKLOG: usize = 64;
+
+#[derive(Parser, Serialize, Deserialize, Default)]
+#[command(
+    name = "audiowire-client",
+    about = "Streams audio to and from an audiowire server"
+)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    #[arg(long, help = "TOML config file, flags take precedence over it")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    #[arg(long, help = "List audio devices and exit")]
+    #[serde(skip)]
+    list_devices: bool,
+    #[arg(long, help = "Server host to connect to, optionally with a port")]
+    connect: Option<String>,
+    #[arg(long, help = "Server port unless given with the host [default: 8760]")]
+    port: Option<u16>,
+    #[arg(long, help = "Transport: tcp or udp [default: tcp]")]
+    transport: Option<Transport>,
+    #[arg(
+        long,
+        help = "Pinned PEM certificate of the server, encrypts tcp sessions with TLS"
+    )]
+    server_cert: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Pinned public key of the server in hex, encrypts udp sessions"
+    )]
+    server_key: Option<String>,
+    #[arg(long, help = "Name to identify this client to the server with")]
+    identity: Option<String>,
+    #[arg(
+        long,
+        help = "File with the pre-shared key or token to authenticate with"
+    )]
+    secret_file: Option<PathBuf>,
+    #[arg(long, help = "Record device to stream to the server, null to disable")]
+    input: Option<String>,
+    #[arg(long, help = "Playback device for the server stream, null to disable")]
+    output: Option<String>,
+    #[arg(
+        long,
+        help = "Failed connection attempts in a row before giving up, counted again after every session [default: retry forever]"
+    )]
+    max_retry: Option<u32>,
+    #[arg(
+        long,
+        help = "Seconds to wait before the first retry, doubles with each one [default: 3]"
+    )]
+    retry_delay: Option<u64>,
+    #[arg(long, help = "Most seconds to wait between retries [default: 60]")]
+    max_retry_delay: Option<u64>,
+    #[arg(long, help = "Leave timestamps out of the log")]
+    #[serde(default, skip_serializing_if = "cli::is_false")]
+    no_timestamps: bool,
+    #[command(flatten)]
+    #[serde(default)]
+    audio: AudioOptions,
+    #[command(flatten)]
+    #[serde(default)]
+    codec: CodecOptions,
+    #[command(flatten)]
+    #[serde(default)]
+    heartbeat: HeartbeatOptions,
+}
+
+// What the session runs over, encrypted when the server is pinned
+enum Link {
+    Tcp(Option<TlsConnector>),
+    Udp(Option<PublicKey>),
+}
 
-const MAX_RETRY: u8 = 5;
-const RETRY_DURATION: Duration = Duration::from_secs(3);
+#[derive(Clone, Copy)]
+struct Retry {
+    // Failed attempts in a row before giving up, None to keep trying
+    max: Option<u32>,
+    backoff: BackoffConfig,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    let mut args = env::args();
-    if let Some(addr) = args.nth(1) {
-        init(addr, args).await.map_err(|e| e.to_string())
-    } else {
-        Err("Address argument is required".to_string())
+    let flags = Options::parse();
+    if flags.list_devices {
+        return cli::list_devices().map_err(|e| e.to_string());
     }
+    let config_path = flags.config.clone();
+    let options = cli::layer(flags, config_path.as_deref()).map_err(|e| e.to_string())?;
+    init(options).await.map_err(|e| e.to_string())
 }
 
-async fn init(addr: String, mut args: env::Args) -> Result<(), Box<dyn Error>> {
-    let config = DEFAULT_CONFIG;
-    let input = args.next();
-    let output = args.next();
-    let opus_disabled = env::var("OPUS_DISABLED")
-        .map(|s| s == "1")
-        .unwrap_or_default();
-    let logger = logging::term_logger();
+async fn init(options: Options) -> Result<(), Box<dyn Error>> {
+    let link = link(&options)?;
+    let identity = options.identity.clone().unwrap_or_default();
+    if identity.len() > u8::MAX as usize {
+        return Err("Identity can't be longer than 255 bytes".into());
+    }
+    let secret = options
+        .secret_file
+        .as_deref()
+        .map(auth::load_secret)
+        .transpose()?;
+    let host = options
+        .connect
+        .ok_or("Server address is required, pass --connect or set connect in the config file")?;
+    let addr = cli::with_port(&host, options.port.unwrap_or(DEFAULT_PORT));
+    let retry = Retry {
+        max: options.max_retry,
+        backoff: BackoffConfig {
+            initial: Duration::from_secs(options.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY)),
+            max: Duration::from_secs(options.max_retry_delay.unwrap_or(DEFAULT_MAX_RETRY_DELAY)),
+        },
+    };
+    let config = options.audio.config(DEFAULT_CONFIG)?;
+    let heartbeat = options.heartbeat.config()?;
+    let input = options.input;
+    let output = options.output;
+    let logger = logging::term_logger(options.no_timestamps);
+    if matches!(link, Link::Tcp(None) | Link::Udp(None)) {
+        warn!(logger, "Sessions are not encrypted");
+    }
 
     audiowire::initialize()?;
     check_audio(&logger, config, input.as_deref(), output.as_deref())?;
-    let result = run(&addr, config, &logger, input, output, opus_disabled).await;
+    let client_type = StreamType::new(
+        input.as_ref().map(|s| s != "null").unwrap_or(true),
+        output.as_ref().map(|s| s != "null").unwrap_or(true),
+    );
+    let hello = Hello {
+        stream_type: client_type,
+        config,
+        codecs: options.codec.codecs(),
+        opus: options.codec.opus_settings()?,
+        identity,
+    };
+    let params = SessionParams {
+        addr: &addr,
+        hello,
+        secret,
+        heartbeat,
+        input_name: input,
+        output_name: output,
+    };
+    let result = run(link, retry, params, &logger).await;
     audiowire::terminate()?;
 
     result
 }
 
+fn link(options: &Options) -> Result<Link, Box<dyn Error>> {
+    match options.transport.unwrap_or(Transport::Tcp) {
+        Transport::Tcp if options.server_key.is_some() => Err(
+            "--server-key only applies to udp, pin a certificate with --server-cert for tcp".into(),
+        ),
+        Transport::Tcp => {
+            let tls = match options.server_cert.as_deref() {
+                Some(path) => Some(secure::tls_connector(secure::load_certs(path)?)?),
+                None => None,
+            };
+            Ok(Link::Tcp(tls))
+        }
+        Transport::Udp if options.server_cert.is_some() => {
+            Err("--server-cert only applies to tcp, pin a key with --server-key for udp".into())
+        }
+        Transport::Udp => Ok(Link::Udp(
+            options
+                .server_key
+                .as_deref()
+                .map(secure::parse_public_key)
+                .transpose()?,
+        )),
+    }
+}
+
+// Keeps a session going until shutdown. Whenever the connection drops the
+// streams are torn down and the client reconnects, backing off while the
+// server can't be reached.
 async fn run(
-    addr: &str,
-    config: Config,
+    link: Link,
+    retry: Retry,
+    params: SessionParams<'_>,
     root_logger: &Logger,
-    input_name: Option<String>,
-    output_name: Option<String>,
-    opus_disabled: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let mut buf = [0u8; 16];
-    let client_type = StreamType::new(
-        input_name.as_ref().map(|s| s != "null").unwrap_or(true),
-        output_name.as_ref().map(|s| s != "null").unwrap_or(true),
-    );
-    let stream_flags = StreamFlags::new(client_type, !opus_disabled);
+    let shutdown = handle_shutdown()?;
+    let active = Arc::new(Mutex::new(None));
+    handle_commands(Arc::clone(&active), root_logger.clone());
+    let mut backoff = Backoff::new(retry.backoff);
+    loop {
+        let started = tokio::select! {
+            result = start_session(&link, &params, &shutdown, root_logger) => result,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        match started {
+            Ok((mut handle, control)) => {
+                *active.lock().unwrap() = Some(control);
+                let result = handle.join().await;
+                active.lock().unwrap().take();
+                result?;
+                if shutdown.is_cancelled() {
+                    info!(root_logger, "Connection terminated");
+                    return Ok(());
+                }
+                warn!(root_logger, "Connection lost");
+                // Not meant to reconnect at all
+                if retry.max == Some(0) {
+                    return Ok(());
+                }
+                backoff.reset();
+            }
+            Err(err) if is_transient(err.as_ref()) => {
+                error!(root_logger, "{}", err);
+                if retry.max.is_some_and(|max| backoff.attempts() >= max) {
+                    return Err(err);
+                }
+            }
+            Err(err) => return Err(err),
+        }
 
-    info!(root_logger, "Connecting to server: {}", addr);
-    let socket = with_retry(&root_logger, || TcpStream::connect(addr)).await?;
-    info!(root_logger, "Connected to server: {}", socket.peer_addr()?);
-
-    let (mut input, mut output) = socket.into_split();
-    output.write_all(&stream_flags.to_bytes()).await?;
-    input.read_exact(&mut buf[..1]).await?;
-    let server_type = StreamType::from(buf.as_slice());
-
-    let mut handles = Vec::new();
-    let term = handle_signal()?;
-    let logger = root_logger.new(o!("opus" => !opus_disabled));
-
-    if client_type.is_source() && server_type.is_sink() {
-        let handle = handle_record(
-            Arc::clone(&term),
-            config.clone(),
-            input_name,
-            addr.to_owned(),
-            logger.new(o!("stream" => "record")),
-            output,
-            !opus_disabled,
-        )?;
-        handles.push(handle);
+        let delay = backoff.next_delay();
+        match retry.max {
+            Some(max) => info!(
+                root_logger,
+                "Reconnecting in {:.1} second(s) ({} retries left)",
+                delay.as_secs_f32(),
+                max.saturating_sub(backoff.attempts() - 1)
+            ),
+            None => info!(
+                root_logger,
+                "Reconnecting in {:.1} second(s), attempt {}",
+                delay.as_secs_f32(),
+                backoff.attempts()
+            ),
+        }
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
     }
+}
 
-    if client_type.is_sink() && server_type.is_source() {
-        let handle = handle_playback(
-            Arc::clone(&term),
-            config.clone(),
-            output_name,
-            addr.to_owned(),
-            logger.new(o!("stream" => "playback")),
-            input,
-            !opus_disabled,
-        )?;
-        handles.push(handle);
+// Network trouble is worth retrying, the server turning the client down or a
+// broken audio device are not
+fn is_transient(err: &(dyn Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<HandshakeError>() {
+        return matches!(err, HandshakeError::Io(_));
     }
+    err.is::<io::Error>()
+}
 
-    for handle in handles {
-        handle.await?;
-        term.store(true, Ordering::Relaxed);
+// Commands typed on stdin, one per line, go to the server over the session
+// that's up at the time. Reading stdin blocks, so it gets a thread of its own
+// that doesn't hold up the runtime when shutting down.
+fn handle_commands(active: Arc<Mutex<Option<StreamControl>>>, logger: Logger) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message: Control = match line.parse() {
+                Ok(message) => message,
+                Err(err) => {
+                    warn!(logger, "{}", err);
+                    continue;
+                }
+            };
+            let Some(control) = active.lock().unwrap().clone() else {
+                warn!(logger, "Not connected, dropping: {}", message);
+                continue;
+            };
+            match control.send(&message) {
+                Ok(()) => info!(logger, "Sent: {}", message),
+                Err(err) => warn!(logger, "Failed to send {}: {}", message, err),
+            }
+        }
+    });
+}
+
+#[inline]
+fn timed_out(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
+
+async fn start_session(
+    link: &Link,
+    params: &SessionParams<'_>,
+    shutdown: &CancellationToken,
+    root_logger: &Logger,
+) -> Result<(SessionHandle, StreamControl), Box<dyn Error>> {
+    let addr = params.addr;
+    info!(root_logger, "Connecting to server: {}", addr);
+    match link {
+        Link::Tcp(tls) => {
+            let socket = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+                .await
+                .map_err(|_| timed_out("Connection timed out"))??;
+            let server_addr = socket.peer_addr()?;
+            info!(root_logger, "Connected to server: {}", server_addr);
+            let Some(connector) = tls else {
+                let (input, output) = socket.into_split();
+                return begin_session(params, shutdown, root_logger, input, output).await;
+            };
+            // The pinned certificate is what gets checked, not the name
+            let name = ServerName::IpAddress(server_addr.ip().into());
+            let stream = timeout(HANDSHAKE_TIMEOUT, connector.connect(name, socket))
+                .await
+                .map_err(|_| timed_out("TLS handshake timed out"))??;
+            info!(root_logger, "TLS established with the pinned certificate");
+            let (input, output) = tokio::io::split(stream);
+            begin_session(params, shutdown, root_logger, input, output).await
+        }
+        Link::Udp(server_key) => {
+            let server_addr = lookup_host(addr).await?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "Failed to resolve server address")
+            })?;
+            let (input, output) = connect_udp(server_addr).await?;
+            info!(root_logger, "Sending to server: {} (udp)", server_addr);
+            let Some(server_key) = server_key else {
+                return begin_session(params, shutdown, root_logger, input, output).await;
+            };
+            let (input, output) = timeout(
+                HANDSHAKE_TIMEOUT,
+                secure::connect_datagram(input, output, server_key),
+            )
+            .await
+            .map_err(|_| timed_out("Key exchange timed out"))??;
+            info!(root_logger, "Keys exchanged with the pinned server key");
+            begin_session(params, shutdown, root_logger, input, output).await
+        }
     }
+}
 
-    info!(root_logger, "Connection terminated");
-    Ok(())
+// The socket only ever talks to the server, so everything it receives is fed
+// straight into the peer's backlog.
+async fn connect_udp(
+    server_addr: SocketAddr,
+) -> Result<
+    (
+        impl PeerReadHalf + PeerPacketRead + Send,
+        impl PeerWriteHalf + PeerPacketWrite + Send,
+    ),
+    Box<dyn Error>,
+> {
+    let bind_addr = if server_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+    socket.connect(server_addr).await?;
+
+    let (input, output, producer) =
+        UdpPeer::new(Arc::clone(&socket), server_addr, UDP_BACKLOG).into_split();
+    // Ends along with the read half, whether the attempt failed or the session
+    // is over, even when the server has gone quiet
+    tokio::spawn(async move {
+        let mut buf = [0u8; 65536];
+        loop {
+            let len = tokio::select! {
+                result = socket.recv(&mut buf) => match result {
+                    Ok(len) => len,
+                    Err(_) => break,
+                },
+                _ = producer.closed() => break,
+            };
+            if producer.send(&buf[..len]).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok((input, output))
 }
 
-async fn with_retry<T, E, F, G>(logger: &Logger, g: G) -> F::Output
+struct SessionParams<'a> {
+    addr: &'a str,
+    hello: Hello,
+    // Proves the identity in the hello when the server asks for it
+    secret: Option<Vec<u8>>,
+    heartbeat: HeartbeatConfig,
+    input_name: Option<String>,
+    output_name: Option<String>,
+}
+
+// Negotiates with the server and starts the streams, handing back the session
+// to wait on and the control to steer it with
+async fn begin_session<R, W>(
+    params: &SessionParams<'_>,
+    shutdown: &CancellationToken,
+    root_logger: &Logger,
+    mut input: R,
+    mut output: W,
+) -> Result<(SessionHandle, StreamControl), Box<dyn Error>>
 where
-    E: Display,
-    F: Future<Output = Result<T, E>>,
-    G: Fn() -> F,
+    R: PeerReadHalf + PeerPacketRead + Send + 'static,
+    W: PeerWriteHalf + PeerPacketWrite + Send + 'static,
 {
-    let mut retry = 0;
-    loop {
-        let err = match g().await {
-            Ok(v) => return Ok(v),
-            Err(e) => {
-                error!(logger, "{}", e);
-                e
-            }
-        };
-        if retry >= MAX_RETRY {
-            return Err(err);
-        }
-        let duration = RETRY_DURATION.mul_f32((retry + 1) as f32);
-        info!(
-            logger,
-            "Retrying in {} second(s) ({} retries left)",
-            duration.as_secs(),
-            MAX_RETRY - retry
-        );
-        if !duration.is_zero() {
-            sleep(duration).await;
-        }
-        retry += 1;
+    let SessionParams {
+        addr,
+        hello,
+        secret,
+        heartbeat,
+        input_name,
+        output_name,
+    } = params;
+    let negotiated = timeout(
+        HANDSHAKE_TIMEOUT,
+        client_handshake(&mut input, &mut output, hello, secret.as_deref()),
+    )
+    .await
+    .map_err(|_| timed_out("Handshake timed out"))??;
+    let client_type = hello.stream_type;
+    let config = hello.config;
+    let server_type = negotiated.peer_type;
+    let record = client_type.is_source() && server_type.is_sink();
+    let playback = client_type.is_sink() && server_type.is_source();
+    if !record && !playback {
+        // Otherwise every session would end as soon as it started
+        return Err("Nothing to stream, neither end records what the other plays".into());
+    }
+    let opus_enabled = negotiated.codec == Codec::Opus;
+    info!(
+        root_logger,
+        "Handshake completed, codec: {}", negotiated.codec
+    );
+    if opus_enabled {
+        info!(root_logger, "Server encodes with {}", negotiated.peer_opus);
+    }
+
+    let mut session = Session::new(shutdown);
+    let (mux, messages, input, output) = mux::start(
+        session.token(),
+        shutdown.clone(),
+        *heartbeat,
+        root_logger.clone(),
+        input,
+        output,
+    );
+    let control = StreamControl::connected(session.token(), mux);
+    handle_control(control.clone(), messages, root_logger.clone());
+    let logger = root_logger.new(o!("opus" => opus_enabled));
+
+    if record {
+        session.set_record(handle_record(
+            control.clone(),
+            config,
+            input_name.clone(),
+            addr.to_string(),
+            logger.new(o!("stream" => "record")),
+            output,
+            opus_enabled.then_some(hello.opus),
+        )?);
     }
+
+    if playback {
+        session.set_playback(handle_playback(
+            control.clone(),
+            config,
+            output_name.clone(),
+            addr.to_string(),
+            logger.new(o!("stream" => "playback")),
+            input,
+            PeerFormat {
+                channels: negotiated.peer_channels,
+                opus: opus_enabled.then_some(negotiated.peer_opus),
+            },
+        )?);
+    }
+
+    Ok((session.start(), control))
 }
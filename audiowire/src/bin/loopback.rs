@@ -1,34 +1,68 @@
-use std::{env, error::Error, ffi::c_void, ptr, sync::atomic::Ordering, thread::sleep};
+use std::{error::Error, path::PathBuf};
 
 use audiowire::{
-    handlers::handle_signal, initialize, logging::term_logger, terminate, Config, SampleFormat,
-    Stream, StreamBuilder,
+    cli::{self, AudioOptions},
+    handlers::{handle_shutdown, log_stream_errors},
+    initialize,
+    logging::term_logger,
+    terminate, Config, SampleFormat, Stream, StreamBuilder,
 };
-use slog::{error, info, Logger};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use slog::info;
 
-fn error_cb(err: i32, message: &str, userdata: *mut c_void) {
-    let logger = unsafe { &ptr::read(userdata as *mut Logger) };
-    error!(logger, "Error {}: {}", err, message);
+const LOOPBACK_CONFIG: Config = Config {
+    channels: 2,
+    sample_rate: 48000,
+    sample_format: SampleFormat::F32,
+    buffer_frames: 480,
+    max_buffer_frames: 4800,
+    device_rate: None,
+};
+
+#[derive(Parser, Serialize, Deserialize, Default)]
+#[command(
+    name = "audiowire-loopback",
+    about = "Plays back a record device locally"
+)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    #[arg(long, help = "TOML config file, flags take precedence over it")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    #[arg(long, help = "List audio devices and exit")]
+    #[serde(skip)]
+    list_devices: bool,
+    #[arg(long, help = "Record device")]
+    input: Option<String>,
+    #[arg(long, help = "Playback device")]
+    output: Option<String>,
+    #[arg(long, help = "Leave timestamps out of the log")]
+    #[serde(default, skip_serializing_if = "cli::is_false")]
+    no_timestamps: bool,
+    #[command(flatten)]
+    #[serde(default)]
+    audio: AudioOptions,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = env::args();
-    let input = args.nth(1);
-    let output = args.next();
-    let config = Config {
-        channels: 2,
-        sample_rate: 48000,
-        sample_format: SampleFormat::F32,
-        buffer_frames: 480,
-        max_buffer_frames: 4800,
-    };
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let flags = Options::parse();
+    if flags.list_devices {
+        return cli::list_devices();
+    }
+    let config_path = flags.config.clone();
+    let options = cli::layer(flags, config_path.as_deref())?;
+    let input = options.input;
+    let output = options.output;
+    let config = options.audio.config(LOOPBACK_CONFIG)?;
 
     initialize()?;
 
-    let logger = term_logger();
+    let logger = term_logger(options.no_timestamps);
 
     let mut record = StreamBuilder::new(config)
-        .error_cb(error_cb, Some(logger.clone()))
+        .on_error(log_stream_errors(&logger))
         .start_record("Source", input.as_deref())?;
     record
         .device_name()
@@ -36,30 +70,32 @@ fn main() -> Result<(), Box<dyn Error>> {
         .unwrap_or_else(|| info!(logger, "Record started"));
 
     let mut playback = StreamBuilder::new(config)
-        .error_cb(error_cb, Some(logger.clone()))
+        .on_error(log_stream_errors(&logger))
         .start_playback("Sink", output.as_deref())?;
     playback
         .device_name()
         .map(|s| info!(logger, "Playback started, device: {}", s))
         .unwrap_or_else(|| info!(logger, "Playback started"));
 
-    let term = handle_signal()?;
-    let duration = config.buffer_duration();
+    let shutdown = handle_shutdown()?;
     let bufsize = config.buffer_size();
-    let mut buf = [0u8; 65536];
-    while !term.load(Ordering::Relaxed) {
+    let mut buf = vec![0u8; bufsize];
+    loop {
+        tokio::select! {
+            _ = record.readable(bufsize) => {}
+            _ = shutdown.cancelled() => break,
+        }
         while record.peek() >= bufsize {
-            let read = record.read(&mut buf[..bufsize]);
+            let read = record.read(&mut buf);
             playback.write(&buf[..read]);
         }
-        sleep(duration);
     }
 
     record.stop()?;
     info!(logger, "Record stopped");
 
     playback.stop()?;
-    info!(logger, "PLayback stopped");
+    info!(logger, "Playback stopped");
 
     terminate()?;
 
@@ -1,162 +1,635 @@
 use std::{
-    env,
+    collections::{HashMap, HashSet},
     error::Error,
     net::SocketAddr,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use audiowire::{
-    handlers::{check_audio, handle_playback, handle_record, handle_signal},
+    auth::{self, Authenticator, RateLimiter, DEFAULT_RATE_LIMIT},
+    cli::{self, AudioOptions, CodecOptions, HeartbeatOptions, DEFAULT_PORT},
+    control::StreamControl,
+    handlers::{
+        check_audio, handle_control, handle_fanout, handle_fanout_record, handle_mixed_playback,
+        handle_mixer, handle_shutdown, PeerFormat,
+    },
+    handshake::{server_handshake, Codec, HandshakeError},
     logging,
-    peer::PeerWriteHalf,
-    Config, StreamFlags, StreamType, DEFAULT_CONFIG,
+    mixer::{Fanout, Mixer},
+    mux::{self, HeartbeatConfig},
+    opus::OpusSettings,
+    peer::{
+        PeerPacketRead, PeerPacketWrite, PeerReadHalf, PeerWriteHalf, Transport, UdpPeer,
+        UdpPeerProducer, UdpPeerReadHalf, UdpPeerWriteHalf,
+    },
+    secure,
+    session::{Session, SessionHandle},
+    Config, StreamType, DEFAULT_CONFIG,
 };
-use slog::{error, info, o, Logger};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use slog::{error, info, o, warn, Logger};
 use tokio::{
-    io::AsyncReadExt,
-    net::{TcpListener, TcpStream},
-    time::timeout,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{self, timeout_at},
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const LISTEN_HOST: &str = "0.0.0.0";
+const DEFAULT_CLIENT_GAIN: f32 = 1.0;
+const UDP_BACKLOG: usize = 64;
+// Covers the TLS or key exchange and the handshake together, the client
+// allows each of them 5 seconds
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Clients still connecting at once, anyone past that is turned away
+const MAX_PENDING_CLIENTS: usize = 64;
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+#[derive(Parser, Serialize, Deserialize, Default)]
+#[command(
+    name = "audiowire-server",
+    about = "Streams audio to and from audiowire clients"
+)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    #[arg(long, help = "TOML config file, flags take precedence over it")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    #[arg(long, help = "List audio devices and exit")]
+    #[serde(skip)]
+    list_devices: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write a new secret key for --udp-key to the file, print its public key and exit"
+    )]
+    #[serde(skip)]
+    generate_key: Option<PathBuf>,
+    #[arg(long, help = "Address to listen on [default: 0.0.0.0]")]
+    listen: Option<String>,
+    #[arg(long, help = "Port to listen on [default: 8760]")]
+    port: Option<u16>,
+    #[arg(long, help = "Transport: tcp or udp [default: tcp]")]
+    transport: Option<Transport>,
+    #[arg(long, help = "PEM certificate chain to encrypt tcp sessions with TLS")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, help = "PEM private key of the --tls-cert certificate")]
+    tls_key: Option<PathBuf>,
+    #[arg(long, help = "File with the secret key to encrypt udp sessions with")]
+    udp_key: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "File with a pre-shared key clients have to prove they hold"
+    )]
+    psk_file: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "TOML file of client identities and the token each has to prove it holds"
+    )]
+    tokens_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "IDENTITY",
+        help = "Only let clients with this identity in, can be given more than once"
+    )]
+    allow: Option<Vec<String>>,
+    #[arg(
+        long,
+        value_name = "IDENTITY",
+        help = "Turn clients with this identity away, can be given more than once"
+    )]
+    deny: Option<Vec<String>>,
+    #[arg(long, help = "Record device to stream to clients, null to disable")]
+    input: Option<String>,
+    #[arg(long, help = "Playback device for client streams, null to disable")]
+    output: Option<String>,
+    #[arg(
+        long,
+        help = "Gain applied to every client mixed into the playback device [default: 1.0]"
+    )]
+    client_gain: Option<f32>,
+    #[arg(long, help = "Leave timestamps out of the log")]
+    #[serde(default, skip_serializing_if = "cli::is_false")]
+    no_timestamps: bool,
+    #[command(flatten)]
+    #[serde(default)]
+    audio: AudioOptions,
+    #[command(flatten)]
+    #[serde(default)]
+    codec: CodecOptions,
+    #[command(flatten)]
+    #[serde(default)]
+    heartbeat: HeartbeatOptions,
+}
+
+// Every source client is mixed into the one playback stream and every sink
+// client gets a copy of the one record stream, so any number of them can
+// share the devices.
+struct Server {
+    listen_addr: String,
+    config: Config,
+    codecs: Vec<Codec>,
+    opus: OpusSettings,
+    client_gain: f32,
+    heartbeat: HeartbeatConfig,
+    mixer: Option<Mixer>,
+    fanout: Option<Fanout>,
+    server_type: StreamType,
+    tls: Option<TlsAcceptor>,
+    udp_key: Option<StaticSecret>,
+    auth: Authenticator,
+    // Addresses that failed to authenticate too often get turned away
+    limiter: Mutex<RateLimiter>,
+    // Slots for clients that haven't started their session yet
+    pending: Arc<Semaphore>,
+    // Parent of every session token, cancelled on shutdown
+    shutdown: CancellationToken,
+}
+
+// A client that hasn't started its session yet
+struct PendingClient {
+    addr: SocketAddr,
+    // Everything before the session has to be done by then
+    deadline: time::Instant,
+    // Given back once the session starts or the client fails to get there
+    _slot: OwnedSemaphorePermit,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let flags = Options::parse();
+    if flags.list_devices {
+        return cli::list_devices();
+    }
+    if let Some(path) = flags.generate_key.as_deref() {
+        return generate_key(path);
+    }
+    let config_path = flags.config.clone();
+    let options = cli::layer(flags, config_path.as_deref())?;
+
     audiowire::initialize()?;
-    let result = run().await;
+    let result = run(options).await;
     audiowire::terminate()?;
     result
 }
 
-async fn run() -> Result<()> {
-    let config = DEFAULT_CONFIG;
-    let mut args = env::args();
-    let output = args.nth(1);
-    let input = args.next();
+async fn run(options: Options) -> Result<()> {
+    let config = options.audio.config(DEFAULT_CONFIG)?;
+    let transport = options.transport.unwrap_or(Transport::Tcp);
+    let (tls, udp_key) = encryption(&options, transport)?;
+    let auth = authenticator(&options)?;
+    let heartbeat = options.heartbeat.config()?;
+    let input = options.input;
+    let output = options.output;
+    let listen = options.listen.as_deref().unwrap_or(LISTEN_HOST);
+
+    let logger = logging::term_logger(options.no_timestamps);
+    if tls.is_none() && udp_key.is_none() {
+        warn!(logger, "Sessions are not encrypted");
+    }
+    if !auth.is_required() {
+        warn!(logger, "Clients are not authenticated");
+    }
+    check_audio(&logger, config, input.as_deref(), output.as_deref())?;
+
+    let server_type = StreamType::new(
+        input.as_ref().map(|s| s != "null").unwrap_or(true),
+        output.as_ref().map(|s| s != "null").unwrap_or(true),
+    );
+    let shutdown = handle_shutdown()?;
+    // The shared streams outlive the sessions feeding them, so they only get
+    // stopped once every client is gone.
+    let streams = CancellationToken::new();
+    let mut handles = Vec::new();
+    let mixer = if server_type.is_sink() {
+        let (mixer, handle) = handle_mixer(
+            streams.clone(),
+            config,
+            output,
+            "Mixer".to_owned(),
+            logger.new(o!("stream" => "playback")),
+        )?;
+        handles.push(handle);
+        Some(mixer)
+    } else {
+        None
+    };
+    let fanout = if server_type.is_source() {
+        let (fanout, handle) = handle_fanout(
+            streams.clone(),
+            config,
+            input,
+            "Fanout".to_owned(),
+            logger.new(o!("stream" => "record")),
+        )?;
+        handles.push(handle);
+        Some(fanout)
+    } else {
+        None
+    };
 
-    let logger = logging::term_logger();
-    check_audio(&logger, config.clone(), input.as_deref(), output.as_deref())?;
-    listen_tcp(config, &logger, input, output)
-        .await
+    let server = Arc::new(Server {
+        listen_addr: cli::with_port(listen, options.port.unwrap_or(DEFAULT_PORT)),
+        config,
+        codecs: options.codec.codecs(),
+        opus: options.codec.opus_settings()?,
+        client_gain: options.client_gain.unwrap_or(DEFAULT_CLIENT_GAIN),
+        heartbeat,
+        mixer,
+        fanout,
+        server_type,
+        tls,
+        udp_key,
+        auth,
+        limiter: Mutex::new(RateLimiter::new(DEFAULT_RATE_LIMIT)),
+        pending: Arc::new(Semaphore::new(MAX_PENDING_CLIENTS)),
+        shutdown,
+    });
+    let result = match transport {
+        Transport::Tcp => listen_tcp(&server, &logger).await,
+        Transport::Udp => listen_udp(&server, &logger).await,
+    };
+    result
         .map_err(|e| error!(logger, "Listener error: {}", e))
         .unwrap_or_default();
 
+    streams.cancel();
+    for handle in handles {
+        handle
+            .await
+            .map_err(|e| error!(logger, "Join error: {}", e))
+            .unwrap_or_default();
+    }
+
     Ok(())
 }
 
-async fn listen_tcp(
-    config: Config,
-    root_logger: &Logger,
-    input_name: Option<String>,
-    output_name: Option<String>,
-) -> Result<()> {
-    let server_type = StreamType::new(
-        input_name.as_ref().map(|s| s != "null").unwrap_or(true),
-        output_name.as_ref().map(|s| s != "null").unwrap_or(true),
+fn generate_key(path: &Path) -> Result<()> {
+    let secret = secure::generate_key();
+    secure::write_secret_key(path, &secret)?;
+    println!(
+        "{}",
+        secure::format_key(PublicKey::from(&secret).as_bytes())
     );
+    Ok(())
+}
+
+// TLS is what encrypts tcp sessions and the secret key what encrypts udp ones
+fn encryption(
+    options: &Options,
+    transport: Transport,
+) -> Result<(Option<TlsAcceptor>, Option<StaticSecret>)> {
+    let tls = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => Some(secure::tls_acceptor(
+            secure::load_certs(cert)?,
+            secure::load_private_key(key)?,
+        )?),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key have to be given together".into()),
+    };
+    let udp_key = options
+        .udp_key
+        .as_deref()
+        .map(secure::load_secret_key)
+        .transpose()?;
+    match transport {
+        Transport::Tcp if udp_key.is_some() => {
+            Err("--udp-key only applies to udp, use --tls-cert and --tls-key with tcp".into())
+        }
+        Transport::Udp if tls.is_some() => {
+            Err("TLS only applies to tcp, use --udp-key with udp".into())
+        }
+        _ => Ok((tls, udp_key)),
+    }
+}
 
+fn authenticator(options: &Options) -> Result<Authenticator> {
+    Ok(Authenticator {
+        psk: options
+            .psk_file
+            .as_deref()
+            .map(auth::load_secret)
+            .transpose()?,
+        tokens: options
+            .tokens_file
+            .as_deref()
+            .map(auth::load_tokens)
+            .transpose()?
+            .unwrap_or_default(),
+        allow: options.allow.clone().map(HashSet::from_iter),
+        deny: options.deny.iter().flatten().cloned().collect(),
+    })
+}
+
+impl Server {
+    #[inline]
+    fn is_locked_out(&self, addr: SocketAddr) -> bool {
+        let limiter = self.limiter.lock().unwrap();
+        limiter.locked(addr.ip(), Instant::now()).is_some()
+    }
+
+    fn fail_attempt(&self, addr: SocketAddr, client_logger: &Logger) {
+        let mut limiter = self.limiter.lock().unwrap();
+        if limiter.fail(addr.ip(), Instant::now()) {
+            warn!(
+                client_logger,
+                "Locking {} out for {} second(s) after repeated failures",
+                addr.ip(),
+                DEFAULT_RATE_LIMIT.lockout.as_secs()
+            );
+        }
+    }
+
+    // None when too many clients are connecting already
+    fn pending_client(&self, addr: SocketAddr) -> Option<PendingClient> {
+        let slot = Arc::clone(&self.pending).try_acquire_owned().ok()?;
+        Some(PendingClient {
+            addr,
+            deadline: time::Instant::now() + HANDSHAKE_TIMEOUT,
+            _slot: slot,
+        })
+    }
+}
+
+async fn listen_tcp(server: &Arc<Server>, root_logger: &Logger) -> Result<()> {
     info!(root_logger, "Starting server");
-    let listener = TcpListener::bind("0.0.0.0:8760").await?;
+    let listener = TcpListener::bind(&server.listen_addr).await?;
     info!(
         root_logger,
-        "Server listening at {}",
+        "Server listening at {} (tcp)",
         listener.local_addr()?
     );
 
-    let term = handle_signal()?;
-    while !term.load(Ordering::Relaxed) {
-        let listener_ref = &listener;
-        let future = timeout(Duration::from_secs(1), async move {
-            listener_ref.accept().await
-        });
-        let (socket, addr) = match future.await {
-            Ok(v) => v?,
-            Err(_) => continue,
+    let clients = TaskTracker::new();
+    loop {
+        let (socket, addr) = tokio::select! {
+            result = listener.accept() => result?,
+            _ = server.shutdown.cancelled() => break,
         };
         let client_logger = root_logger.new(o!("addr" => addr));
+        if server.is_locked_out(addr) {
+            warn!(
+                client_logger,
+                "Client turned away, too many failed attempts"
+            );
+            continue;
+        }
+        let Some(client) = server.pending_client(addr) else {
+            warn!(
+                client_logger,
+                "Client turned away, too many clients connecting"
+            );
+            continue;
+        };
         info!(client_logger, "Client connected");
-        handle_client(
-            config,
-            &client_logger,
-            input_name.clone(),
-            output_name.clone(),
-            server_type,
-            &term,
+        clients.spawn(serve_tcp_client(
+            Arc::clone(server),
+            client_logger,
             socket,
-            addr,
-        )
-        .await
-        .map_err(|e| error!(client_logger, "Client error: {}", e))
-        .unwrap_or_default();
+            client,
+        ));
     }
 
+    clients.close();
+    clients.wait().await;
     info!(root_logger, "Server terminated");
     Ok(())
 }
 
-async fn handle_client(
-    config: Config,
-    client_logger: &Logger,
-    input_name: Option<String>,
-    output_name: Option<String>,
-    server_type: StreamType,
-    term: &Arc<AtomicBool>,
-    socket: TcpStream,
-    addr: SocketAddr,
-) -> Result<()> {
-    let mut buf = [0u8; 16];
-    let (mut input, mut output) = socket.into_split();
-    output.write_all(server_type.to_bytes().as_slice()).await?;
-    input.read_exact(&mut buf[..1]).await?;
-
-    let flags = StreamFlags::from(buf.as_slice());
-    let client_type = flags.stream_type();
-    let opus_enabled = flags.opus_enabled();
-    let stream_logger = client_logger.new(o!("opus" => opus_enabled));
-    let mut handles = Vec::new();
+// There are no connections with UDP, so datagrams are demultiplexed by their
+// source address and every new address gets a peer of its own.
+async fn listen_udp(server: &Arc<Server>, root_logger: &Logger) -> Result<()> {
+    info!(root_logger, "Starting server");
+    let socket = Arc::new(UdpSocket::bind(&server.listen_addr).await?);
+    info!(
+        root_logger,
+        "Server listening at {} (udp)",
+        socket.local_addr()?
+    );
 
-    if server_type.is_sink() && client_type.is_source() {
-        let logger = stream_logger.new(o!("stream" => "playback"));
-        let handle = handle_playback(
-            Arc::clone(term),
-            config,
-            output_name,
-            addr.to_string(),
-            logger.clone(),
-            input,
-            opus_enabled,
-        )?;
-        handles.push((handle, logger));
+    let peers: Arc<Mutex<HashMap<SocketAddr, UdpPeerProducer>>> = Default::default();
+    let clients = TaskTracker::new();
+    let mut buf = [0u8; 65536];
+    loop {
+        let (len, addr) = tokio::select! {
+            result = socket.recv_from(&mut buf) => result?,
+            _ = server.shutdown.cancelled() => break,
+        };
+
+        let existing = peers.lock().unwrap().get(&addr).cloned();
+        let producer = if let Some(producer) = existing {
+            producer
+        } else if server.is_locked_out(addr) {
+            // Every datagram would be a new attempt, there's no point logging each
+            continue;
+        } else {
+            // Source addresses are cheap to make up, so only so many of them
+            // get a peer before finishing the handshake
+            let Some(client) = server.pending_client(addr) else {
+                continue;
+            };
+            let (input, output, producer) =
+                UdpPeer::new(Arc::clone(&socket), addr, UDP_BACKLOG).into_split();
+            peers.lock().unwrap().insert(addr, producer.clone());
+
+            let client_logger = root_logger.new(o!("addr" => addr));
+            info!(client_logger, "Client connected");
+            let server = Arc::clone(server);
+            let peers = Arc::clone(&peers);
+            clients.spawn(async move {
+                serve_udp_client(server, client_logger, client, input, output).await;
+                peers.lock().unwrap().remove(&addr);
+            });
+            producer
+        };
+
+        if producer.try_send(&buf[..len]).is_err() {
+            warn!(
+                root_logger,
+                "Dropped datagram from {}, backlog is full", addr
+            );
+        }
     }
 
-    if server_type.is_source() && client_type.is_sink() {
-        let logger = stream_logger.new(o!("stream" => "record"));
-        let handle = handle_record(
-            Arc::clone(term),
-            config,
-            input_name,
-            addr.to_string(),
-            logger.clone(),
-            output,
-            opus_enabled,
-        )?;
-        handles.push((handle, logger));
+    clients.close();
+    clients.wait().await;
+    info!(root_logger, "Server terminated");
+    Ok(())
+}
+
+// Sets up TLS first when the server has a certificate
+async fn serve_tcp_client(
+    server: Arc<Server>,
+    client_logger: Logger,
+    socket: TcpStream,
+    client: PendingClient,
+) {
+    let Some(acceptor) = server.tls.clone() else {
+        let (input, output) = socket.into_split();
+        return serve_client(server, client_logger, client, input, output).await;
+    };
+    let result = tokio::select! {
+        result = timeout_at(client.deadline, acceptor.accept(socket)) => result,
+        _ = server.shutdown.cancelled() => return,
+    };
+    match result {
+        Ok(Ok(stream)) => {
+            let (input, output) = tokio::io::split(stream);
+            serve_client(server, client_logger, client, input, output).await
+        }
+        Ok(Err(e)) => error!(client_logger, "TLS error: {}", e),
+        Err(_) => error!(client_logger, "TLS handshake timed out"),
     }
+}
+
+// Exchanges keys first when the server has a secret key
+async fn serve_udp_client(
+    server: Arc<Server>,
+    client_logger: Logger,
+    client: PendingClient,
+    input: UdpPeerReadHalf,
+    output: UdpPeerWriteHalf,
+) {
+    let Some(secret) = server.udp_key.clone() else {
+        return serve_client(server, client_logger, client, input, output).await;
+    };
+    let exchange = secure::accept_datagram(input, output, &secret);
+    let result = tokio::select! {
+        result = timeout_at(client.deadline, exchange) => result,
+        _ = server.shutdown.cancelled() => return,
+    };
+    match result {
+        Ok(Ok((input, output))) => serve_client(server, client_logger, client, input, output).await,
+        Ok(Err(e)) => error!(client_logger, "Client error: {}", e),
+        Err(_) => error!(client_logger, "Key exchange timed out"),
+    }
+}
 
-    let logger = client_logger.clone();
-    tokio::spawn(async move {
-        for (handle, logger) in handles {
-            handle
+// Runs one client from the handshake until its session is over
+async fn serve_client<R, W>(
+    server: Arc<Server>,
+    client_logger: Logger,
+    client: PendingClient,
+    input: R,
+    output: W,
+) where
+    R: PeerReadHalf + PeerPacketRead + Send + 'static,
+    W: PeerWriteHalf + PeerPacketWrite + Send + 'static,
+{
+    let result = tokio::select! {
+        result = handle_client(&server, &client_logger, &client, input, output) => {
+            result.map_err(|e| e.to_string())
+        }
+        _ = server.shutdown.cancelled() => return,
+    };
+    drop(client);
+    match result {
+        Ok(mut session) => {
+            session
+                .join()
                 .await
-                .map_err(|e| error!(logger, "Join error: {}", e))
+                .map_err(|e| error!(client_logger, "Join error: {}", e))
                 .unwrap_or_default();
+            info!(client_logger, "Client disconnected");
         }
-        info!(logger, "Client disconnected");
-    });
+        Err(e) => error!(client_logger, "Client error: {}", e),
+    }
+}
 
-    Ok(())
+async fn handle_client<R, W>(
+    server: &Server,
+    client_logger: &Logger,
+    client: &PendingClient,
+    mut input: R,
+    mut output: W,
+) -> Result<SessionHandle>
+where
+    R: PeerReadHalf + PeerPacketRead + Send + 'static,
+    W: PeerWriteHalf + PeerPacketWrite + Send + 'static,
+{
+    let addr = client.addr;
+    let server_type = server.server_type;
+    let handshake = server_handshake(
+        &mut input,
+        &mut output,
+        server_type,
+        server.config,
+        &server.codecs,
+        server.opus,
+        &server.auth,
+    );
+    let negotiated = match timeout_at(client.deadline, handshake).await {
+        Ok(Ok(negotiated)) => negotiated,
+        Ok(Err(err @ HandshakeError::Unauthorized(_))) => {
+            server.fail_attempt(addr, client_logger);
+            return Err(err.into());
+        }
+        Ok(Err(err)) => return Err(err.into()),
+        Err(_) => {
+            // Sitting on the challenge counts the same as answering it wrong
+            if server.auth.is_required() {
+                server.fail_attempt(addr, client_logger);
+            }
+            return Err("Handshake timed out".into());
+        }
+    };
+    let identity = negotiated.peer_identity.clone();
+    if server.auth.is_required() {
+        server.limiter.lock().unwrap().succeed(addr.ip());
+        info!(client_logger, "Client authenticated as {:?}", identity);
+    }
+    let client_logger = &client_logger.new(o!("client" => identity));
+    let client_type = negotiated.peer_type;
+    let opus_enabled = negotiated.codec == Codec::Opus;
+    let stream_logger = client_logger.new(o!("opus" => opus_enabled));
+    if opus_enabled {
+        info!(
+            client_logger,
+            "Client encodes with {}", negotiated.peer_opus
+        );
+    }
+    let mut session = Session::new(&server.shutdown);
+    let (mux, messages, input, output) = mux::start(
+        session.token(),
+        server.shutdown.clone(),
+        server.heartbeat,
+        client_logger.clone(),
+        input,
+        output,
+    );
+    let control = StreamControl::connected(session.token(), mux);
+    handle_control(control.clone(), messages, client_logger.clone());
+
+    if let Some(mixer) = server.mixer.as_ref().filter(|_| client_type.is_source()) {
+        session.set_playback(handle_mixed_playback(
+            control.clone(),
+            server.config,
+            mixer.add_input(server.client_gain),
+            stream_logger.new(o!("stream" => "playback")),
+            input,
+            PeerFormat {
+                channels: negotiated.peer_channels,
+                opus: opus_enabled.then_some(negotiated.peer_opus),
+            },
+        ));
+    }
+
+    if let Some(fanout) = server.fanout.as_ref().filter(|_| client_type.is_sink()) {
+        session.set_record(handle_fanout_record(
+            control,
+            server.config,
+            fanout.add_output(),
+            stream_logger.new(o!("stream" => "record")),
+            output,
+            opus_enabled.then_some(server.opus),
+        ));
+    }
+
+    Ok(session.start())
 }
@@ -0,0 +1,376 @@
+use std::{error::Error, fs, net::SocketAddr, path::Path, time::Duration};
+
+use clap::Args;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use toml::{Table, Value};
+
+use crate::{
+    audiowire::{Config, SampleFormat},
+    handshake::Codec,
+    mux::{HeartbeatConfig, DEFAULT_HEARTBEAT},
+    opus::{OpusSettings, DEFAULT_OPUS_SETTINGS},
+};
+
+pub const DEFAULT_PORT: u16 = 8760;
+// Highest sample rate taken from the command line or a config file
+const MAX_SAMPLE_RATE: u32 = 768000;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Args, Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AudioOptions {
+    #[arg(long, help = "Number of channels")]
+    pub channels: Option<u8>,
+    #[arg(long, help = "Sample rate in Hz")]
+    pub sample_rate: Option<u32>,
+    #[arg(long, help = "Sample format: s16, s24, s24_32, s32, u8, f32 or f64")]
+    pub format: Option<SampleFormat>,
+    #[arg(
+        long,
+        help = "Frames per buffer, also the network packet size for raw audio"
+    )]
+    pub buffer_frames: Option<usize>,
+    #[arg(long, help = "Frames the device buffers hold at most")]
+    pub max_buffer_frames: Option<usize>,
+    #[arg(
+        long,
+        help = "Sample rate to run devices at, resampled to and from --sample-rate [default: same as --sample-rate]"
+    )]
+    pub device_rate: Option<u32>,
+}
+
+impl AudioOptions {
+    // Fills in whatever was given on top of the base config
+    pub fn config(&self, base: Config) -> Result<Config> {
+        let config = Config {
+            channels: self.channels.unwrap_or(base.channels),
+            sample_rate: self.sample_rate.unwrap_or(base.sample_rate),
+            sample_format: self.format.unwrap_or(base.sample_format),
+            buffer_frames: self.buffer_frames.unwrap_or(base.buffer_frames),
+            max_buffer_frames: self.max_buffer_frames.unwrap_or(base.max_buffer_frames),
+            device_rate: self.device_rate.or(base.device_rate),
+        };
+        if config.channels == 0 {
+            return Err("Channel count can't be zero".into());
+        }
+        for rate in [Some(config.sample_rate), config.device_rate]
+            .into_iter()
+            .flatten()
+        {
+            if !(1..=MAX_SAMPLE_RATE).contains(&rate) {
+                return Err(format!(
+                    "Sample rate has to be from 1 to {} Hz, got {}",
+                    MAX_SAMPLE_RATE, rate
+                )
+                .into());
+            }
+        }
+        if config.buffer_frames == 0 {
+            return Err("Buffer frames can't be zero".into());
+        }
+        // The device buffers have to hold at least one whole buffer, and their
+        // size goes to the native library as 32 bits
+        if config.max_buffer_frames < config.buffer_frames {
+            return Err(format!(
+                "Max buffer frames has to be at least the buffer frames of {}",
+                config.buffer_frames
+            )
+            .into());
+        }
+        if config.max_buffer_frames > u32::MAX as usize {
+            return Err(format!("Max buffer frames can't be more than {}", u32::MAX).into());
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Args, Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CodecOptions {
+    #[arg(long, help = "Preferred codec: opus or raw [default: opus]")]
+    pub codec: Option<Codec>,
+    #[arg(long, help = "Opus bitrate: auto, max or bits per second")]
+    #[serde(default, deserialize_with = "string_or_integer")]
+    pub bitrate: Option<String>,
+    #[arg(long, help = "Opus bitrate mode: vbr or cbr")]
+    pub bitrate_mode: Option<String>,
+    #[arg(long, help = "Opus complexity from 0 to 10")]
+    pub complexity: Option<u8>,
+    #[arg(long, help = "Opus application: voip, audio or lowdelay")]
+    pub application: Option<String>,
+    #[arg(
+        long,
+        help = "Opus max bandwidth: auto, narrowband, mediumband, wideband, superwideband or fullband"
+    )]
+    pub max_bandwidth: Option<String>,
+    #[arg(long, help = "Opus discontinuous transmission: on or off")]
+    pub dtx: Option<String>,
+    #[arg(
+        long,
+        help = "Opus frame duration in milliseconds: 2.5, 5, 10, 20, 40, 60, 80, 100 or 120"
+    )]
+    pub frame_duration: Option<String>,
+}
+
+impl CodecOptions {
+    // Codecs to offer in order of preference, raw is always there to fall back on
+    pub fn codecs(&self) -> Vec<Codec> {
+        match self.codec.unwrap_or(Codec::Opus) {
+            Codec::Opus => vec![Codec::Opus, Codec::Raw],
+            Codec::Raw => vec![Codec::Raw],
+        }
+    }
+
+    pub fn opus_settings(&self) -> Result<OpusSettings> {
+        let settings = [
+            ("bitrate", self.bitrate.clone()),
+            ("bitrate-mode", self.bitrate_mode.clone()),
+            ("complexity", self.complexity.map(|c| c.to_string())),
+            ("application", self.application.clone()),
+            ("max-bandwidth", self.max_bandwidth.clone()),
+            ("dtx", self.dtx.clone()),
+            ("frame-duration", self.frame_duration.clone()),
+        ];
+        let mut opus = DEFAULT_OPUS_SETTINGS;
+        for (name, value) in settings {
+            if let Some(value) = value {
+                opus.set(name, &value)?;
+            }
+        }
+        Ok(opus)
+    }
+}
+
+#[derive(Args, Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeartbeatOptions {
+    #[arg(
+        long = "heartbeat-interval",
+        help = "Seconds between pings to the peer [default: 1]"
+    )]
+    pub interval: Option<u64>,
+    #[arg(
+        long,
+        help = "Seconds without hearing from the peer before the session is closed [default: 10]"
+    )]
+    pub idle_timeout: Option<u64>,
+}
+
+impl HeartbeatOptions {
+    pub fn config(&self) -> Result<HeartbeatConfig> {
+        let config = HeartbeatConfig {
+            interval: self
+                .interval
+                .map_or(DEFAULT_HEARTBEAT.interval, Duration::from_secs),
+            idle_timeout: self
+                .idle_timeout
+                .map_or(DEFAULT_HEARTBEAT.idle_timeout, Duration::from_secs),
+        };
+        if config.interval.is_zero() {
+            return Err("Heartbeat interval can't be zero".into());
+        }
+        // A single late ping shouldn't be enough to close the session
+        if config.idle_timeout < config.interval * 2 {
+            return Err(format!(
+                "Idle timeout has to be at least twice the heartbeat interval of {:?}",
+                config.interval
+            )
+            .into());
+        }
+        Ok(config)
+    }
+}
+
+// Layers the flags on top of the config file at the given path. Anything left
+// unset in both is up to the caller to default.
+pub fn layer<T: Serialize + DeserializeOwned>(flags: T, path: Option<&Path>) -> Result<T> {
+    let Some(path) = path else {
+        return Ok(flags);
+    };
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+    let mut table: Table = toml::from_str(&content)
+        .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+    merge(&mut table, Table::try_from(flags)?);
+    Ok(table.try_into()?)
+}
+
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge(base, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+pub fn list_devices() -> Result<()> {
+    crate::initialize()?;
+    let devices = crate::devices();
+    crate::terminate()?;
+    for device in devices? {
+        println!("{}", device);
+    }
+    Ok(())
+}
+
+// Appends the port unless the host already carries one
+pub fn with_port(host: &str, port: u16) -> String {
+    if host.parse::<SocketAddr>().is_ok() {
+        host.to_owned()
+    } else if host.contains(':') && !host.starts_with('[') && host.matches(':').count() > 1 {
+        // Bare IPv6 address
+        format!("[{}]:{}", host, port)
+    } else if host
+        .rsplit_once(':')
+        .is_some_and(|(_, p)| p.parse::<u16>().is_ok())
+    {
+        host.to_owned()
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+#[inline]
+pub fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+// Bitrates read naturally as numbers in TOML, but also take auto and max
+fn string_or_integer<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrInteger {
+        String(String),
+        Integer(i64),
+    }
+    Ok(
+        Option::<StringOrInteger>::deserialize(deserializer)?.map(|value| match value {
+            StringOrInteger::String(s) => s,
+            StringOrInteger::Integer(i) => i.to_string(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use crate::DEFAULT_CONFIG;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct Options {
+        port: Option<u16>,
+        input: Option<String>,
+        #[serde(default)]
+        audio: AudioOptions,
+        #[serde(default)]
+        codec: CodecOptions,
+    }
+
+    #[test]
+    fn flags_override_config_file() {
+        let path = env::temp_dir().join(format!("audiowire-{}-cli.toml", process::id()));
+        fs::write(
+            &path,
+            "port = 9000\ninput = \"file:in.wav\"\n\n[audio]\nsample_rate = 44100\nformat = \"f32\"\n\n[codec]\nbitrate = 32000\ndtx = \"on\"\n",
+        )
+        .unwrap();
+
+        let flags = Options {
+            port: Some(9100),
+            audio: AudioOptions {
+                channels: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let options = layer(flags, Some(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(options.port, Some(9100));
+        assert_eq!(options.input.as_deref(), Some("file:in.wav"));
+        let config = options.audio.config(DEFAULT_CONFIG).unwrap();
+        assert_eq!(config.channels, 1);
+        assert_eq!(config.sample_rate, 44100);
+        assert_eq!(config.sample_format, SampleFormat::F32);
+        assert_eq!(config.buffer_frames, DEFAULT_CONFIG.buffer_frames);
+
+        let opus = options.codec.opus_settings().unwrap();
+        assert_eq!(opus.bitrate, opus::Bitrate::Bits(32000));
+        assert!(opus.dtx);
+        assert_eq!(options.codec.codecs(), vec![Codec::Opus, Codec::Raw]);
+    }
+
+    #[test]
+    fn rejects_invalid_audio_config() {
+        let invalid = [
+            AudioOptions {
+                channels: Some(0),
+                ..Default::default()
+            },
+            AudioOptions {
+                sample_rate: Some(0),
+                ..Default::default()
+            },
+            AudioOptions {
+                device_rate: Some(MAX_SAMPLE_RATE + 1),
+                ..Default::default()
+            },
+            AudioOptions {
+                buffer_frames: Some(0),
+                ..Default::default()
+            },
+            AudioOptions {
+                max_buffer_frames: Some(0),
+                ..Default::default()
+            },
+            AudioOptions {
+                buffer_frames: Some(DEFAULT_CONFIG.max_buffer_frames + 1),
+                ..Default::default()
+            },
+        ];
+        for options in invalid {
+            assert!(options.config(DEFAULT_CONFIG).is_err(), "{:?}", options);
+        }
+        let options = AudioOptions {
+            buffer_frames: Some(DEFAULT_CONFIG.max_buffer_frames),
+            ..Default::default()
+        };
+        assert!(options.config(DEFAULT_CONFIG).is_ok());
+    }
+
+    #[test]
+    fn heartbeat_config() {
+        let config = HeartbeatOptions::default().config().unwrap();
+        assert_eq!(config.interval, DEFAULT_HEARTBEAT.interval);
+        assert_eq!(config.idle_timeout, DEFAULT_HEARTBEAT.idle_timeout);
+
+        let options = HeartbeatOptions {
+            interval: Some(5),
+            idle_timeout: Some(6),
+        };
+        assert!(options.config().is_err());
+        let options = HeartbeatOptions {
+            interval: Some(0),
+            ..Default::default()
+        };
+        assert!(options.config().is_err());
+    }
+
+    #[test]
+    fn append_port() {
+        assert_eq!(with_port("localhost", 8760), "localhost:8760");
+        assert_eq!(with_port("localhost:9000", 8760), "localhost:9000");
+        assert_eq!(with_port("10.0.0.1", 8760), "10.0.0.1:8760");
+        assert_eq!(with_port("::1", 8760), "[::1]:8760");
+        assert_eq!(with_port("[::1]:9000", 8760), "[::1]:9000");
+    }
+}
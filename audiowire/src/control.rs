@@ -0,0 +1,384 @@
+use std::{
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use opus::Bitrate;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    mux::Mux,
+    opus::{bitrate_from_i32, bitrate_to_i32, format_bitrate, parse_bitrate},
+};
+
+const TYPE_MUTE: u8 = 0;
+const TYPE_VOLUME: u8 = 1;
+const TYPE_BITRATE: u8 = 2;
+const TYPE_STATS: u8 = 3;
+const TYPE_DEVICE_CHANGED: u8 = 4;
+const TYPE_GOODBYE: u8 = 5;
+
+// Highest volume a peer may ask for, anything louder is bound to clip
+pub const MAX_VOLUME: f32 = 4.0;
+
+// Control messages travel in between audio frames once a session is up, see
+// mux for how they're framed. Each one starts with its type:
+//
+//   mute:           type:u8 muted:u8
+//   volume:         type:u8 volume:f32
+//   bitrate:        type:u8 bitrate:i32
+//   stats:          type:u8 received:u64 lost:u64 late:u64 jitter:u32 rtt:u32
+//   device changed: type:u8 name[]
+//   goodbye:        type:u8 reason[]
+//
+// Mute, volume and bitrate are requests about the audio the sender sends:
+// how the receiver should play it, and what bitrate the sender would like
+// the receiver to encode at. Jitter and round trip are in microseconds, the
+// bitrate is laid out the same as in the Opus settings.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Control {
+    Mute(bool),
+    Volume(f32),
+    Bitrate(Bitrate),
+    Stats(StatsReport),
+    DeviceChanged(String),
+    // Sent right before closing the session on purpose
+    Goodbye(String),
+}
+
+// How the audio of the peer is arriving, as seen by the receiving end
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct StatsReport {
+    pub received: u64,
+    pub lost: u64,
+    pub late: u64,
+    pub jitter: Duration,
+    pub rtt: Duration,
+}
+
+impl Control {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Self::Mute(muted) => {
+                buf.push(TYPE_MUTE);
+                buf.push(*muted as u8);
+            }
+            Self::Volume(volume) => {
+                buf.push(TYPE_VOLUME);
+                buf.extend_from_slice(&volume.to_be_bytes());
+            }
+            Self::Bitrate(bitrate) => {
+                buf.push(TYPE_BITRATE);
+                buf.extend_from_slice(&bitrate_to_i32(*bitrate).to_be_bytes());
+            }
+            Self::Stats(report) => {
+                buf.push(TYPE_STATS);
+                buf.extend_from_slice(&report.received.to_be_bytes());
+                buf.extend_from_slice(&report.lost.to_be_bytes());
+                buf.extend_from_slice(&report.late.to_be_bytes());
+                buf.extend_from_slice(&micros(report.jitter).to_be_bytes());
+                buf.extend_from_slice(&micros(report.rtt).to_be_bytes());
+            }
+            Self::DeviceChanged(name) => {
+                buf.push(TYPE_DEVICE_CHANGED);
+                buf.extend_from_slice(name.as_bytes());
+            }
+            Self::Goodbye(reason) => {
+                buf.push(TYPE_GOODBYE);
+                buf.extend_from_slice(reason.as_bytes());
+            }
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, String> {
+        let (&kind, payload) = buf.split_first().ok_or("empty control message")?;
+        let truncated = || format!("truncated control message of type {}", kind);
+        let message = match kind {
+            TYPE_MUTE => {
+                let &[muted] = payload else {
+                    return Err(truncated());
+                };
+                Self::Mute(muted != 0)
+            }
+            TYPE_VOLUME => {
+                let bytes = payload.try_into().map_err(|_| truncated())?;
+                Self::Volume(f32::from_be_bytes(bytes))
+            }
+            TYPE_BITRATE => {
+                let bytes = payload.try_into().map_err(|_| truncated())?;
+                Self::Bitrate(bitrate_from_i32(i32::from_be_bytes(bytes))?)
+            }
+            TYPE_STATS => {
+                if payload.len() != 32 {
+                    return Err(truncated());
+                }
+                let u64_at =
+                    |at: usize| u64::from_be_bytes(payload[at..at + 8].try_into().unwrap());
+                let micros_at = |at: usize| {
+                    let value = u32::from_be_bytes(payload[at..at + 4].try_into().unwrap());
+                    Duration::from_micros(value as u64)
+                };
+                Self::Stats(StatsReport {
+                    received: u64_at(0),
+                    lost: u64_at(8),
+                    late: u64_at(16),
+                    jitter: micros_at(24),
+                    rtt: micros_at(28),
+                })
+            }
+            TYPE_DEVICE_CHANGED => {
+                Self::DeviceChanged(String::from_utf8_lossy(payload).to_string())
+            }
+            TYPE_GOODBYE => Self::Goodbye(String::from_utf8_lossy(payload).to_string()),
+            other => return Err(format!("unknown control message type {}", other)),
+        };
+        Ok(message)
+    }
+}
+
+#[inline]
+fn micros(duration: Duration) -> u32 {
+    duration.as_micros().min(u32::MAX as u128) as u32
+}
+
+#[inline]
+fn valid_volume(volume: f32) -> bool {
+    (0.0..=MAX_VOLUME).contains(&volume)
+}
+
+// The commands a user can type to adjust a live session:
+//
+//   mute, unmute, volume <0 to 4>, bitrate <auto, max or bits per second>
+impl FromStr for Control {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        if words.next().is_some() {
+            return Err(format!("Too many arguments for {}", command));
+        }
+        match (command, argument) {
+            ("mute", None) => Ok(Self::Mute(true)),
+            ("unmute", None) => Ok(Self::Mute(false)),
+            ("volume", Some(volume)) => volume
+                .parse()
+                .ok()
+                .filter(|&volume| valid_volume(volume))
+                .map(Self::Volume)
+                .ok_or_else(|| format!("Volume has to be from 0 to {}: {}", MAX_VOLUME, volume)),
+            ("bitrate", Some(bitrate)) => parse_bitrate(bitrate)
+                .map(Self::Bitrate)
+                .ok_or_else(|| format!("Invalid bitrate: {}", bitrate)),
+            ("volume" | "bitrate", None) => Err(format!("Missing a value for {}", command)),
+            _ => Err(format!(
+                "Unknown command: {}, try mute, unmute, volume or bitrate",
+                s.trim()
+            )),
+        }
+    }
+}
+
+impl Display for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mute(true) => write!(f, "mute"),
+            Self::Mute(false) => write!(f, "unmute"),
+            Self::Volume(volume) => write!(f, "volume {}", volume),
+            Self::Bitrate(bitrate) => write!(f, "bitrate {}", format_bitrate(*bitrate)),
+            Self::Stats(report) => write!(
+                f,
+                "received: {}, lost: {}, late: {}, jitter: {:?}, round trip: {:?}",
+                report.received, report.lost, report.late, report.jitter, report.rtt
+            ),
+            Self::DeviceChanged(name) => write!(f, "device {}", name),
+            Self::Goodbye(reason) => write!(f, "goodbye: {}", reason),
+        }
+    }
+}
+
+struct State {
+    muted: bool,
+    volume: f32,
+    // Bitrate the peer asked for, until the record stream picks it up
+    bitrate: Option<Bitrate>,
+    stats: StatsReport,
+}
+
+// Steers the streams of a session while they run. Every stream task holds a
+// clone, so whatever the peer asks for reaches them without restarting
+// anything. The token stops them all.
+#[derive(Clone)]
+pub struct StreamControl {
+    token: CancellationToken,
+    // Streams that aren't connected to a peer have nobody to tell anything
+    mux: Option<Mux>,
+    state: Arc<Mutex<State>>,
+}
+
+impl StreamControl {
+    pub fn new(token: CancellationToken) -> Self {
+        Self {
+            token,
+            mux: None,
+            state: Arc::new(Mutex::new(State {
+                muted: false,
+                volume: 1.0,
+                bitrate: None,
+                stats: StatsReport::default(),
+            })),
+        }
+    }
+
+    pub fn connected(token: CancellationToken, mux: Mux) -> Self {
+        Self {
+            mux: Some(mux),
+            ..Self::new(token)
+        }
+    }
+
+    #[inline]
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    // Messages are dropped rather than queued behind a full backlog
+    pub fn send(&self, message: &Control) -> io::Result<()> {
+        match &self.mux {
+            Some(mux) => mux.send(message),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    #[inline]
+    pub fn round_trip(&self) -> Duration {
+        self.mux
+            .as_ref()
+            .map(|mux| mux.rtt().smoothed)
+            .unwrap_or_default()
+    }
+
+    // What the peer's audio is scaled by when played
+    pub fn gain(&self) -> f32 {
+        let state = self.state.lock().unwrap();
+        if state.muted {
+            0.0
+        } else {
+            state.volume
+        }
+    }
+
+    #[inline]
+    pub fn set_muted(&self, muted: bool) {
+        self.state.lock().unwrap().muted = muted;
+    }
+
+    pub fn set_volume(&self, volume: f32) -> Result<(), String> {
+        if !valid_volume(volume) {
+            return Err(format!("volume {} is out of range", volume));
+        }
+        self.state.lock().unwrap().volume = volume;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn request_bitrate(&self, bitrate: Bitrate) {
+        self.state.lock().unwrap().bitrate = Some(bitrate);
+    }
+
+    #[inline]
+    pub fn take_bitrate(&self) -> Option<Bitrate> {
+        self.state.lock().unwrap().bitrate.take()
+    }
+
+    #[inline]
+    pub fn set_stats(&self, stats: StatsReport) {
+        self.state.lock().unwrap().stats = stats;
+    }
+
+    pub fn stats(&self) -> StatsReport {
+        StatsReport {
+            rtt: self.round_trip(),
+            ..self.state.lock().unwrap().stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let messages = [
+            Control::Mute(true),
+            Control::Mute(false),
+            Control::Volume(0.5),
+            Control::Bitrate(Bitrate::Bits(32000)),
+            Control::Bitrate(Bitrate::Max),
+            Control::Stats(StatsReport {
+                received: 1000,
+                lost: 3,
+                late: 1,
+                jitter: Duration::from_micros(1500),
+                rtt: Duration::from_millis(42),
+            }),
+            Control::DeviceChanged("Speakers".to_owned()),
+            Control::Goodbye("shutting down".to_owned()),
+        ];
+        for message in messages {
+            assert_eq!(Control::from_bytes(&message.to_bytes()).unwrap(), message);
+        }
+
+        assert!(Control::from_bytes(&[]).is_err());
+        assert!(Control::from_bytes(&[TYPE_VOLUME, 0, 0]).is_err());
+        assert!(Control::from_bytes(&[TYPE_STATS, 0]).is_err());
+        assert!(Control::from_bytes(&[TYPE_BITRATE, 0xFF, 0xFF, 0xFF, 0xFE]).is_err());
+        assert!(Control::from_bytes(&[42]).is_err());
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("mute".parse(), Ok(Control::Mute(true)));
+        assert_eq!(" unmute ".parse(), Ok(Control::Mute(false)));
+        assert_eq!("volume 0.25".parse(), Ok(Control::Volume(0.25)));
+        assert_eq!(
+            "bitrate 64000".parse(),
+            Ok(Control::Bitrate(Bitrate::Bits(64000)))
+        );
+        assert_eq!("bitrate auto".parse(), Ok(Control::Bitrate(Bitrate::Auto)));
+        assert!("volume".parse::<Control>().is_err());
+        assert!("volume 9".parse::<Control>().is_err());
+        assert!("volume NaN".parse::<Control>().is_err());
+        assert!("bitrate -5".parse::<Control>().is_err());
+        assert!("mute now".parse::<Control>().is_err());
+        assert!("goodbye".parse::<Control>().is_err());
+    }
+
+    #[test]
+    fn stream_control_state() {
+        let control = StreamControl::new(CancellationToken::new());
+        assert_eq!(control.gain(), 1.0);
+        control.set_volume(0.5).unwrap();
+        assert!(control.set_volume(-1.0).is_err());
+        assert_eq!(control.gain(), 0.5);
+        control.set_muted(true);
+        assert_eq!(control.gain(), 0.0);
+        control.set_muted(false);
+        assert_eq!(control.gain(), 0.5);
+
+        assert_eq!(control.take_bitrate(), None);
+        control.request_bitrate(Bitrate::Bits(24000));
+        assert_eq!(control.take_bitrate(), Some(Bitrate::Bits(24000)));
+        assert_eq!(control.take_bitrate(), None);
+
+        let err = control.send(&Control::Mute(true)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+}
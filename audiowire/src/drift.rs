@@ -0,0 +1,437 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::Config;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DriftConfig {
+    // Largest correction applied either way, in parts per million
+    pub max_correction: f64,
+    // Time constant of the low-pass filter over the fill level, nothing is
+    // corrected until that long after the first update
+    pub smoothing: Duration,
+    // Correction per second of fill level error
+    pub proportional: f64,
+    // How fast a lasting fill level error builds up a correction, per second
+    // of error and second of time
+    pub integral: f64,
+}
+
+// A critically damped loop settling over a few minutes, slow enough for the
+// correction to stay inaudible and for packet sized jumps in the fill level to
+// average out.
+pub const DEFAULT_DRIFT_CONFIG: DriftConfig = DriftConfig {
+    max_correction: 1000.0,
+    smoothing: Duration::from_secs(5),
+    proportional: 2e-2,
+    integral: 1e-4,
+};
+
+// DriftCompensator keeps a buffer between a remote sender and a local device
+// at a constant fill level although the two clocks never run at exactly the
+// same rate. It watches how far the fill level strays from its target over
+// time and turns that into a playback ratio: above one when the sender runs
+// fast and the buffer grows, below one when it runs slow and the buffer
+// drains. Consuming input at that ratio keeps up with it exactly, so the
+// latency neither creeps up until the buffer overflows nor down until it
+// underruns, however long the stream runs.
+pub struct DriftCompensator {
+    config: DriftConfig,
+    started: Option<Instant>,
+    updated: Option<Instant>,
+    // Smoothed fill level, in seconds
+    level: f64,
+    // Level kept by hold, taken once the compensator has warmed up
+    held: Option<f64>,
+    // Correction that cancels out the drift itself, once it has settled
+    integral: f64,
+    correction: f64,
+}
+
+impl DriftCompensator {
+    pub fn new(config: DriftConfig) -> Self {
+        Self {
+            config,
+            started: None,
+            updated: None,
+            level: 0.0,
+            held: None,
+            integral: 0.0,
+            correction: 0.0,
+        }
+    }
+
+    // Feeds in the fill level measured now, returns the input frames to
+    // consume per output frame from here on
+    #[inline]
+    pub fn update(&mut self, fill: Duration, target: Duration, now: Instant) -> f64 {
+        self.adjust(fill, Some(target.as_secs_f64()), now)
+    }
+
+    // Same as update, the target being whatever level the buffer settled at
+    // while warming up
+    #[inline]
+    pub fn hold(&mut self, fill: Duration, now: Instant) -> f64 {
+        self.adjust(fill, None, now)
+    }
+
+    fn adjust(&mut self, fill: Duration, target: Option<f64>, now: Instant) -> f64 {
+        let fill = fill.as_secs_f64();
+        let Some(updated) = self.updated.replace(now) else {
+            self.started = Some(now);
+            self.level = fill;
+            return self.ratio();
+        };
+        let elapsed = now.duration_since(updated).as_secs_f64();
+        let smoothing = self.config.smoothing.as_secs_f64();
+        self.level += (fill - self.level) * elapsed / (smoothing + elapsed);
+        if self
+            .started
+            .is_some_and(|started| now.duration_since(started) < self.config.smoothing)
+        {
+            return self.ratio();
+        }
+
+        let error = self.level - target.unwrap_or(*self.held.get_or_insert(self.level));
+        let limit = self.config.max_correction * 1e-6;
+        self.integral =
+            (self.integral + self.config.integral * error * elapsed).clamp(-limit, limit);
+        self.correction = (self.config.proportional * error + self.integral).clamp(-limit, limit);
+        self.ratio()
+    }
+
+    #[inline]
+    pub fn ratio(&self) -> f64 {
+        1.0 + self.correction
+    }
+
+    // How much faster the sender's clock runs than the local one, in parts
+    // per million
+    #[inline]
+    pub fn drift(&self) -> f64 {
+        self.integral * 1e6
+    }
+
+    // Correction applied right now, in parts per million
+    #[inline]
+    pub fn correction(&self) -> f64 {
+        self.correction * 1e6
+    }
+}
+
+// FrameSlipper applies a playback ratio by dropping or repeating a single
+// frame whenever a whole one is owed. Unlike resampling it leaves every frame
+// that gets played bit for bit intact, which is what raw streams are for.
+pub struct FrameSlipper {
+    frame_size: usize,
+    owed: f64,
+    pub dropped: u64,
+    pub repeated: u64,
+}
+
+impl FrameSlipper {
+    pub fn new(frame_size: usize) -> Self {
+        Self {
+            frame_size,
+            owed: 0.0,
+            dropped: 0,
+            repeated: 0,
+        }
+    }
+
+    // Appends the input to the output less or plus the frame owed, if any
+    pub fn process(&mut self, ratio: f64, input: &[u8], output: &mut Vec<u8>) {
+        let frames = input.len() / self.frame_size;
+        let input = &input[..frames * self.frame_size];
+        self.owed += frames as f64 * (ratio - 1.0);
+        if self.owed >= 1.0 && frames > 1 {
+            self.owed -= 1.0;
+            self.dropped += 1;
+            output.extend_from_slice(&input[..input.len() - self.frame_size]);
+        } else if self.owed <= -1.0 && frames > 0 {
+            self.owed += 1.0;
+            self.repeated += 1;
+            output.extend_from_slice(input);
+            output.extend_from_slice(&input[input.len() - self.frame_size..]);
+        } else {
+            output.extend_from_slice(input);
+        }
+    }
+}
+
+// SlipQueue holds raw audio on its way from the network to the device. It
+// takes in whatever arrives as soon as it arrives, so that together with the
+// device fill it sees the whole backlog. A sender running fast piles up here
+// and gets frames dropped just the same as a slow one gets frames repeated,
+// the latency the stream started with is kept either way. Past the max buffer
+// size the oldest frames are dropped, the same as a device ring buffer would.
+pub struct SlipQueue {
+    config: Config,
+    backlog: VecDeque<u8>,
+    compensator: DriftCompensator,
+    slipper: FrameSlipper,
+    // Frames dropped because the backlog was full
+    pub overflowed: u64,
+}
+
+impl SlipQueue {
+    pub fn new(drift: DriftConfig, config: Config) -> Self {
+        Self {
+            config,
+            backlog: VecDeque::new(),
+            compensator: DriftCompensator::new(drift),
+            slipper: FrameSlipper::new(config.frame_size()),
+            overflowed: 0,
+        }
+    }
+
+    pub fn push(&mut self, buf: &[u8]) {
+        self.backlog.extend(buf);
+        let frame_size = self.config.frame_size();
+        let overflow = self
+            .backlog
+            .len()
+            .saturating_sub(self.config.max_buffer_size())
+            .div_ceil(frame_size);
+        self.backlog.drain(..overflow * frame_size);
+        self.overflowed += overflow as u64;
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.backlog.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.backlog.is_empty()
+    }
+
+    // Appends a buffer less or plus a slipped frame for a device already
+    // holding device_fill bytes, false when there isn't a whole buffer queued
+    pub fn pop(&mut self, device_fill: usize, now: Instant, output: &mut Vec<u8>) -> bool {
+        let size = self.config.buffer_size();
+        if self.backlog.len() < size {
+            return false;
+        }
+        let fill = self
+            .config
+            .bytes_to_duration(self.backlog.len() + device_fill);
+        let ratio = self.compensator.hold(fill, now);
+        self.slipper
+            .process(ratio, &self.backlog.make_contiguous()[..size], output);
+        self.backlog.drain(..size);
+        true
+    }
+
+    #[inline]
+    pub fn compensator(&self) -> &DriftCompensator {
+        &self.compensator
+    }
+
+    #[inline]
+    pub fn slipper(&self) -> &FrameSlipper {
+        &self.slipper
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DEFAULT_CONFIG;
+
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_millis(20);
+    const TARGET: Duration = Duration::from_millis(60);
+
+    // Runs a buffer filled by a sender whose clock is off by drift ppm and
+    // drained by the compensated playback, the fill level only ever seen in
+    // whole packets like a jitter buffer reports it. Returns the exact fill
+    // level after every simulated second.
+    fn simulate(
+        drift: f64,
+        seconds: u64,
+        mut update: impl FnMut(&mut DriftCompensator, Duration, Instant) -> f64,
+    ) -> (DriftCompensator, Vec<f64>) {
+        let mut compensator = DriftCompensator::new(DEFAULT_DRIFT_CONFIG);
+        let start = Instant::now();
+        let mut fill = TARGET.as_secs_f64() + 0.005;
+        let mut levels = Vec::new();
+        let ticks_per_second = 1000 / PERIOD.as_millis() as u64;
+        for tick in 0..seconds * ticks_per_second {
+            let packets = (fill / PERIOD.as_secs_f64()).floor();
+            let measured = PERIOD.mul_f64(packets);
+            let now = start + PERIOD * tick as u32;
+            let ratio = update(&mut compensator, measured, now);
+            fill += PERIOD.as_secs_f64() * (1.0 + drift * 1e-6 - ratio);
+            if tick % ticks_per_second == 0 {
+                levels.push(fill);
+            }
+        }
+        (compensator, levels)
+    }
+
+    fn spread(levels: &[f64]) -> (f64, f64) {
+        let lowest = levels.iter().cloned().fold(f64::MAX, f64::min);
+        let highest = levels.iter().cloned().fold(f64::MIN, f64::max);
+        (lowest, highest)
+    }
+
+    #[test]
+    fn holds_latency_against_drift() {
+        for drift in [250.0, -100.0, 30.0] {
+            let (compensator, levels) = simulate(drift, 4 * 3600, |compensator, fill, now| {
+                compensator.update(fill, TARGET, now)
+            });
+            assert!(
+                (compensator.drift() - drift).abs() < drift.abs() * 0.05,
+                "estimated {} ppm, expected {} ppm",
+                compensator.drift(),
+                drift
+            );
+            // Once settled, the fill level stays put for hours
+            let (lowest, highest) = spread(&levels[1800..]);
+            assert!(
+                highest - lowest < 0.025,
+                "{} ppm: {}..{}",
+                drift,
+                lowest,
+                highest
+            );
+            assert!((lowest - TARGET.as_secs_f64()).abs() < 0.025);
+        }
+    }
+
+    #[test]
+    fn holds_the_level_it_started_at() {
+        let (compensator, levels) = simulate(-150.0, 3600, |compensator, fill, now| {
+            compensator.hold(fill, now)
+        });
+        assert!((compensator.drift() + 150.0).abs() < 7.5);
+        let (lowest, highest) = spread(&levels);
+        assert!(highest - lowest < 0.025, "{}..{}", lowest, highest);
+    }
+
+    #[test]
+    fn correction_is_bounded() {
+        let (compensator, levels) = simulate(5000.0, 600, |compensator, fill, now| {
+            compensator.update(fill, TARGET, now)
+        });
+        assert!((compensator.correction() - DEFAULT_DRIFT_CONFIG.max_correction).abs() < 1e-6);
+        // A drift beyond the limit can only be slowed down
+        assert!(levels.last().unwrap() > &TARGET.as_secs_f64());
+    }
+
+    // Feeds the queue from a sender whose clock is off by drift ppm while a
+    // device plays one buffer per period and gets topped up to two. Returns
+    // the whole backlog, queued and on the device, after every second.
+    fn simulate_queue(drift: f64, seconds: u64) -> (SlipQueue, Vec<f64>) {
+        let config = DEFAULT_CONFIG;
+        let bufsize = config.buffer_size();
+        let frame_size = config.frame_size();
+        let period = config.buffer_duration();
+        let mut queue = SlipQueue::new(DEFAULT_DRIFT_CONFIG, config);
+        queue.push(&vec![0u8; bufsize * 3]);
+        let start = Instant::now();
+        let mut device = 0;
+        let mut owed = 0.0;
+        let mut output = Vec::new();
+        let mut levels = Vec::new();
+        let ticks_per_second = (1.0 / period.as_secs_f64()).round() as u64;
+        for tick in 0..seconds * ticks_per_second {
+            owed += config.buffer_frames as f64 * (1.0 + drift * 1e-6);
+            let frames = owed.floor();
+            owed -= frames;
+            queue.push(&vec![0u8; frames as usize * frame_size]);
+
+            device -= bufsize.min(device);
+            let now = start + period * tick as u32;
+            while device < bufsize * 2 {
+                output.clear();
+                if !queue.pop(device, now, &mut output) {
+                    break;
+                }
+                device += output.len();
+            }
+            if tick % ticks_per_second == 0 {
+                levels.push(config.bytes_to_duration(queue.len() + device).as_secs_f64());
+            }
+        }
+        (queue, levels)
+    }
+
+    #[test]
+    fn queue_holds_latency_against_fast_sender() {
+        for drift in [200.0, -100.0] {
+            let (queue, levels) = simulate_queue(drift, 2 * 3600);
+            assert!(
+                (queue.compensator().drift() - drift).abs() < drift.abs() * 0.05,
+                "estimated {} ppm, expected {} ppm",
+                queue.compensator().drift(),
+                drift
+            );
+            // Uncorrected, 200 ppm would add up to well over a second
+            let (lowest, highest) = spread(&levels[1800..]);
+            assert!(
+                highest - lowest < 0.025,
+                "{} ppm: {}..{}",
+                drift,
+                lowest,
+                highest
+            );
+            let slipped = if drift > 0.0 {
+                queue.slipper().dropped
+            } else {
+                queue.slipper().repeated
+            };
+            assert!(slipped > 0);
+        }
+    }
+
+    #[test]
+    fn queue_drops_oldest_frames_when_full() {
+        let config = DEFAULT_CONFIG;
+        let frame_size = config.frame_size();
+        let mut queue = SlipQueue::new(DEFAULT_DRIFT_CONFIG, config);
+        // A couple of seconds arriving at once after a stall
+        let burst = config.sample_rate as usize * 2;
+        let frames: Vec<u8> = (0..burst).flat_map(|i| vec![i as u8; frame_size]).collect();
+        queue.push(&frames);
+        assert_eq!(queue.len(), config.max_buffer_size());
+        assert_eq!(queue.overflowed, (burst - config.max_buffer_frames) as u64);
+
+        let mut output = Vec::new();
+        assert!(queue.pop(0, Instant::now(), &mut output));
+        let first = burst - config.max_buffer_frames;
+        assert_eq!(&output[..frame_size], vec![first as u8; frame_size]);
+    }
+
+    #[test]
+    fn slips_whole_frames() {
+        let mut slipper = FrameSlipper::new(2);
+        let input: Vec<u8> = (0..=255).collect();
+        let mut output = Vec::new();
+        // Nothing changes at the nominal ratio
+        slipper.process(1.0, &input, &mut output);
+        assert_eq!(output, input);
+
+        // A quarter of a frame is owed per call
+        output.clear();
+        for _ in 0..10 {
+            slipper.process(1.0 + 1.0 / 512.0, &input, &mut output);
+        }
+        assert_eq!(slipper.dropped, 2);
+        assert_eq!(output.len(), 10 * input.len() - 2 * 2);
+
+        output.clear();
+        for _ in 0..10 {
+            slipper.process(1.0 - 1.0 / 512.0, &input, &mut output);
+        }
+        assert_eq!(slipper.repeated, 2);
+        assert_eq!(output.len(), 10 * input.len() + 2 * 2);
+        // Every frame is one of the input's, untouched
+        assert!(output.chunks_exact(2).all(|frame| frame[1] == frame[0] + 1));
+    }
+}
@@ -0,0 +1,755 @@
+use std::{error::Error, fmt::Display, io, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audiowire::{Config, SampleFormat, StreamType},
+    auth::{self, Authenticator, NONCE_SIZE, PROOF_SIZE},
+    opus::{OpusSettings, MAX_CHANNELS},
+    peer::{PeerReadHalf, PeerWriteHalf},
+};
+
+pub const MAGIC: [u8; 4] = *b"AWIR";
+pub const PROTOCOL_VERSION: u8 = 8;
+
+const STATUS_ACCEPTED: u8 = 0;
+const STATUS_REJECTED: u8 = 1;
+const STATUS_CHALLENGE: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Raw = 0,
+    Opus = 1,
+}
+
+impl Codec {
+    #[inline]
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Raw),
+            1 => Some(Self::Opus),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "opus" => Ok(Self::Opus),
+            other => Err(format!("Unknown codec: {}", other)),
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Raw => write!(f, "raw"),
+            Self::Opus => write!(f, "opus"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    InvalidMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    InvalidMessage(String),
+    Rejected(String),
+    // Raised on the server when a client fails to authenticate
+    Unauthorized(String),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Handshake failed: {}", err),
+            Self::InvalidMagic(magic) => write!(
+                f,
+                "Handshake failed: invalid magic {:02x?}, peer is not an audiowire endpoint",
+                magic
+            ),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Handshake failed: unsupported protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            Self::InvalidMessage(message) => write!(f, "Handshake failed: {}", message),
+            Self::Rejected(reason) => write!(f, "Handshake rejected: {}", reason),
+            Self::Unauthorized(reason) => write!(f, "Authentication failed: {}", reason),
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    #[inline]
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+type Result<T> = std::result::Result<T, HandshakeError>;
+
+// Hello is sent by the client right after connecting. Every field is laid out
+// in network byte order:
+//
+//   magic[4] version:u8 stream_type:u8 channels:u8 sample_format:u8
+//   sample_rate:u32 buffer_frames:u32 codec_count:u8 codecs[codec_count]:u8
+//   opus[OpusSettings::SIZE] identity_length:u8 identity[identity_length]
+//
+// Codecs are listed in order of preference. Frame duration is carried as the
+// number of frames per buffer, see Config::buffer_duration. The Opus settings
+// are the ones the client encodes with, whether or not Opus ends up picked.
+// Channels are the ones the client sends, they don't have to match the
+// server's as the receiving end of every stream remixes to its own. The
+// identity is the name the client goes by, the server may ask it to prove it.
+#[derive(Clone)]
+pub struct Hello {
+    pub stream_type: StreamType,
+    pub config: Config,
+    pub codecs: Vec<Codec>,
+    pub opus: OpusSettings,
+    pub identity: String,
+}
+
+impl Hello {
+    pub async fn write<P: PeerWriteHalf>(&self, peer: &mut P) -> io::Result<()> {
+        let identity = &self.identity.as_bytes()[..self.identity.len().min(u8::MAX as usize)];
+        let mut buf =
+            Vec::with_capacity(21 + self.codecs.len() + OpusSettings::SIZE + identity.len());
+        write_preamble(&mut buf);
+        buf.push(self.stream_type.to_bytes()[0]);
+        buf.push(self.config.channels);
+        buf.push(self.config.sample_format as u8);
+        buf.extend_from_slice(&self.config.sample_rate.to_be_bytes());
+        buf.extend_from_slice(&(self.config.buffer_frames as u32).to_be_bytes());
+        buf.push(self.codecs.len() as u8);
+        buf.extend(self.codecs.iter().map(|&codec| codec as u8));
+        buf.extend_from_slice(&self.opus.to_bytes());
+        buf.push(identity.len() as u8);
+        buf.extend_from_slice(identity);
+        peer.write_all(&buf).await
+    }
+
+    // Reads a hello using the local config as the base for the fields that
+    // aren't part of the message.
+    pub async fn read<P: PeerReadHalf>(peer: &mut P, local: Config) -> Result<Self> {
+        read_preamble(peer).await?;
+
+        let mut buf = [0u8; 12];
+        peer.read_exact(&mut buf).await?;
+        let sample_format = SampleFormat::from_u8(buf[2]).ok_or_else(|| {
+            HandshakeError::InvalidMessage(format!("unsupported sample format {}", buf[2]))
+        })?;
+        let config = Config {
+            channels: buf[1],
+            sample_rate: u32::from_be_bytes(buf[3..7].try_into().unwrap()),
+            sample_format,
+            buffer_frames: u32::from_be_bytes(buf[7..11].try_into().unwrap()) as usize,
+            ..local
+        };
+        let mut codecs = vec![0u8; buf[11] as usize];
+        peer.read_exact(&mut codecs).await?;
+        let opus = read_opus_settings(peer).await?;
+        let mut length = [0u8; 1];
+        peer.read_exact(&mut length).await?;
+        let mut identity = vec![0u8; length[0] as usize];
+        peer.read_exact(&mut identity).await?;
+
+        Ok(Self {
+            stream_type: StreamType::from([buf[0]]),
+            config,
+            // Codecs unknown to this build are skipped so newer peers can still negotiate
+            codecs: codecs.into_iter().filter_map(Codec::from_u8).collect(),
+            opus,
+            identity: String::from_utf8_lossy(&identity).to_string(),
+        })
+    }
+}
+
+// HelloReply is the server's verdict on a hello:
+//
+//   magic[4] version:u8 status:u8
+//   accepted: stream_type:u8 codec:u8 channels:u8 opus[OpusSettings::SIZE]
+//   rejected: reason_length:u16 reason[reason_length]
+//   challenge: nonce[NONCE_SIZE]
+//
+// A challenge is answered with proof[PROOF_SIZE], see auth::prove, after
+// which the server replies once more with its verdict.
+pub enum HelloReply {
+    Accept {
+        stream_type: StreamType,
+        codec: Codec,
+        channels: u8,
+        opus: OpusSettings,
+    },
+    Reject(String),
+    Challenge([u8; NONCE_SIZE]),
+}
+
+impl HelloReply {
+    pub async fn write<P: PeerWriteHalf>(&self, peer: &mut P) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(8 + OpusSettings::SIZE);
+        write_preamble(&mut buf);
+        match self {
+            Self::Accept {
+                stream_type,
+                codec,
+                channels,
+                opus,
+            } => {
+                buf.push(STATUS_ACCEPTED);
+                buf.push(stream_type.to_bytes()[0]);
+                buf.push(*codec as u8);
+                buf.push(*channels);
+                buf.extend_from_slice(&opus.to_bytes());
+            }
+            Self::Reject(reason) => {
+                let reason = &reason.as_bytes()[..reason.len().min(u16::MAX as usize)];
+                buf.push(STATUS_REJECTED);
+                buf.extend_from_slice(&(reason.len() as u16).to_be_bytes());
+                buf.extend_from_slice(reason);
+            }
+            Self::Challenge(nonce) => {
+                buf.push(STATUS_CHALLENGE);
+                buf.extend_from_slice(nonce);
+            }
+        }
+        peer.write_all(&buf).await
+    }
+
+    pub async fn read<P: PeerReadHalf>(peer: &mut P) -> Result<Self> {
+        read_preamble(peer).await?;
+
+        let mut status = [0u8; 1];
+        peer.read_exact(&mut status).await?;
+        match status[0] {
+            STATUS_ACCEPTED => {
+                let mut buf = [0u8; 3];
+                peer.read_exact(&mut buf).await?;
+                let codec = Codec::from_u8(buf[1]).ok_or_else(|| {
+                    HandshakeError::InvalidMessage(format!("unknown codec {}", buf[1]))
+                })?;
+                Ok(Self::Accept {
+                    stream_type: StreamType::from([buf[0]]),
+                    codec,
+                    channels: buf[2],
+                    opus: read_opus_settings(peer).await?,
+                })
+            }
+            STATUS_REJECTED => {
+                let mut length = [0u8; 2];
+                peer.read_exact(&mut length).await?;
+                let mut reason = vec![0u8; u16::from_be_bytes(length) as usize];
+                peer.read_exact(&mut reason).await?;
+                Ok(Self::Reject(String::from_utf8_lossy(&reason).to_string()))
+            }
+            STATUS_CHALLENGE => {
+                let mut nonce = [0u8; NONCE_SIZE];
+                peer.read_exact(&mut nonce).await?;
+                Ok(Self::Challenge(nonce))
+            }
+            other => Err(HandshakeError::InvalidMessage(format!(
+                "unknown handshake status {}",
+                other
+            ))),
+        }
+    }
+}
+
+pub struct Negotiated {
+    pub peer_type: StreamType,
+    pub codec: Codec,
+    // Channels of the audio the peer sends
+    pub peer_channels: u8,
+    // What the peer encodes its Opus packets with
+    pub peer_opus: OpusSettings,
+    // Who the client claims to be, proven if the server asked it to. Servers
+    // don't go by a name, so it's left empty on the client.
+    pub peer_identity: String,
+}
+
+// The secret is what the client answers a challenge with, a client without
+// one still answers so the server gets to tell it why it's turned away.
+pub async fn client_handshake<R, W>(
+    input: &mut R,
+    output: &mut W,
+    hello: &Hello,
+    secret: Option<&[u8]>,
+) -> Result<Negotiated>
+where
+    R: PeerReadHalf,
+    W: PeerWriteHalf,
+{
+    hello.write(output).await?;
+    let mut reply = HelloReply::read(input).await?;
+    if let HelloReply::Challenge(nonce) = reply {
+        let proof = auth::prove(secret.unwrap_or_default(), &nonce, &hello.identity);
+        output.write_all(&proof).await?;
+        reply = HelloReply::read(input).await?;
+    }
+    match reply {
+        HelloReply::Accept {
+            stream_type,
+            codec,
+            channels,
+            opus,
+        } if hello.codecs.contains(&codec) && channels > 0 => Ok(Negotiated {
+            peer_type: stream_type,
+            codec,
+            peer_channels: channels,
+            peer_opus: opus,
+            peer_identity: String::new(),
+        }),
+        HelloReply::Accept { channels: 0, .. } => Err(HandshakeError::InvalidMessage(
+            "server has no channels".to_owned(),
+        )),
+        HelloReply::Accept { codec, .. } => Err(HandshakeError::InvalidMessage(format!(
+            "server picked codec {} which was not offered",
+            codec
+        ))),
+        HelloReply::Reject(reason) => Err(HandshakeError::Rejected(reason)),
+        HelloReply::Challenge(_) => Err(HandshakeError::InvalidMessage(
+            "server sent a second challenge".to_owned(),
+        )),
+    }
+}
+
+// Clients are authenticated before anything about their audio is looked at,
+// so a client that fails learns nothing about the server's setup.
+pub async fn server_handshake<R, W>(
+    input: &mut R,
+    output: &mut W,
+    stream_type: StreamType,
+    config: Config,
+    codecs: &[Codec],
+    opus: OpusSettings,
+    auth: &Authenticator,
+) -> Result<Negotiated>
+where
+    R: PeerReadHalf,
+    W: PeerWriteHalf,
+{
+    let hello = match Hello::read(input, config).await {
+        Ok(hello) => hello,
+        // Let the other side know why it's being dropped before bailing out
+        Err(err @ HandshakeError::UnsupportedVersion(_)) => {
+            HelloReply::Reject(err.to_string()).write(output).await?;
+            return Err(err);
+        }
+        Err(err) => return Err(err),
+    };
+
+    if let Err(reason) = authenticate(input, output, &hello.identity, auth).await? {
+        // The client isn't told more than that it failed
+        HelloReply::Reject("authentication failed".to_owned())
+            .write(output)
+            .await?;
+        return Err(HandshakeError::Unauthorized(reason));
+    }
+
+    let codec = match negotiate(&hello, config, codecs) {
+        Ok(codec) => codec,
+        Err(reason) => {
+            HelloReply::Reject(reason.clone()).write(output).await?;
+            return Err(HandshakeError::Rejected(reason));
+        }
+    };
+    HelloReply::Accept {
+        stream_type,
+        codec,
+        channels: config.channels,
+        opus,
+    }
+    .write(output)
+    .await?;
+    Ok(Negotiated {
+        peer_type: hello.stream_type,
+        codec,
+        peer_channels: hello.config.channels,
+        peer_opus: hello.opus,
+        peer_identity: hello.identity,
+    })
+}
+
+// Challenges the client when the server requires it, the outer result is for
+// the connection and the inner one for the client's answer
+async fn authenticate<R, W>(
+    input: &mut R,
+    output: &mut W,
+    identity: &str,
+    auth: &Authenticator,
+) -> Result<std::result::Result<(), String>>
+where
+    R: PeerReadHalf,
+    W: PeerWriteHalf,
+{
+    if !auth.is_required() {
+        return Ok(auth.admit(identity));
+    }
+    let nonce = auth::new_nonce();
+    HelloReply::Challenge(nonce).write(output).await?;
+    let mut proof = [0u8; PROOF_SIZE];
+    input.read_exact(&mut proof).await?;
+    Ok(auth
+        .admit(identity)
+        .and_then(|_| auth.verify(identity, &nonce, &proof)))
+}
+
+fn negotiate(
+    hello: &Hello,
+    config: Config,
+    codecs: &[Codec],
+) -> std::result::Result<Codec, String> {
+    let remote = hello.config;
+    if remote.channels == 0 {
+        return Err("client has no channels".to_owned());
+    }
+    if remote.sample_rate != config.sample_rate
+        || remote.sample_format != config.sample_format
+        || remote.buffer_frames != config.buffer_frames
+    {
+        return Err(format!(
+            "audio config mismatch, client has {} but server has {}",
+            describe_config(&remote),
+            describe_config(&config)
+        ));
+    }
+    // Opus only goes up to 7.1, more channels than that have to be sent raw
+    let opus_channels = remote.channels.max(config.channels) <= MAX_CHANNELS;
+    hello
+        .codecs
+        .iter()
+        .find(|&&codec| codecs.contains(&codec) && (codec != Codec::Opus || opus_channels))
+        .copied()
+        .ok_or_else(|| {
+            let names: Vec<String> = codecs.iter().map(|c| c.to_string()).collect();
+            let mut reason = format!("no common codec, server supports: {}", names.join(", "));
+            if !opus_channels {
+                reason.push_str(&format!(" (Opus up to {} channels)", MAX_CHANNELS));
+            }
+            reason
+        })
+}
+
+fn describe_config(config: &Config) -> String {
+    format!(
+        "{} channel(s) {:?} at {} Hz with {} frames per buffer",
+        config.channels, config.sample_format, config.sample_rate, config.buffer_frames
+    )
+}
+
+async fn read_opus_settings<P: PeerReadHalf>(peer: &mut P) -> Result<OpusSettings> {
+    let mut buf = [0u8; OpusSettings::SIZE];
+    peer.read_exact(&mut buf).await?;
+    OpusSettings::from_bytes(&buf).map_err(HandshakeError::InvalidMessage)
+}
+
+#[inline]
+fn write_preamble(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&MAGIC);
+    buf.push(PROTOCOL_VERSION);
+}
+
+async fn read_preamble<P: PeerReadHalf>(peer: &mut P) -> Result<()> {
+    let mut buf = [0u8; 5];
+    peer.read_exact(&mut buf).await?;
+    let magic: [u8; 4] = buf[..4].try_into().unwrap();
+    if magic != MAGIC {
+        Err(HandshakeError::InvalidMagic(magic))
+    } else if buf[4] != PROTOCOL_VERSION {
+        Err(HandshakeError::UnsupportedVersion(buf[4]))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use tokio::net::{tcp, TcpListener, TcpStream};
+
+    use opus::{Application, Bitrate, FrameSize};
+
+    use crate::{opus::DEFAULT_OPUS_SETTINGS, DEFAULT_CONFIG};
+
+    use super::*;
+
+    const SERVER_OPUS: OpusSettings = OpusSettings {
+        bitrate: Bitrate::Bits(24000),
+        application: Application::Voip,
+        frame_duration: FrameSize::Ms10,
+        ..DEFAULT_OPUS_SETTINGS
+    };
+
+    async fn connect() -> (
+        (tcp::OwnedReadHalf, tcp::OwnedWriteHalf),
+        (tcp::OwnedReadHalf, tcp::OwnedWriteHalf),
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap().into_split(), server.unwrap().0.into_split())
+    }
+
+    async fn handshake(
+        hello: Hello,
+        config: Config,
+        codecs: &[Codec],
+    ) -> (Result<Negotiated>, Result<Negotiated>) {
+        authenticated_handshake(hello, None, &Authenticator::default(), config, codecs).await
+    }
+
+    async fn authenticated_handshake(
+        hello: Hello,
+        secret: Option<&[u8]>,
+        auth: &Authenticator,
+        config: Config,
+        codecs: &[Codec],
+    ) -> (Result<Negotiated>, Result<Negotiated>) {
+        let ((mut client_in, mut client_out), (mut server_in, mut server_out)) = connect().await;
+        let server_type = StreamType::new(true, false);
+        tokio::join!(
+            client_handshake(&mut client_in, &mut client_out, &hello, secret),
+            server_handshake(
+                &mut server_in,
+                &mut server_out,
+                server_type,
+                config,
+                codecs,
+                SERVER_OPUS,
+                auth,
+            ),
+        )
+    }
+
+    fn identified_hello(identity: &str) -> Hello {
+        Hello {
+            stream_type: StreamType::new(true, true),
+            config: DEFAULT_CONFIG,
+            codecs: vec![Codec::Raw],
+            opus: DEFAULT_OPUS_SETTINGS,
+            identity: identity.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_preferred_codec() {
+        let hello = Hello {
+            stream_type: StreamType::new(false, true),
+            config: DEFAULT_CONFIG,
+            codecs: vec![Codec::Opus, Codec::Raw],
+            opus: DEFAULT_OPUS_SETTINGS,
+            identity: String::new(),
+        };
+        let (client, server) = handshake(hello, DEFAULT_CONFIG, &[Codec::Raw, Codec::Opus]).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.codec, Codec::Opus);
+        assert_eq!(server.codec, Codec::Opus);
+        assert!(client.peer_type.is_source() && !client.peer_type.is_sink());
+        assert!(server.peer_type.is_sink() && !server.peer_type.is_source());
+        assert_eq!(client.peer_opus, SERVER_OPUS);
+        assert_eq!(server.peer_opus, DEFAULT_OPUS_SETTINGS);
+
+        let hello = Hello {
+            stream_type: StreamType::new(false, true),
+            config: DEFAULT_CONFIG,
+            codecs: vec![Codec::Opus, Codec::Raw],
+            opus: DEFAULT_OPUS_SETTINGS,
+            identity: String::new(),
+        };
+        let (client, server) = handshake(hello, DEFAULT_CONFIG, &[Codec::Raw]).await;
+        assert_eq!(client.unwrap().codec, Codec::Raw);
+        assert_eq!(server.unwrap().codec, Codec::Raw);
+    }
+
+    #[tokio::test]
+    async fn reject_config_mismatch() {
+        let hello = Hello {
+            stream_type: StreamType::new(true, true),
+            config: Config {
+                sample_rate: 44100,
+                ..DEFAULT_CONFIG
+            },
+            codecs: vec![Codec::Raw],
+            opus: DEFAULT_OPUS_SETTINGS,
+            identity: String::new(),
+        };
+        let (client, server) = handshake(hello, DEFAULT_CONFIG, &[Codec::Raw]).await;
+        assert!(
+            matches!(client, Err(HandshakeError::Rejected(reason)) if reason.contains("44100"))
+        );
+        assert!(matches!(server, Err(HandshakeError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn accept_different_channels() {
+        let hello = Hello {
+            stream_type: StreamType::new(true, true),
+            config: Config {
+                channels: 1,
+                ..DEFAULT_CONFIG
+            },
+            codecs: vec![Codec::Opus, Codec::Raw],
+            opus: DEFAULT_OPUS_SETTINGS,
+            identity: String::new(),
+        };
+        let (client, server) = handshake(hello, DEFAULT_CONFIG, &[Codec::Opus, Codec::Raw]).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.codec, Codec::Opus);
+        assert_eq!(client.peer_channels, DEFAULT_CONFIG.channels);
+        assert_eq!(server.peer_channels, 1);
+
+        // Past 7.1 only raw is left
+        let hello = Hello {
+            stream_type: StreamType::new(true, true),
+            config: Config {
+                channels: 16,
+                ..DEFAULT_CONFIG
+            },
+            codecs: vec![Codec::Opus, Codec::Raw],
+            opus: DEFAULT_OPUS_SETTINGS,
+            identity: String::new(),
+        };
+        let (client, server) =
+            handshake(hello.clone(), DEFAULT_CONFIG, &[Codec::Opus, Codec::Raw]).await;
+        assert_eq!(client.unwrap().codec, Codec::Raw);
+        assert_eq!(server.unwrap().peer_channels, 16);
+        let (client, _) = handshake(hello, DEFAULT_CONFIG, &[Codec::Opus]).await;
+        assert!(
+            matches!(client, Err(HandshakeError::Rejected(reason)) if reason.contains("channels"))
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_without_common_codec() {
+        let hello = Hello {
+            stream_type: StreamType::new(true, true),
+            config: DEFAULT_CONFIG,
+            codecs: vec![Codec::Opus],
+            opus: DEFAULT_OPUS_SETTINGS,
+            identity: String::new(),
+        };
+        let (client, server) = handshake(hello, DEFAULT_CONFIG, &[Codec::Raw]).await;
+        assert!(matches!(client, Err(HandshakeError::Rejected(_))));
+        assert!(matches!(server, Err(HandshakeError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn authenticate_with_secret() {
+        let auth = Authenticator {
+            psk: Some(b"shared".to_vec()),
+            tokens: HashMap::from([("kitchen".to_owned(), b"token".to_vec())]),
+            ..Default::default()
+        };
+        let (client, server) = authenticated_handshake(
+            identified_hello("kitchen"),
+            Some(b"token"),
+            &auth,
+            DEFAULT_CONFIG,
+            &[Codec::Raw],
+        )
+        .await;
+        assert!(client.is_ok());
+        assert_eq!(server.unwrap().peer_identity, "kitchen");
+
+        let (client, server) = authenticated_handshake(
+            identified_hello("attic"),
+            Some(b"shared"),
+            &auth,
+            DEFAULT_CONFIG,
+            &[Codec::Raw],
+        )
+        .await;
+        assert!(client.is_ok());
+        assert_eq!(server.unwrap().peer_identity, "attic");
+
+        for secret in [Some(&b"shared"[..]), Some(b"wrong"), None] {
+            let (client, server) = authenticated_handshake(
+                identified_hello("kitchen"),
+                secret,
+                &auth,
+                DEFAULT_CONFIG,
+                &[Codec::Raw],
+            )
+            .await;
+            assert!(
+                matches!(client, Err(HandshakeError::Rejected(reason)) if reason == "authentication failed")
+            );
+            assert!(matches!(server, Err(HandshakeError::Unauthorized(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_denied_identity() {
+        let auth = Authenticator {
+            deny: HashSet::from(["attic".to_owned()]),
+            ..Default::default()
+        };
+        let (client, server) = authenticated_handshake(
+            identified_hello("attic"),
+            None,
+            &auth,
+            DEFAULT_CONFIG,
+            &[Codec::Raw],
+        )
+        .await;
+        assert!(matches!(client, Err(HandshakeError::Rejected(_))));
+        assert!(
+            matches!(server, Err(HandshakeError::Unauthorized(reason)) if reason.contains("denied"))
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_unsupported_version() {
+        let ((mut client_in, mut client_out), (mut server_in, mut server_out)) = connect().await;
+        let mut hello = MAGIC.to_vec();
+        hello.push(PROTOCOL_VERSION + 1);
+        client_out.write_all(&hello).await.unwrap();
+
+        let server_type = StreamType::new(true, true);
+        let server = server_handshake(
+            &mut server_in,
+            &mut server_out,
+            server_type,
+            DEFAULT_CONFIG,
+            &[Codec::Raw],
+            DEFAULT_OPUS_SETTINGS,
+            &Authenticator::default(),
+        )
+        .await;
+        assert!(
+            matches!(server, Err(HandshakeError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1)
+        );
+        let reply = HelloReply::read(&mut client_in).await.unwrap();
+        assert!(matches!(reply, HelloReply::Reject(reason) if reason.contains("version")));
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_magic() {
+        let ((_, mut client_out), (mut server_in, mut server_out)) = connect().await;
+        client_out.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        let server_type = StreamType::new(true, true);
+        let server = server_handshake(
+            &mut server_in,
+            &mut server_out,
+            server_type,
+            DEFAULT_CONFIG,
+            &[Codec::Raw],
+            DEFAULT_OPUS_SETTINGS,
+            &Authenticator::default(),
+        )
+        .await;
+        assert!(matches!(server, Err(HandshakeError::InvalidMagic(_))));
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::packet::PacketHeader;
+
+#[derive(Clone, Copy, Debug)]
+pub struct JitterConfig {
+    // Lowest target delay, and the fixed one when adaptive is off
+    pub min_delay: Duration,
+    // Packets queued past this delay are dropped, oldest first
+    pub max_delay: Duration,
+    pub adaptive: bool,
+}
+
+pub const DEFAULT_JITTER_CONFIG: JitterConfig = JitterConfig {
+    min_delay: Duration::from_millis(40),
+    max_delay: Duration::from_millis(400),
+    adaptive: true,
+};
+
+// How many times the measured jitter is kept buffered on top of one packet
+const JITTER_MULTIPLIER: f64 = 3.0;
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Playout {
+    Frame(Vec<u8>),
+    // The packet due now never arrived, its slot has to be concealed
+    Missing,
+    // Waiting until enough packets are queued to reach the target delay
+    Buffering,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct JitterStats {
+    pub depth: Duration,
+    pub target: Duration,
+    pub jitter: Duration,
+    pub late: u64,
+    pub missing: u64,
+    pub dropped: u64,
+    pub underruns: u64,
+}
+
+// JitterBuffer sits between the network and the decoder. Packets are queued
+// by sequence number as they arrive and played out one per packet period,
+// once enough of them are buffered to ride out the variation in arrival
+// times. The target delay follows an interarrival jitter estimate the same
+// way RTP receivers compute it.
+pub struct JitterBuffer {
+    config: JitterConfig,
+    packet_frames: usize,
+    sample_rate: u32,
+    packets: BTreeMap<u64, Vec<u8>>,
+    // Sequence numbers are extended to 64 bits so ordering survives the wrap
+    highest: Option<u64>,
+    next: Option<u64>,
+    buffering: bool,
+    // Arrival and timestamp of the packet before, transit times are only
+    // compared between consecutive packets so timestamps are free to wrap
+    previous: Option<(Instant, u32)>,
+    jitter: f64,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(config: JitterConfig, packet_frames: usize, sample_rate: u32) -> Self {
+        Self {
+            config,
+            packet_frames: packet_frames.max(1),
+            sample_rate,
+            packets: BTreeMap::new(),
+            highest: None,
+            next: None,
+            buffering: true,
+            previous: None,
+            jitter: 0.0,
+            stats: JitterStats::default(),
+        }
+    }
+
+    // Returns false if the packet was discarded for being late or a duplicate
+    pub fn push(&mut self, header: PacketHeader, payload: &[u8], arrival: Instant) -> bool {
+        let sequence = self.extend(header.sequence);
+        self.update_jitter(header.timestamp, arrival);
+        if self.next.is_some_and(|next| sequence < next) || self.packets.contains_key(&sequence) {
+            self.stats.late += 1;
+            return false;
+        }
+
+        self.packets.insert(sequence, payload.to_vec());
+        self.highest = Some(
+            self.highest
+                .map_or(sequence, |highest| highest.max(sequence)),
+        );
+        let max_packets = self.duration_to_packets(self.config.max_delay).max(1);
+        while self.packets.len() > max_packets {
+            self.drop_oldest();
+        }
+        true
+    }
+
+    pub fn pop(&mut self) -> Playout {
+        if self.buffering {
+            if self.packets.len() < self.target_packets() {
+                return Playout::Buffering;
+            }
+            // Whatever was missing while refilling is skipped over
+            self.buffering = false;
+            self.next = self.packets.keys().next().copied();
+        }
+
+        if self.packets.is_empty() {
+            self.buffering = true;
+            self.stats.underruns += 1;
+            return Playout::Buffering;
+        }
+
+        // Catch up when the queue has grown well past the target, so latency
+        // comes back down once the network settles.
+        let target = self.target_packets();
+        if self.config.adaptive && self.packets.len() > target + target.max(2) {
+            self.drop_oldest();
+        }
+
+        let Some(next) = self.next else {
+            return Playout::Buffering;
+        };
+        self.next = Some(next + 1);
+        match self.packets.remove(&next) {
+            Some(payload) => Playout::Frame(payload),
+            None => {
+                self.stats.missing += 1;
+                Playout::Missing
+            }
+        }
+    }
+
+    // Payload due at the next pop, if it has arrived already
+    #[inline]
+    pub fn peek(&self) -> Option<&[u8]> {
+        let next = self.next?;
+        self.packets.get(&next).map(|payload| payload.as_slice())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    #[inline]
+    pub fn depth(&self) -> Duration {
+        self.packets_to_duration(self.packets.len())
+    }
+
+    #[inline]
+    pub fn target_delay(&self) -> Duration {
+        self.packets_to_duration(self.target_packets())
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats {
+            depth: self.depth(),
+            target: self.target_delay(),
+            jitter: Duration::from_secs_f64(self.jitter / self.sample_rate as f64),
+            ..self.stats
+        }
+    }
+
+    fn extend(&self, sequence: u16) -> u64 {
+        match self.highest {
+            // Start far enough from zero that early reordering can't underflow
+            None => (1 << 32) + sequence as u64,
+            Some(highest) => {
+                let diff = sequence.wrapping_sub(highest as u16) as i16;
+                highest.wrapping_add_signed(diff as i64)
+            }
+        }
+    }
+
+    // Interarrival jitter from RFC 3550, kept in frames
+    fn update_jitter(&mut self, timestamp: u32, arrival: Instant) {
+        if let Some((previous_arrival, previous_timestamp)) = self.previous {
+            let elapsed = arrival.saturating_duration_since(previous_arrival);
+            let elapsed = elapsed.as_secs_f64() * self.sample_rate as f64;
+            let advanced = timestamp.wrapping_sub(previous_timestamp) as i32;
+            let d = (elapsed - advanced as f64).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.previous = Some((arrival, timestamp));
+    }
+
+    fn target_packets(&self) -> usize {
+        let min = self.duration_to_packets(self.config.min_delay).max(1);
+        if !self.config.adaptive {
+            return min;
+        }
+        let max = self.duration_to_packets(self.config.max_delay).max(min);
+        let frames = self.packet_frames as f64 + JITTER_MULTIPLIER * self.jitter;
+        let packets = (frames / self.packet_frames as f64).ceil() as usize;
+        packets.clamp(min, max)
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some((sequence, _)) = self.packets.pop_first() {
+            self.stats.dropped += 1;
+            if self.next.is_some() {
+                self.next = Some(sequence + 1);
+            }
+        }
+    }
+
+    #[inline]
+    fn duration_to_packets(&self, duration: Duration) -> usize {
+        let frames = duration.as_micros() * self.sample_rate as u128 / 1_000_000;
+        (frames as usize).div_ceil(self.packet_frames)
+    }
+
+    #[inline]
+    fn packets_to_duration(&self, count: usize) -> Duration {
+        let frames = (count * self.packet_frames) as u64;
+        Duration::from_micros(frames * 1_000_000 / self.sample_rate as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET_FRAMES: usize = 960;
+    const SAMPLE_RATE: u32 = 48000;
+    const PACKET_DURATION: Duration = Duration::from_millis(20);
+
+    const CONFIG: JitterConfig = JitterConfig {
+        min_delay: Duration::from_millis(40),
+        max_delay: Duration::from_millis(200),
+        adaptive: false,
+    };
+
+    fn header(sequence: u16) -> PacketHeader {
+        PacketHeader {
+            sequence,
+            timestamp: (sequence as u32).wrapping_mul(PACKET_FRAMES as u32),
+        }
+    }
+
+    fn push_all(buffer: &mut JitterBuffer, sequences: &[u16]) {
+        let start = Instant::now();
+        for &sequence in sequences {
+            let arrival = start + PACKET_DURATION * sequence as u32;
+            buffer.push(header(sequence), &[sequence as u8], arrival);
+        }
+    }
+
+    #[test]
+    fn reorders_and_conceals_missing() {
+        let mut buffer = JitterBuffer::new(CONFIG, PACKET_FRAMES, SAMPLE_RATE);
+        assert_eq!(buffer.pop(), Playout::Buffering);
+        push_all(&mut buffer, &[1, 0, 3, 4]);
+        assert_eq!(buffer.depth(), PACKET_DURATION * 4);
+
+        assert_eq!(buffer.pop(), Playout::Frame(vec![0]));
+        assert_eq!(buffer.pop(), Playout::Frame(vec![1]));
+        assert_eq!(buffer.pop(), Playout::Missing);
+        assert_eq!(buffer.peek(), Some([3].as_slice()));
+        assert_eq!(buffer.pop(), Playout::Frame(vec![3]));
+
+        // Already played out, too late to be of any use
+        assert!(!buffer.push(header(2), &[2], Instant::now()));
+        assert_eq!(buffer.pop(), Playout::Frame(vec![4]));
+        assert_eq!(buffer.pop(), Playout::Buffering);
+
+        let stats = buffer.stats();
+        assert_eq!(stats.missing, 1);
+        assert_eq!(stats.late, 1);
+        assert_eq!(stats.underruns, 1);
+    }
+
+    #[test]
+    fn survives_sequence_wrap() {
+        let mut buffer = JitterBuffer::new(CONFIG, PACKET_FRAMES, SAMPLE_RATE);
+        let start = Instant::now();
+        for (i, sequence) in [0xFFFE, 0, 0xFFFF, 1].into_iter().enumerate() {
+            buffer.push(
+                header(sequence),
+                &[i as u8],
+                start + PACKET_DURATION * i as u32,
+            );
+        }
+        assert_eq!(buffer.pop(), Playout::Frame(vec![0]));
+        assert_eq!(buffer.pop(), Playout::Frame(vec![2]));
+        assert_eq!(buffer.pop(), Playout::Frame(vec![1]));
+        assert_eq!(buffer.pop(), Playout::Frame(vec![3]));
+    }
+
+    #[test]
+    fn jitter_survives_timestamp_wrap() {
+        let config = JitterConfig {
+            adaptive: true,
+            ..CONFIG
+        };
+        let mut buffer = JitterBuffer::new(config, PACKET_FRAMES, SAMPLE_RATE);
+        let start = Instant::now();
+        let first = u32::MAX - 5 * PACKET_FRAMES as u32;
+        for sequence in 0..20u16 {
+            let header = PacketHeader {
+                sequence,
+                timestamp: first.wrapping_add(sequence as u32 * PACKET_FRAMES as u32),
+            };
+            buffer.push(header, &[], start + PACKET_DURATION * sequence as u32);
+            buffer.pop();
+        }
+        assert!(buffer.stats().jitter < Duration::from_millis(1));
+        assert_eq!(buffer.target_delay(), config.min_delay);
+    }
+
+    #[test]
+    fn drops_past_max_delay() {
+        let mut buffer = JitterBuffer::new(CONFIG, PACKET_FRAMES, SAMPLE_RATE);
+        push_all(&mut buffer, &(0..20).collect::<Vec<_>>());
+        assert_eq!(buffer.depth(), CONFIG.max_delay);
+        assert_eq!(buffer.stats().dropped, 10);
+        assert_eq!(buffer.pop(), Playout::Frame(vec![10]));
+    }
+
+    #[test]
+    fn adapts_target_to_jitter() {
+        let config = JitterConfig {
+            adaptive: true,
+            ..CONFIG
+        };
+        let mut buffer = JitterBuffer::new(config, PACKET_FRAMES, SAMPLE_RATE);
+        push_all(&mut buffer, &[0, 1, 2, 3]);
+        assert_eq!(buffer.target_delay(), config.min_delay);
+
+        // Packets arriving in bursts of two make the estimate grow
+        let start = Instant::now();
+        for sequence in 4..64u16 {
+            let arrival = start + PACKET_DURATION * (sequence as u32 / 2 * 2);
+            buffer.push(header(sequence), &[], arrival);
+            buffer.pop();
+        }
+        assert!(buffer.target_delay() > config.min_delay);
+        assert!(buffer.target_delay() <= config.max_delay);
+    }
+}
@@ -1,9 +1,22 @@
 mod audiowire;
 
+pub mod auth;
+pub mod backoff;
+pub mod cli;
+pub mod control;
+pub mod drift;
 pub mod handlers;
+pub mod handshake;
+pub mod jitter;
 pub mod logging;
+pub mod mixer;
+pub mod mux;
 pub mod opus;
+pub mod packet;
 pub mod peer;
+pub mod remix;
+pub mod secure;
+pub mod session;
 
 pub use audiowire::*;
 
@@ -13,4 +26,5 @@ pub const DEFAULT_CONFIG: Config = Config {
     sample_format: SampleFormat::S16,
     buffer_frames: 960,
     max_buffer_frames: 14400,
+    device_rate: None,
 };
@@ -1,23 +1,17 @@
-use std::{
-    env,
-    io::{self, Write},
-};
+use std::io::{self, Write};
 
 use slog::{o, Drain, Logger, OwnedKV, Record, SendSyncRefUnwindSafeKV};
 use slog_term::{CountingWriter, RecordDecorator, ThreadSafeTimestampFn};
 
 #[inline]
-pub fn term_logger() -> Logger {
-    term_logger_with_values(o!())
+pub fn term_logger(timestamp_disabled: bool) -> Logger {
+    term_logger_with_values(timestamp_disabled, o!())
 }
 
-pub fn term_logger_with_values<T>(kv: OwnedKV<T>) -> Logger
+pub fn term_logger_with_values<T>(timestamp_disabled: bool, kv: OwnedKV<T>) -> Logger
 where
     T: SendSyncRefUnwindSafeKV + 'static,
 {
-    let timestamp_disabled = env::var("LOG_TIMESTAMP_DISABLED")
-        .map(|s| s == "1")
-        .unwrap_or_default();
     let decorator = slog_term::TermDecorator::new().build();
     let root_builder = slog_term::FullFormat::new(decorator);
     let builder = if timestamp_disabled {
@@ -0,0 +1,513 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+use crate::{Config, PlaybackStream, RecordStream, Result, Stream};
+
+// PlaybackQueue is anything a playback handler can feed decoded audio into,
+// either a device stream of its own or an input of a shared mixer.
+pub trait PlaybackQueue: Send {
+    fn capacity(&self) -> usize;
+    // Bytes that can be written without overflowing
+    fn peek(&self) -> usize;
+    fn write(&mut self, buf: &[u8]) -> usize;
+    fn stop(&mut self) -> Result<()>;
+    // Resolves once at least len bytes, or a full buffer, can be written
+    fn writable(&self, len: usize) -> impl Future<Output = ()> + Send;
+}
+
+// RecordQueue is anything a record handler can pull audio from, either a
+// device stream of its own or an output of a shared fanout.
+pub trait RecordQueue: Send {
+    // Bytes ready to be read
+    fn peek(&self) -> usize;
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+    fn stop(&mut self) -> Result<()>;
+    // Resolves once at least len bytes, or a full buffer, can be read
+    fn readable(&self, len: usize) -> impl Future<Output = ()> + Send;
+}
+
+impl PlaybackQueue for PlaybackStream {
+    #[inline]
+    fn capacity(&self) -> usize {
+        Stream::capacity(self)
+    }
+
+    #[inline]
+    fn peek(&self) -> usize {
+        Stream::peek(self)
+    }
+
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> usize {
+        PlaybackStream::write(self, buf)
+    }
+
+    #[inline]
+    fn stop(&mut self) -> Result<()> {
+        Stream::stop(self)
+    }
+
+    #[inline]
+    fn writable(&self, len: usize) -> impl Future<Output = ()> + Send {
+        PlaybackStream::writable(self, len)
+    }
+}
+
+impl RecordQueue for RecordStream {
+    #[inline]
+    fn peek(&self) -> usize {
+        Stream::peek(self)
+    }
+
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        RecordStream::read(self, buf)
+    }
+
+    #[inline]
+    fn stop(&mut self) -> Result<()> {
+        Stream::stop(self)
+    }
+
+    #[inline]
+    fn readable(&self, len: usize) -> impl Future<Output = ()> + Send {
+        RecordStream::readable(self, len)
+    }
+}
+
+struct Input {
+    gain: f32,
+    queue: VecDeque<u8>,
+    // Signalled whenever the mixer drained some of the queue
+    drained: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct Inputs {
+    next_id: u64,
+    inputs: HashMap<u64, Input>,
+}
+
+// Mixer sums every input into one stream of audio, each scaled by its own
+// gain. Inputs behave like playback devices of their own, the mixer drains
+// them at the pace of the device it feeds.
+#[derive(Clone)]
+pub struct Mixer {
+    config: Config,
+    inputs: Arc<Mutex<Inputs>>,
+    // Signalled whenever any input got written to
+    queued: Arc<Notify>,
+}
+
+impl Mixer {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            inputs: Default::default(),
+            queued: Default::default(),
+        }
+    }
+
+    pub fn add_input(&self, gain: f32) -> MixerInput {
+        let mut inputs = self.inputs.lock().unwrap();
+        let id = inputs.next_id;
+        inputs.next_id += 1;
+        let drained = Arc::new(Notify::new());
+        inputs.inputs.insert(
+            id,
+            Input {
+                gain,
+                queue: VecDeque::new(),
+                drained: Arc::clone(&drained),
+            },
+        );
+        MixerInput {
+            id,
+            max_bufsize: self.config.max_buffer_size(),
+            inputs: Arc::clone(&self.inputs),
+            drained,
+            queued: Arc::clone(&self.queued),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inputs.lock().unwrap().inputs.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Resolves once any input has something to mix
+    pub async fn readable(&self) {
+        loop {
+            let notified = self.queued.notified();
+            let queued = self
+                .inputs
+                .lock()
+                .unwrap()
+                .inputs
+                .values()
+                .any(|input| !input.queue.is_empty());
+            if queued {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    // Mixes as many whole frames as the fullest input holds, up to the length
+    // of the buffer. Inputs running short are padded with silence. Returns
+    // the number of bytes mixed, zero when every input is empty.
+    pub fn mix(&self, buf: &mut [u8]) -> usize {
+        let format = self.config.sample_format;
+        let sample_size = format.size();
+        let frame_size = self.config.frame_size();
+        let mut inputs = self.inputs.lock().unwrap();
+        let queued = inputs
+            .inputs
+            .values()
+            .map(|input| input.queue.len())
+            .max()
+            .unwrap_or_default();
+        let length = queued.min(buf.len()) / frame_size * frame_size;
+        if length == 0 {
+            return 0;
+        }
+
+        let mut mixed = vec![0f32; length / sample_size];
+        for input in inputs.inputs.values_mut() {
+            let size = input.queue.len().min(length) / sample_size * sample_size;
+            let bytes = &input.queue.make_contiguous()[..size];
+            for (value, sample) in mixed.iter_mut().zip(bytes.chunks_exact(sample_size)) {
+                *value += format.decode(sample) * input.gain;
+            }
+            input.queue.drain(..size);
+            input.drained.notify_one();
+        }
+        for (value, dst) in mixed.iter().zip(buf.chunks_exact_mut(sample_size)) {
+            format.encode(*value, dst);
+        }
+        length
+    }
+}
+
+pub struct MixerInput {
+    id: u64,
+    max_bufsize: usize,
+    inputs: Arc<Mutex<Inputs>>,
+    drained: Arc<Notify>,
+    queued: Arc<Notify>,
+}
+
+impl MixerInput {
+    pub fn gain(&self) -> f32 {
+        self.with_input(|input| input.gain).unwrap_or_default()
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.with_input(|input| input.gain = gain);
+    }
+
+    #[inline]
+    fn with_input<T>(&self, f: impl FnOnce(&mut Input) -> T) -> Option<T> {
+        self.inputs.lock().unwrap().inputs.get_mut(&self.id).map(f)
+    }
+}
+
+impl PlaybackQueue for MixerInput {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.max_bufsize
+    }
+
+    #[inline]
+    fn peek(&self) -> usize {
+        let queued = self.with_input(|input| input.queue.len());
+        self.max_bufsize - queued.unwrap_or(self.max_bufsize)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> usize {
+        let max_bufsize = self.max_bufsize;
+        self.with_input(|input| {
+            input.queue.extend(buf);
+            // Same as a device ring buffer, overflowing writes drop the oldest data
+            let overflow = input.queue.len().saturating_sub(max_bufsize);
+            input.queue.drain(..overflow);
+            buf.len()
+        })
+        .inspect(|_| self.queued.notify_one())
+        .unwrap_or_default()
+    }
+
+    // Whatever is still queued is dropped along with the input
+    #[inline]
+    fn stop(&mut self) -> Result<()> {
+        self.inputs.lock().unwrap().inputs.remove(&self.id);
+        Ok(())
+    }
+
+    // A removed input never fills up, writes to it are simply discarded
+    async fn writable(&self, len: usize) {
+        let len = len.min(self.max_bufsize);
+        loop {
+            let notified = self.drained.notified();
+            match self.with_input(|input| input.queue.len()) {
+                Some(queued) if self.max_bufsize - queued < len => notified.await,
+                _ => return,
+            }
+        }
+    }
+}
+
+impl Drop for MixerInput {
+    fn drop(&mut self) {
+        self.stop().unwrap_or_default();
+    }
+}
+
+struct Output {
+    queue: VecDeque<u8>,
+    // Signalled whenever the fanout pushed more audio
+    pushed: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct Outputs {
+    next_id: u64,
+    outputs: HashMap<u64, Output>,
+}
+
+// Fanout hands a copy of one stream of audio to every output. Outputs behave
+// like record devices of their own, each read at its own pace.
+#[derive(Clone)]
+pub struct Fanout {
+    max_bufsize: usize,
+    outputs: Arc<Mutex<Outputs>>,
+}
+
+impl Fanout {
+    pub fn new(config: Config) -> Self {
+        Self {
+            max_bufsize: config.max_buffer_size(),
+            outputs: Default::default(),
+        }
+    }
+
+    pub fn add_output(&self) -> FanoutOutput {
+        let mut outputs = self.outputs.lock().unwrap();
+        let id = outputs.next_id;
+        outputs.next_id += 1;
+        let pushed = Arc::new(Notify::new());
+        outputs.outputs.insert(
+            id,
+            Output {
+                queue: VecDeque::new(),
+                pushed: Arc::clone(&pushed),
+            },
+        );
+        FanoutOutput {
+            id,
+            max_bufsize: self.max_bufsize,
+            outputs: Arc::clone(&self.outputs),
+            pushed,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.outputs.lock().unwrap().outputs.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&self, buf: &[u8]) {
+        for output in self.outputs.lock().unwrap().outputs.values_mut() {
+            output.queue.extend(buf);
+            // Outputs that fall behind lose the oldest data first
+            let overflow = output.queue.len().saturating_sub(self.max_bufsize);
+            output.queue.drain(..overflow);
+            output.pushed.notify_one();
+        }
+    }
+}
+
+pub struct FanoutOutput {
+    id: u64,
+    max_bufsize: usize,
+    outputs: Arc<Mutex<Outputs>>,
+    pushed: Arc<Notify>,
+}
+
+impl RecordQueue for FanoutOutput {
+    #[inline]
+    fn peek(&self) -> usize {
+        let outputs = self.outputs.lock().unwrap();
+        outputs
+            .outputs
+            .get(&self.id)
+            .map_or(0, |output| output.queue.len())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut outputs = self.outputs.lock().unwrap();
+        let Some(output) = outputs.outputs.get_mut(&self.id) else {
+            return 0;
+        };
+        let length = buf.len().min(output.queue.len());
+        for (dst, src) in buf.iter_mut().zip(output.queue.drain(..length)) {
+            *dst = src;
+        }
+        length
+    }
+
+    #[inline]
+    fn stop(&mut self) -> Result<()> {
+        self.outputs.lock().unwrap().outputs.remove(&self.id);
+        Ok(())
+    }
+
+    async fn readable(&self, len: usize) {
+        let len = len.min(self.max_bufsize);
+        loop {
+            let notified = self.pushed.notified();
+            if self.peek() >= len {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for FanoutOutput {
+    fn drop(&mut self) {
+        self.stop().unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::{SampleFormat, DEFAULT_CONFIG};
+
+    use super::*;
+
+    fn samples(values: &[i16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    #[test]
+    fn mixes_inputs_with_gain() {
+        let mixer = Mixer::new(DEFAULT_CONFIG);
+        let mut first = mixer.add_input(1.0);
+        let mut second = mixer.add_input(0.5);
+        assert_eq!(mixer.len(), 2);
+
+        first.write(&samples(&[1000, -1000, 30000, 30000]));
+        second.write(&samples(&[2000, 2000]));
+        let mut buf = [0u8; 16];
+        assert_eq!(mixer.mix(&mut buf), 8);
+        assert_eq!(buf[..8], samples(&[2000, 0, 30000, 30000]));
+        assert_eq!(first.peek(), first.capacity());
+
+        // Sums past full scale are clipped rather than wrapped around
+        second.set_gain(2.0);
+        first.write(&samples(&[30000, -30000]));
+        second.write(&samples(&[30000, -30000]));
+        assert_eq!(mixer.mix(&mut buf), 4);
+        assert_eq!(buf[..4], samples(&[i16::MAX, i16::MIN]));
+
+        drop(first);
+        assert_eq!(mixer.len(), 1);
+        assert_eq!(mixer.mix(&mut buf), 0);
+    }
+
+    #[test]
+    fn mixes_f64() {
+        let format = SampleFormat::F64;
+        let mixer = Mixer::new(Config {
+            sample_format: format,
+            ..DEFAULT_CONFIG
+        });
+        let mut first = mixer.add_input(1.0);
+        let mut second = mixer.add_input(0.5);
+        let encode = |values: &[f64]| -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect()
+        };
+
+        first.write(&encode(&[0.25, -0.5, 0.125, 0.0]));
+        second.write(&encode(&[0.5, 0.5, -0.25, 1.0]));
+        let mut buf = [0u8; 64];
+        assert_eq!(mixer.mix(&mut buf), 32);
+        assert_eq!(buf[..32], encode(&[0.5, -0.25, 0.0, 0.5]));
+    }
+
+    #[test]
+    fn fans_out_to_every_output() {
+        let fanout = Fanout::new(DEFAULT_CONFIG);
+        let mut first = fanout.add_output();
+        let mut second = fanout.add_output();
+        fanout.push(&[1, 2, 3, 4]);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(first.read(&mut buf[..2]), 2);
+        assert_eq!(buf[..2], [1, 2]);
+        assert_eq!(first.peek(), 2);
+        assert_eq!(second.read(&mut buf), 4);
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        // Outputs falling behind keep only the latest audio
+        let max_bufsize = DEFAULT_CONFIG.max_buffer_size();
+        fanout.push(&vec![5; max_bufsize]);
+        assert_eq!(first.peek(), max_bufsize);
+        assert_eq!(second.peek(), max_bufsize);
+
+        second.stop().unwrap();
+        assert_eq!(fanout.len(), 1);
+        assert_eq!(second.read(&mut buf), 0);
+    }
+
+    #[tokio::test]
+    async fn wakes_up_waiting_queues() {
+        const TIMEOUT: Duration = Duration::from_secs(1);
+        let max_bufsize = DEFAULT_CONFIG.max_buffer_size();
+        let mixer = Mixer::new(DEFAULT_CONFIG);
+        let mut input = mixer.add_input(1.0);
+        input.write(&vec![0; max_bufsize]);
+        timeout(TIMEOUT, mixer.readable()).await.unwrap();
+
+        let waiting = tokio::spawn(async move {
+            input.writable(4).await;
+            input.peek()
+        });
+        let mut buf = [0u8; 4];
+        assert_eq!(mixer.mix(&mut buf), 4);
+        assert_eq!(timeout(TIMEOUT, waiting).await.unwrap().unwrap(), 4);
+
+        let fanout = Fanout::new(DEFAULT_CONFIG);
+        let output = fanout.add_output();
+        let waiting = tokio::spawn(async move {
+            output.readable(4).await;
+            output.peek()
+        });
+        fanout.push(&[1, 2]);
+        fanout.push(&[3, 4]);
+        assert_eq!(timeout(TIMEOUT, waiting).await.unwrap().unwrap(), 4);
+    }
+}
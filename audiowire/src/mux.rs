@@ -0,0 +1,731 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use slog::{info, warn, Logger};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::{interval, timeout, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    control::Control,
+    peer::{self, PeerPacketRead, PeerPacketWrite, PeerWriteHalf, QueueProducer, QueueReadHalf},
+};
+
+// Once the handshake is done every packet on the wire starts with its kind,
+// so keepalives and control messages travel in between audio frames on the
+// same connection:
+//
+//   kind:u8 payload[]
+//
+// Audio payloads are whatever the streams write. Raw buffers too large for a
+// single packet go out in parts, each but the last one sent as a part, and
+// are handed on or dropped as a whole on the receiving end so playback never
+// loses track of where frames start. Pings carry the sender's
+// clock as u64 microseconds and pongs echo it back untouched, the sender
+// measures the round trip from it without keeping any state. Control
+// payloads are laid out as described in control.
+//
+// Control messages and pongs are queued apart from the audio and always go
+// out first, so a congested link delays audio rather than dropping them.
+const KIND_AUDIO: u8 = 0;
+const KIND_PING: u8 = 1;
+const KIND_PONG: u8 = 2;
+const KIND_CONTROL: u8 = 3;
+const KIND_AUDIO_PART: u8 = 4;
+
+// Raw audio is split up so every frame fits in a packet of its own
+const MAX_PAYLOAD: usize = 32768;
+const MAX_PACKET: usize = 65536;
+// Most a peer gets to send in parts of one buffer
+const MAX_AUDIO_BUFFER: usize = 16 << 20;
+const FRAME_BACKLOG: usize = 64;
+const CONTROL_BACKLOG: usize = 16;
+// Control messages and pongs waiting to go out ahead of the audio
+const URGENT_BACKLOG: usize = 16;
+// How long a goodbye gets to go out before the connection is dropped anyway
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig {
+    // How often each end pings the other
+    pub interval: Duration,
+    // The session is torn down once nothing at all arrives for this long
+    pub idle_timeout: Duration,
+}
+
+pub const DEFAULT_HEARTBEAT: HeartbeatConfig = HeartbeatConfig {
+    interval: Duration::from_secs(1),
+    idle_timeout: Duration::from_secs(10),
+};
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RttStats {
+    pub latest: Duration,
+    // Weighted the way TCP smooths its own round trip estimate
+    pub smoothed: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub samples: u64,
+}
+
+impl RttStats {
+    pub fn update(&mut self, rtt: Duration) {
+        if self.samples == 0 {
+            self.smoothed = rtt;
+            self.min = rtt;
+        } else {
+            self.smoothed = (self.smoothed * 7 + rtt) / 8;
+            self.min = self.min.min(rtt);
+        }
+        self.latest = rtt;
+        self.max = self.max.max(rtt);
+        self.samples += 1;
+    }
+}
+
+// Handle on the tasks driving the connection
+#[derive(Clone)]
+pub struct Mux {
+    // What the clock in pings counts from
+    epoch: Instant,
+    rtt: Arc<Mutex<RttStats>>,
+    urgent: mpsc::Sender<Vec<u8>>,
+    // Set once the peer left, there's nobody to say goodbye to anymore
+    closed: Arc<AtomicBool>,
+}
+
+impl Mux {
+    #[inline]
+    pub fn rtt(&self) -> RttStats {
+        *self.rtt.lock().unwrap()
+    }
+
+    // Queues a message for the peer ahead of any audio, fails when there are
+    // more messages waiting than the backlog holds
+    pub fn send(&self, message: &Control) -> io::Result<()> {
+        self.urgent
+            .try_send(frame(KIND_CONTROL, &message.to_bytes()))
+            .map_err(|err| match err {
+                TrySendError::Full(_) => io::ErrorKind::WouldBlock.into(),
+                TrySendError::Closed(_) => connection_closed(),
+            })
+    }
+}
+
+// What the writer sends, the urgent frames first
+struct Backlogs {
+    urgent: mpsc::Receiver<Vec<u8>>,
+    audio: mpsc::Receiver<Vec<u8>>,
+}
+
+// Where the reader hands off whatever arrives
+struct Demux {
+    audio: QueueProducer,
+    controls: mpsc::Sender<Control>,
+    mux: Mux,
+}
+
+// Splits the connection into halves the streams read and write audio with,
+// while the connection itself is driven by tasks of its own. Those answer
+// pings, measure the round trip, hand control messages over to the receiver
+// returned along with the halves and cancel the token when the peer goes
+// quiet or the connection fails. When the session is stopped on this end,
+// the peer is told so with a goodbye. The shutdown token tells whether it's
+// because the whole process is shutting down.
+pub fn start<R, W>(
+    token: CancellationToken,
+    shutdown: CancellationToken,
+    config: HeartbeatConfig,
+    logger: Logger,
+    input: R,
+    output: W,
+) -> (Mux, mpsc::Receiver<Control>, MuxReadHalf, MuxWriteHalf)
+where
+    R: PeerPacketRead + Send + 'static,
+    W: PeerPacketWrite + Send + 'static,
+{
+    let (read, audio) = peer::queue(FRAME_BACKLOG);
+    let (frames, backlog) = mpsc::channel(FRAME_BACKLOG);
+    let (urgent, urgent_backlog) = mpsc::channel(URGENT_BACKLOG);
+    let (controls, messages) = mpsc::channel(CONTROL_BACKLOG);
+    let mux = Mux {
+        epoch: Instant::now(),
+        rtt: Arc::default(),
+        urgent,
+        closed: Arc::default(),
+    };
+    let demux = Demux {
+        audio,
+        controls,
+        mux: mux.clone(),
+    };
+    tokio::spawn(read_frames(
+        token.clone(),
+        config,
+        logger.clone(),
+        input,
+        demux,
+    ));
+    tokio::spawn(write_frames(
+        token,
+        shutdown,
+        config,
+        logger,
+        output,
+        Backlogs {
+            urgent: urgent_backlog,
+            audio: backlog,
+        },
+        mux.clone(),
+    ));
+    (mux, messages, read, MuxWriteHalf { frames })
+}
+
+pub type MuxReadHalf = QueueReadHalf;
+
+pub struct MuxWriteHalf {
+    frames: mpsc::Sender<Vec<u8>>,
+}
+
+impl MuxWriteHalf {
+    async fn send(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        self.frames
+            .send(frame(kind, payload))
+            .await
+            .map_err(|_| connection_closed())
+    }
+}
+
+impl PeerWriteHalf for MuxWriteHalf {
+    async fn write_all<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        let mut chunks = src.chunks(MAX_PAYLOAD).peekable();
+        while let Some(chunk) = chunks.next() {
+            let kind = if chunks.peek().is_some() {
+                KIND_AUDIO_PART
+            } else {
+                KIND_AUDIO
+            };
+            self.send(kind, chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+impl PeerPacketWrite for MuxWriteHalf {
+    async fn write_packet<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        self.send(KIND_AUDIO, src).await
+    }
+}
+
+#[inline]
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed")
+}
+
+#[inline]
+fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+async fn read_frames<R: PeerPacketRead>(
+    token: CancellationToken,
+    config: HeartbeatConfig,
+    logger: Logger,
+    mut input: R,
+    demux: Demux,
+) {
+    let mux = &demux.mux;
+    let mut buf = vec![0u8; MAX_PACKET];
+    // Audio arriving while playback is behind is dropped, waiting for room
+    // would leave pings and control messages unread
+    let mut dropped = 0u64;
+    // Parts of a raw buffer received so far
+    let mut parts = Vec::new();
+    let peer_left = loop {
+        let result = tokio::select! {
+            result = timeout(config.idle_timeout, input.read_packet(&mut buf)) => result,
+            _ = token.cancelled() => break false,
+        };
+        let length = match result {
+            Ok(Ok(length)) => length,
+            Ok(Err(err)) => {
+                warn!(logger, "Connection error: {}", err);
+                break true;
+            }
+            Err(_) => {
+                warn!(
+                    logger,
+                    "Nothing heard from the peer for {:?}, closing the session",
+                    config.idle_timeout
+                );
+                break true;
+            }
+        };
+        let Some((&kind, payload)) = buf[..length].split_first() else {
+            continue;
+        };
+        match kind {
+            KIND_AUDIO_PART => {
+                if parts.len() + payload.len() > MAX_AUDIO_BUFFER {
+                    warn!(logger, "Audio buffer too large, closing the session");
+                    break true;
+                }
+                parts.extend_from_slice(payload);
+            }
+            KIND_AUDIO => {
+                let audio = if parts.is_empty() {
+                    payload
+                } else {
+                    parts.extend_from_slice(payload);
+                    &parts[..]
+                };
+                // Also fails once nobody plays the audio anymore, which is fine
+                if let Err(TrySendError::Full(_)) = demux.audio.try_send(audio) {
+                    dropped += 1;
+                }
+                parts.clear();
+            }
+            KIND_PING => {
+                // Dropping a pong when the queue is full is fine, the next
+                // ping gets answered
+                mux.urgent.try_send(frame(KIND_PONG, payload)).ok();
+            }
+            KIND_PONG => {
+                let Some(sample) = round_trip(mux.epoch, payload) else {
+                    continue;
+                };
+                let mut rtt = mux.rtt.lock().unwrap();
+                rtt.update(sample);
+                if rtt.samples == 1 {
+                    info!(logger, "Round trip time: {:?}", sample);
+                }
+            }
+            KIND_CONTROL => match Control::from_bytes(payload) {
+                Ok(Control::Goodbye(reason)) => {
+                    info!(logger, "Peer closed the session: {}", reason);
+                    break true;
+                }
+                Ok(message) => {
+                    if let Err(TrySendError::Full(message)) = demux.controls.try_send(message) {
+                        warn!(logger, "Control messages backed up, dropped: {}", message);
+                    }
+                }
+                Err(err) => warn!(logger, "Invalid control message: {}", err),
+            },
+            _ => {}
+        }
+    };
+    if peer_left {
+        mux.closed.store(true, Ordering::Release);
+    }
+    token.cancel();
+
+    if dropped > 0 {
+        warn!(
+            logger,
+            "Audio frames dropped, playback fell behind: {}", dropped
+        );
+    }
+    let rtt = mux.rtt();
+    if rtt.samples > 0 {
+        info!(
+            logger,
+            "Round trip time: {:?}, smoothed: {:?}, min: {:?}, max: {:?}",
+            rtt.latest,
+            rtt.smoothed,
+            rtt.min,
+            rtt.max
+        );
+    }
+}
+
+async fn write_frames<W: PeerPacketWrite>(
+    token: CancellationToken,
+    shutdown: CancellationToken,
+    config: HeartbeatConfig,
+    logger: Logger,
+    mut output: W,
+    mut backlogs: Backlogs,
+    mux: Mux,
+) {
+    let mut ticker = interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        // A backlog with nothing left to send it just stops being polled,
+        // sessions that only play audio have no writer for it at all
+        let packet = tokio::select! {
+            biased;
+            _ = token.cancelled() => {
+                if !mux.closed.load(Ordering::Acquire) {
+                    let reason = if shutdown.is_cancelled() {
+                        "shutting down"
+                    } else {
+                        "session closed"
+                    };
+                    let goodbye = Control::Goodbye(reason.to_owned());
+                    let packet = frame(KIND_CONTROL, &goodbye.to_bytes());
+                    timeout(GOODBYE_TIMEOUT, output.write_packet(&packet))
+                        .await
+                        .ok();
+                }
+                break;
+            }
+            Some(packet) = backlogs.urgent.recv() => packet,
+            _ = ticker.tick() => {
+                let clock = mux.epoch.elapsed().as_micros() as u64;
+                frame(KIND_PING, &clock.to_be_bytes())
+            }
+            Some(packet) = backlogs.audio.recv() => packet,
+        };
+        if let Err(err) = output.write_packet(&packet).await {
+            warn!(logger, "Connection error: {}", err);
+            break;
+        }
+    }
+    token.cancel();
+}
+
+fn round_trip(epoch: Instant, payload: &[u8]) -> Option<Duration> {
+    let clock = u64::from_be_bytes(payload.try_into().ok()?);
+    epoch.elapsed().checked_sub(Duration::from_micros(clock))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use slog::{o, Discard};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::peer::PeerReadHalf;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    fn start_tcp(
+        token: &CancellationToken,
+        config: HeartbeatConfig,
+        socket: TcpStream,
+    ) -> (Mux, mpsc::Receiver<Control>, MuxReadHalf, MuxWriteHalf) {
+        let (input, output) = socket.into_split();
+        let logger = Logger::root(Discard, o!());
+        let shutdown = CancellationToken::new();
+        start(token.clone(), shutdown, config, logger, input, output)
+    }
+
+    #[tokio::test]
+    async fn audio_between_heartbeats() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(5),
+            ..DEFAULT_HEARTBEAT
+        };
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (_, _, _, mut output) = start_tcp(&token, config, client);
+        let (_, _, mut input, _) = start_tcp(&token, config, server);
+
+        let raw: Vec<u8> = (0..MAX_PAYLOAD * 2 + 10).map(|i| i as u8).collect();
+        output.write_all(&raw).await.unwrap();
+        tokio::time::sleep(config.interval * 4).await;
+        output.write_packet(&[1, 2, 3]).await.unwrap();
+
+        let mut read = vec![0u8; raw.len()];
+        timeout(TIMEOUT, input.read_exact(&mut read))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, raw);
+        let mut buf = [0u8; 8];
+        let length = timeout(TIMEOUT, input.read_packet(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..length], &[1, 2, 3]);
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn measures_round_trip() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(5),
+            ..DEFAULT_HEARTBEAT
+        };
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (mux, _messages, _input, _output) = start_tcp(&token, config, client);
+        let _server = start_tcp(&token, config, server);
+
+        timeout(TIMEOUT, async {
+            while mux.rtt().samples < 3 {
+                tokio::time::sleep(config.interval).await;
+            }
+        })
+        .await
+        .unwrap();
+        let rtt = mux.rtt();
+        assert!(rtt.min <= rtt.smoothed && rtt.smoothed <= rtt.max);
+        assert!(rtt.max < TIMEOUT);
+        assert!(round_trip(Instant::now(), &[0; 3]).is_none());
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn answers_pings() {
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (mut input, mut output) = client.into_split();
+        let _server = start_tcp(&token, DEFAULT_HEARTBEAT, server);
+
+        output.write_packet(&[KIND_PING, 7, 7, 7]).await.unwrap();
+        let mut buf = [0u8; 64];
+        loop {
+            let length = timeout(TIMEOUT, input.read_packet(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            if buf[0] == KIND_PONG {
+                assert_eq!(&buf[..length], &[KIND_PONG, 7, 7, 7]);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn control_jumps_audio_backlog() {
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (mux, _, _, mut output) = start_tcp(&token, DEFAULT_HEARTBEAT, client);
+        let (mut input, _output) = server.into_split();
+
+        // Far more than the socket buffers hold while nobody reads
+        let queued = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queued);
+        let writer = tokio::spawn(async move {
+            for _ in 0..400 {
+                output.write_packet(&[0; MAX_PAYLOAD]).await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        mux.send(&Control::Mute(true)).unwrap();
+        let queued = queued.load(Ordering::Relaxed);
+
+        let mut buf = vec![0u8; MAX_PACKET];
+        let mut audio = 0;
+        loop {
+            let length = timeout(TIMEOUT, input.read_packet(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            match buf[0] {
+                KIND_AUDIO => audio += 1,
+                KIND_CONTROL => {
+                    let message = Control::from_bytes(&buf[1..length]).unwrap();
+                    assert_eq!(message, Control::Mute(true));
+                    break;
+                }
+                _ => {}
+            }
+        }
+        // Behind the audio it would have come after all of it
+        assert!(audio < queued, "{} of {} audio frames first", audio, queued);
+        token.cancel();
+        writer.abort();
+    }
+
+    #[tokio::test]
+    async fn answers_pings_behind_unplayed_audio() {
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (mut input, mut output) = client.into_split();
+        // Nobody reads the audio on this end
+        let _server = start_tcp(&token, DEFAULT_HEARTBEAT, server);
+
+        for _ in 0..FRAME_BACKLOG * 3 {
+            output.write_packet(&[KIND_AUDIO, 1, 2, 3]).await.unwrap();
+        }
+        output.write_packet(&[KIND_PING, 7, 7, 7]).await.unwrap();
+        let mut buf = [0u8; 64];
+        loop {
+            let length = timeout(TIMEOUT, input.read_packet(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            if buf[0] == KIND_PONG {
+                assert_eq!(&buf[..length], &[KIND_PONG, 7, 7, 7]);
+                break;
+            }
+        }
+        token.cancel();
+    }
+
+    // Buffers sent in parts are dropped whole, the audio that does get played
+    // is made up of complete buffers only
+    #[tokio::test]
+    async fn drops_buffers_sent_in_parts_whole() {
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (mut socket_input, mut socket_output) = client.into_split();
+        let (_, _, mut input, _) = start_tcp(&token, DEFAULT_HEARTBEAT, server);
+
+        let count = FRAME_BACKLOG * 3;
+        for i in 0..count {
+            socket_output
+                .write_packet(&[KIND_AUDIO_PART, i as u8, i as u8])
+                .await
+                .unwrap();
+            socket_output
+                .write_packet(&[KIND_AUDIO, i as u8])
+                .await
+                .unwrap();
+        }
+        // Everything before the ping has been read once it's answered
+        socket_output.write_packet(&[KIND_PING, 7]).await.unwrap();
+        let mut buf = [0u8; 64];
+        loop {
+            timeout(TIMEOUT, socket_input.read_packet(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            if buf[0] == KIND_PONG {
+                break;
+            }
+        }
+
+        let mut buffers = Vec::new();
+        let mut buffer = [0u8; 3];
+        while let Ok(result) =
+            timeout(Duration::from_millis(50), input.read_exact(&mut buffer)).await
+        {
+            result.unwrap();
+            assert!(buffer.iter().all(|&b| b == buffer[0]), "{:?}", buffer);
+            buffers.push(buffer[0]);
+        }
+        assert!(!buffers.is_empty() && buffers.len() < count);
+        assert!(buffers.windows(2).all(|pair| pair[0] < pair[1]));
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn idle_peer_cancels_session() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            idle_timeout: Duration::from_millis(50),
+        };
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (_, _, mut input, _) = start_tcp(&token, config, server);
+
+        timeout(TIMEOUT, token.cancelled()).await.unwrap();
+        let mut buf = [0u8; 8];
+        let err = input.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn control_between_audio() {
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let (mux, _, _, mut output) = start_tcp(&token, DEFAULT_HEARTBEAT, client);
+        let (_, mut messages, mut input, _) = start_tcp(&token, DEFAULT_HEARTBEAT, server);
+
+        output.write_packet(&[1, 2, 3]).await.unwrap();
+        mux.send(&Control::Volume(0.5)).unwrap();
+        mux.send(&Control::Mute(true)).unwrap();
+
+        let mut buf = [0u8; 8];
+        let length = timeout(TIMEOUT, input.read_packet(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..length], &[1, 2, 3]);
+        for expected in [Control::Volume(0.5), Control::Mute(true)] {
+            let message = timeout(TIMEOUT, messages.recv()).await.unwrap();
+            assert_eq!(message, Some(expected));
+        }
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn says_goodbye_on_close() {
+        for (shutting_down, reason) in [(false, "session closed"), (true, "shutting down")] {
+            let (client, server) = tcp_pair().await;
+            let (input, output) = client.into_split();
+            let shutdown = CancellationToken::new();
+            let token = shutdown.child_token();
+            let logger = Logger::root(Discard, o!());
+            let _client = start(
+                token.clone(),
+                shutdown.clone(),
+                DEFAULT_HEARTBEAT,
+                logger,
+                input,
+                output,
+            );
+            let (mut input, _output) = server.into_split();
+
+            if shutting_down {
+                shutdown.cancel();
+            } else {
+                token.cancel();
+            }
+            let mut buf = [0u8; 64];
+            loop {
+                let length = timeout(TIMEOUT, input.read_packet(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                if buf[0] == KIND_CONTROL {
+                    let message = Control::from_bytes(&buf[1..length]).unwrap();
+                    assert_eq!(message, Control::Goodbye(reason.to_owned()));
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn goodbye_ends_peer_session() {
+        let (client, server) = tcp_pair().await;
+        let token = CancellationToken::new();
+        let peer = CancellationToken::new();
+        let _client = start_tcp(&token, DEFAULT_HEARTBEAT, client);
+        let _server = start_tcp(&peer, DEFAULT_HEARTBEAT, server);
+
+        token.cancel();
+        timeout(TIMEOUT, peer.cancelled()).await.unwrap();
+    }
+
+    #[test]
+    fn smooths_round_trips() {
+        let mut rtt = RttStats::default();
+        rtt.update(Duration::from_millis(80));
+        assert_eq!(rtt.smoothed, Duration::from_millis(80));
+        rtt.update(Duration::from_millis(160));
+        assert_eq!(rtt.smoothed, Duration::from_millis(90));
+        assert_eq!(rtt.latest, Duration::from_millis(160));
+        assert_eq!(rtt.min, Duration::from_millis(80));
+        assert_eq!(rtt.max, Duration::from_millis(160));
+        assert_eq!(rtt.samples, 2);
+    }
+}
@@ -1,15 +1,742 @@
-use opus::Channels;
+use std::{
+    ffi::{c_int, CStr},
+    fmt::Display,
+    ptr,
+    time::Duration,
+};
+
+use audiopus_sys as ffi;
+use opus::{Application, Bandwidth, Bitrate, Channels, FrameSize};
+
+#[derive(Debug)]
+pub enum OpusError {
+    UnsupportedChannels(u8),
+    Codec { function: &'static str, code: i32 },
+}
+
+impl Display for OpusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedChannels(channels) => write!(
+                f,
+                "Opus supports 1 to {} channels, got {}",
+                MAX_CHANNELS, channels
+            ),
+            Self::Codec { function, code } => {
+                let description = unsafe { CStr::from_ptr(ffi::opus_strerror(*code)) };
+                write!(f, "{}: {}", function, description.to_string_lossy())
+            }
+        }
+    }
+}
+
+impl std::error::Error for OpusError {}
+
+pub type OpusResult<T> = std::result::Result<T, OpusError>;
+
+#[inline]
+fn check(function: &'static str, result: c_int) -> OpusResult<usize> {
+    if result < 0 {
+        Err(OpusError::Codec {
+            function,
+            code: result,
+        })
+    } else {
+        Ok(result as usize)
+    }
+}
 
 pub trait ChannelsParser {
-    fn from_u8(value: u8) -> Channels;
+    fn from_u8(value: u8) -> OpusResult<Channels>;
 }
 
 impl ChannelsParser for Channels {
-    fn from_u8(value: u8) -> Channels {
+    fn from_u8(value: u8) -> OpusResult<Channels> {
         match value {
-            1 => Channels::Mono,
-            2 => Channels::Stereo,
-            other => panic!("Unsupported number of channels: {}", other),
+            1 => Ok(Channels::Mono),
+            2 => Ok(Channels::Stereo),
+            other => Err(OpusError::UnsupportedChannels(other)),
+        }
+    }
+}
+
+pub const MAX_CHANNELS: u8 = 8;
+
+// Streams, coupled streams and the stream channel each channel is coded in,
+// for every channel count of mapping family 1. The surround encoder picks the
+// same ones, they're only spelled out for the decoder which has to be told.
+const VORBIS_MAPPINGS: [(c_int, c_int, &[u8]); MAX_CHANNELS as usize] = [
+    (1, 0, &[0]),
+    (1, 1, &[0, 1]),
+    (2, 1, &[0, 2, 1]),
+    (2, 2, &[0, 1, 2, 3]),
+    (3, 2, &[0, 4, 1, 2, 3]),
+    (4, 2, &[0, 4, 1, 2, 3, 5]),
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+];
+
+// Opus orders surround channels the way Vorbis does, eg. FL FC FR RL RR LFE,
+// while devices interleave them the way WAV does, eg. FL FR FC LFE RL RR. This
+// is the device channel for each channel in Opus order.
+const VORBIS_ORDER: [&[usize]; MAX_CHANNELS as usize] = [
+    &[0],
+    &[0, 1],
+    &[0, 2, 1],
+    &[0, 1, 2, 3],
+    &[0, 2, 1, 3, 4],
+    &[0, 2, 1, 4, 5, 3],
+    &[0, 2, 1, 5, 6, 4, 3],
+    &[0, 2, 1, 6, 7, 4, 5, 3],
+];
+
+// Largest packet a single stream codes 120 ms into, six frames of 1275 bytes
+const MAX_STREAM_PACKET: usize = 1275 * 6;
+
+// Room for any packet coded for that many channels. Every stream but the last
+// is prefixed with its length, which takes up to 2 bytes.
+pub fn max_packet_size(channels: u8) -> OpusResult<usize> {
+    let (streams, _, _) = VORBIS_MAPPINGS[vorbis_index(channels)?];
+    Ok(streams as usize * (MAX_STREAM_PACKET + 2))
+}
+
+#[inline]
+fn vorbis_index(channels: u8) -> OpusResult<usize> {
+    match channels {
+        1..=MAX_CHANNELS => Ok(channels as usize - 1),
+        other => Err(OpusError::UnsupportedChannels(other)),
+    }
+}
+
+// Opus encoder for any channel count Opus supports. Mono and stereo are coded
+// as a single stream, which makes their packets plain Opus packets, anything
+// up to 7.1 as a surround multistream. Input is interleaved in device order.
+pub struct Encoder {
+    ptr: *mut ffi::OpusMSEncoder,
+    order: &'static [usize],
+    reordered: Vec<i16>,
+}
+
+// The encoder state is only ever touched through &mut self
+unsafe impl Send for Encoder {}
+
+impl Encoder {
+    pub fn new(sample_rate: u32, channels: u8, application: Application) -> OpusResult<Self> {
+        let index = vorbis_index(channels)?;
+        let family = if channels > 2 { 1 } else { 0 };
+        let (mut streams, mut coupled) = (0, 0);
+        let mut mapping = [0u8; MAX_CHANNELS as usize];
+        let mut error = 0;
+        let ptr = unsafe {
+            ffi::opus_multistream_surround_encoder_create(
+                sample_rate as i32,
+                channels as c_int,
+                family,
+                &mut streams,
+                &mut coupled,
+                mapping.as_mut_ptr(),
+                application as c_int,
+                &mut error,
+            )
+        };
+        if ptr.is_null() {
+            check("opus_multistream_surround_encoder_create", error.min(-1))?;
+        }
+        Ok(Self {
+            ptr,
+            order: VORBIS_ORDER[index],
+            reordered: Vec::new(),
+        })
+    }
+
+    fn ctl(&mut self, function: &'static str, request: c_int, value: c_int) -> OpusResult<()> {
+        let result = unsafe { ffi::opus_multistream_encoder_ctl(self.ptr, request, value) };
+        check(function, result).map(|_| ())
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: Bitrate) -> OpusResult<()> {
+        let value = match bitrate {
+            Bitrate::Auto => ffi::OPUS_AUTO,
+            Bitrate::Max => ffi::OPUS_BITRATE_MAX,
+            Bitrate::Bits(bits) => bits,
+        };
+        self.ctl("set_bitrate", ffi::OPUS_SET_BITRATE_REQUEST, value)
+    }
+
+    pub fn set_vbr(&mut self, vbr: bool) -> OpusResult<()> {
+        self.ctl("set_vbr", ffi::OPUS_SET_VBR_REQUEST, vbr as c_int)
+    }
+
+    pub fn set_complexity(&mut self, complexity: i32) -> OpusResult<()> {
+        self.ctl(
+            "set_complexity",
+            ffi::OPUS_SET_COMPLEXITY_REQUEST,
+            complexity,
+        )
+    }
+
+    pub fn set_max_bandwidth(&mut self, bandwidth: Bandwidth) -> OpusResult<()> {
+        let request = ffi::OPUS_SET_MAX_BANDWIDTH_REQUEST;
+        self.ctl("set_max_bandwidth", request, bandwidth as c_int)
+    }
+
+    pub fn set_dtx(&mut self, dtx: bool) -> OpusResult<()> {
+        self.ctl("set_dtx", ffi::OPUS_SET_DTX_REQUEST, dtx as c_int)
+    }
+
+    pub fn set_inband_fec(&mut self, fec: bool) -> OpusResult<()> {
+        self.ctl(
+            "set_inband_fec",
+            ffi::OPUS_SET_INBAND_FEC_REQUEST,
+            fec as c_int,
+        )
+    }
+
+    pub fn set_packet_loss_perc(&mut self, percentage: i32) -> OpusResult<()> {
+        let request = ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST;
+        self.ctl("set_packet_loss_perc", request, percentage)
+    }
+
+    // Encodes one frame, returns the size of the packet
+    pub fn encode(&mut self, input: &[i16], output: &mut [u8]) -> OpusResult<usize> {
+        let channels = self.order.len();
+        self.reordered.clear();
+        for frame in input.chunks_exact(channels) {
+            self.reordered.extend(self.order.iter().map(|&i| frame[i]));
+        }
+        let result = unsafe {
+            ffi::opus_multistream_encode(
+                self.ptr,
+                self.reordered.as_ptr(),
+                (self.reordered.len() / channels) as c_int,
+                output.as_mut_ptr(),
+                output.len() as i32,
+            )
+        };
+        check("opus_multistream_encode", result)
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_multistream_encoder_destroy(self.ptr) }
+    }
+}
+
+// Decodes what Encoder produces for the same channel count, output is
+// interleaved in device order.
+pub struct Decoder {
+    ptr: *mut ffi::OpusMSDecoder,
+    order: &'static [usize],
+    decoded: Vec<i16>,
+}
+
+unsafe impl Send for Decoder {}
+
+impl Decoder {
+    pub fn new(sample_rate: u32, channels: u8) -> OpusResult<Self> {
+        let index = vorbis_index(channels)?;
+        let (streams, coupled, mapping) = VORBIS_MAPPINGS[index];
+        let mut error = 0;
+        let ptr = unsafe {
+            ffi::opus_multistream_decoder_create(
+                sample_rate as i32,
+                channels as c_int,
+                streams,
+                coupled,
+                mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        if ptr.is_null() {
+            check("opus_multistream_decoder_create", error.min(-1))?;
+        }
+        Ok(Self {
+            ptr,
+            order: VORBIS_ORDER[index],
+            decoded: Vec::new(),
+        })
+    }
+
+    // Decodes a packet into as many frames as fit into the output, returns
+    // the number of frames. An empty packet stands for a lost one.
+    pub fn decode(&mut self, input: &[u8], output: &mut [i16], fec: bool) -> OpusResult<usize> {
+        let channels = self.order.len();
+        self.decoded.resize(output.len() / channels * channels, 0);
+        let data = if input.is_empty() {
+            ptr::null()
+        } else {
+            input.as_ptr()
+        };
+        let result = unsafe {
+            ffi::opus_multistream_decode(
+                self.ptr,
+                data,
+                input.len() as i32,
+                self.decoded.as_mut_ptr(),
+                (self.decoded.len() / channels) as c_int,
+                fec as c_int,
+            )
+        };
+        let frames = check("opus_multistream_decode", result)?;
+        for (src, dst) in self
+            .decoded
+            .chunks_exact(channels)
+            .zip(output.chunks_exact_mut(channels))
+            .take(frames)
+        {
+            for (sample, &i) in src.iter().zip(self.order) {
+                dst[i] = *sample;
+            }
+        }
+        Ok(frames)
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_multistream_decoder_destroy(self.ptr) }
+    }
+}
+
+// Encoder parameters of a sending endpoint. Every endpoint encodes with its
+// own settings and sends them along in the handshake, so the receiving end
+// knows the frame duration of the packets headed its way.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OpusSettings {
+    pub bitrate: Bitrate,
+    pub vbr: bool,
+    pub complexity: u8,
+    pub application: Application,
+    pub max_bandwidth: Bandwidth,
+    pub dtx: bool,
+    pub frame_duration: FrameSize,
+}
+
+pub const DEFAULT_OPUS_SETTINGS: OpusSettings = OpusSettings {
+    bitrate: Bitrate::Auto,
+    vbr: true,
+    complexity: 10,
+    application: Application::Audio,
+    max_bandwidth: Bandwidth::Fullband,
+    dtx: false,
+    frame_duration: FrameSize::Ms20,
+};
+
+const BANDWIDTHS: [(Bandwidth, &str); 6] = [
+    (Bandwidth::Auto, "auto"),
+    (Bandwidth::Narrowband, "narrowband"),
+    (Bandwidth::Mediumband, "mediumband"),
+    (Bandwidth::Wideband, "wideband"),
+    (Bandwidth::Superwideband, "superwideband"),
+    (Bandwidth::Fullband, "fullband"),
+];
+
+// Valid frame durations along with their length in microseconds
+const FRAME_SIZES: [(FrameSize, &str, u64); 9] = [
+    (FrameSize::Ms2_5, "2.5", 2500),
+    (FrameSize::Ms5, "5", 5000),
+    (FrameSize::Ms10, "10", 10000),
+    (FrameSize::Ms20, "20", 20000),
+    (FrameSize::Ms40, "40", 40000),
+    (FrameSize::Ms60, "60", 60000),
+    (FrameSize::Ms80, "80", 80000),
+    (FrameSize::Ms100, "100", 100000),
+    (FrameSize::Ms120, "120", 120000),
+];
+
+const APPLICATIONS: [(Application, &str); 3] = [
+    (Application::Voip, "voip"),
+    (Application::Audio, "audio"),
+    (Application::LowDelay, "lowdelay"),
+];
+
+impl OpusSettings {
+    // Size of the settings on the wire:
+    //   bitrate:i32 vbr:u8 complexity:u8 application:u8 max_bandwidth:u8
+    //   dtx:u8 frame_duration:u8
+    // where a bitrate of 0 stands for auto and -1 for max.
+    pub const SIZE: usize = 10;
+
+    pub fn encoder(&self, sample_rate: u32, channels: u8) -> OpusResult<Encoder> {
+        let mut encoder = Encoder::new(sample_rate, channels, self.application)?;
+        encoder.set_bitrate(self.bitrate)?;
+        encoder.set_vbr(self.vbr)?;
+        encoder.set_complexity(self.complexity as i32)?;
+        encoder.set_max_bandwidth(self.max_bandwidth)?;
+        encoder.set_dtx(self.dtx)?;
+        Ok(encoder)
+    }
+
+    #[inline]
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_micros(frame_size_micros(self.frame_duration))
+    }
+
+    #[inline]
+    pub fn frame_count(&self, sample_rate: u32) -> usize {
+        (frame_size_micros(self.frame_duration) * sample_rate as u64 / 1_000_000) as usize
+    }
+
+    // Applies a single setting by name, as given on the command line
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid value for {}: {}", name, value);
+        match name {
+            "bitrate" => self.bitrate = parse_bitrate(value).ok_or_else(invalid)?,
+            "bitrate-mode" => {
+                self.vbr = match value {
+                    "vbr" => true,
+                    "cbr" => false,
+                    _ => return Err(invalid()),
+                }
+            }
+            "complexity" => {
+                self.complexity = match value.parse() {
+                    Ok(complexity) if complexity <= 10 => complexity,
+                    _ => return Err(invalid()),
+                }
+            }
+            "application" => {
+                self.application = find_by_name(&APPLICATIONS, value).ok_or_else(invalid)?
+            }
+            "max-bandwidth" => {
+                self.max_bandwidth = find_by_name(&BANDWIDTHS, value).ok_or_else(invalid)?
+            }
+            "dtx" => {
+                self.dtx = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(invalid()),
+                }
+            }
+            "frame-duration" => {
+                self.frame_duration = FRAME_SIZES
+                    .iter()
+                    .find(|(_, name, _)| *name == value)
+                    .map(|&(size, _, _)| size)
+                    .ok_or_else(invalid)?
+            }
+            _ => return Err(format!("Unknown Opus setting: {}", name)),
         }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+        buf[..4].copy_from_slice(&bitrate_to_i32(self.bitrate).to_be_bytes());
+        buf[4] = self.vbr as u8;
+        buf[5] = self.complexity;
+        buf[6] = index_of(&APPLICATIONS, self.application);
+        buf[7] = index_of(&BANDWIDTHS, self.max_bandwidth);
+        buf[8] = self.dtx as u8;
+        buf[9] = FRAME_SIZES
+            .iter()
+            .position(|&(size, _, _)| size == self.frame_duration)
+            .unwrap_or_default() as u8;
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; Self::SIZE]) -> Result<Self, String> {
+        let invalid = |name: &str, value: u8| format!("invalid Opus {} {}", name, value);
+        let bitrate = bitrate_from_i32(i32::from_be_bytes(buf[..4].try_into().unwrap()))?;
+        Ok(Self {
+            bitrate,
+            vbr: buf[4] != 0,
+            complexity: buf[5].min(10),
+            application: APPLICATIONS
+                .get(buf[6] as usize)
+                .map(|&(application, _)| application)
+                .ok_or_else(|| invalid("application", buf[6]))?,
+            max_bandwidth: BANDWIDTHS
+                .get(buf[7] as usize)
+                .map(|&(bandwidth, _)| bandwidth)
+                .ok_or_else(|| invalid("bandwidth", buf[7]))?,
+            dtx: buf[8] != 0,
+            frame_duration: FRAME_SIZES
+                .get(buf[9] as usize)
+                .map(|&(size, _, _)| size)
+                .ok_or_else(|| invalid("frame duration", buf[9]))?,
+        })
+    }
+}
+
+// Bitrates are given as auto, max or bits per second
+pub fn parse_bitrate(value: &str) -> Option<Bitrate> {
+    match value {
+        "auto" => Some(Bitrate::Auto),
+        "max" => Some(Bitrate::Max),
+        bits => bits
+            .parse()
+            .ok()
+            .filter(|&bits| bits > 0)
+            .map(Bitrate::Bits),
+    }
+}
+
+pub fn format_bitrate(bitrate: Bitrate) -> String {
+    match bitrate {
+        Bitrate::Auto => "auto bitrate".to_owned(),
+        Bitrate::Max => "max bitrate".to_owned(),
+        Bitrate::Bits(bits) => format!("{} bps", bits),
+    }
+}
+
+// On the wire a bitrate of 0 stands for auto and -1 for max
+pub fn bitrate_to_i32(bitrate: Bitrate) -> i32 {
+    match bitrate {
+        Bitrate::Auto => 0,
+        Bitrate::Max => -1,
+        Bitrate::Bits(bits) => bits,
+    }
+}
+
+pub fn bitrate_from_i32(value: i32) -> Result<Bitrate, String> {
+    match value {
+        0 => Ok(Bitrate::Auto),
+        -1 => Ok(Bitrate::Max),
+        bits if bits > 0 => Ok(Bitrate::Bits(bits)),
+        bits => Err(format!("invalid Opus bitrate {}", bits)),
+    }
+}
+
+impl Display for OpusSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_bitrate(self.bitrate))?;
+        write!(
+            f,
+            " {}, complexity {}, {}, max bandwidth {}, dtx {}, {:?} frames",
+            if self.vbr { "vbr" } else { "cbr" },
+            self.complexity,
+            name_of(&APPLICATIONS, self.application),
+            name_of(&BANDWIDTHS, self.max_bandwidth),
+            if self.dtx { "on" } else { "off" },
+            self.frame_duration()
+        )
+    }
+}
+
+#[inline]
+fn frame_size_micros(size: FrameSize) -> u64 {
+    FRAME_SIZES
+        .iter()
+        .find(|&&(other, _, _)| other == size)
+        .map(|&(_, _, micros)| micros)
+        .unwrap_or(20000)
+}
+
+#[inline]
+fn find_by_name<T: Copy>(values: &[(T, &str)], name: &str) -> Option<T> {
+    values
+        .iter()
+        .find(|&&(_, other)| other == name)
+        .map(|&(value, _)| value)
+}
+
+#[inline]
+fn name_of<T: Copy + PartialEq>(values: &[(T, &'static str)], value: T) -> &'static str {
+    values
+        .iter()
+        .find(|&&(other, _)| other == value)
+        .map(|&(_, name)| name)
+        .unwrap_or_default()
+}
+
+#[inline]
+fn index_of<T: Copy + PartialEq>(values: &[(T, &str)], value: T) -> u8 {
+    values
+        .iter()
+        .position(|&(other, _)| other == value)
+        .unwrap_or_default() as u8
+}
+
+// Fills in a lost frame, output has to be exactly one frame long. The packet
+// after the lost one carries a low bitrate copy of it when the encoder has
+// in-band FEC enabled, otherwise the decoder extrapolates from what it played
+// last.
+pub fn conceal(
+    decoder: &mut Decoder,
+    next: Option<&[u8]>,
+    output: &mut [i16],
+) -> OpusResult<usize> {
+    match next {
+        Some(packet) => decoder.decode(packet, output, true),
+        None => decoder.decode(&[], output, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    // Plain single stream packets, as an older peer would send them
+    use opus::{Application, Encoder};
+
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const FRAMES: usize = 960;
+
+    fn encode_sine(count: usize) -> Vec<Vec<u8>> {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Voip).unwrap();
+        encoder.set_inband_fec(true).unwrap();
+        encoder.set_packet_loss_perc(20).unwrap();
+        let mut phase = 0f32;
+        (0..count)
+            .map(|_| {
+                let frame: Vec<i16> = (0..FRAMES)
+                    .map(|_| {
+                        phase = (phase + 2.0 * PI * 440.0 / SAMPLE_RATE as f32) % (2.0 * PI);
+                        (phase.sin() * 8192.0) as i16
+                    })
+                    .collect();
+                encoder.encode_vec(&frame, 4000).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn conceal_lost_frames() {
+        let packets = encode_sine(20);
+        let mut decoder = Decoder::new(SAMPLE_RATE, 1).unwrap();
+        let mut buf = [0i16; FRAMES];
+        for packet in &packets[..10] {
+            decoder.decode(packet, &mut buf, false).unwrap();
+        }
+
+        // Packet 10 is lost but 11 has arrived, so it gets recovered with FEC
+        assert_eq!(
+            conceal(&mut decoder, Some(&packets[11]), &mut buf).unwrap(),
+            FRAMES
+        );
+        assert!(buf.iter().any(|&s| s != 0));
+        decoder.decode(&packets[11], &mut buf, false).unwrap();
+
+        // Nothing to recover from, the decoder falls back to PLC
+        assert_eq!(conceal(&mut decoder, None, &mut buf).unwrap(), FRAMES);
+        assert!(buf.iter().any(|&s| s != 0));
+    }
+
+    #[test]
+    fn max_packet_sizes() {
+        assert_eq!(max_packet_size(2).unwrap(), 7652);
+        assert_eq!(max_packet_size(8).unwrap(), 5 * 7652);
+        assert!(max_packet_size(0).is_err());
+    }
+
+    #[test]
+    fn vorbis_mappings_match_the_surround_encoder() {
+        for channels in 1..=MAX_CHANNELS {
+            let (mut streams, mut coupled) = (0, 0);
+            let mut mapping = [0u8; MAX_CHANNELS as usize];
+            let mut error = 0;
+            let ptr = unsafe {
+                ffi::opus_multistream_surround_encoder_create(
+                    SAMPLE_RATE as i32,
+                    channels as c_int,
+                    if channels > 2 { 1 } else { 0 },
+                    &mut streams,
+                    &mut coupled,
+                    mapping.as_mut_ptr(),
+                    Application::Audio as c_int,
+                    &mut error,
+                )
+            };
+            assert!(!ptr.is_null());
+            unsafe { ffi::opus_multistream_encoder_destroy(ptr) };
+            let (expected_streams, expected_coupled, expected_mapping) =
+                VORBIS_MAPPINGS[channels as usize - 1];
+            assert_eq!((streams, coupled), (expected_streams, expected_coupled));
+            assert_eq!(&mapping[..channels as usize], expected_mapping);
+        }
+    }
+
+    // Every channel of a 5.1 stream gets its own level, so any channel coming
+    // out in the wrong place shows up in the decoded levels.
+    #[test]
+    fn surround_keeps_channel_order() {
+        const CHANNELS: usize = 6;
+        let levels = [1000.0, 2000.0, 4000.0, 3000.0, 6000.0, 8000.0];
+        let mut encoder = DEFAULT_OPUS_SETTINGS
+            .encoder(SAMPLE_RATE, CHANNELS as u8)
+            .unwrap();
+        let mut decoder = Decoder::new(SAMPLE_RATE, CHANNELS as u8).unwrap();
+        let mut packet = [0u8; 4000];
+        let mut decoded = [0i16; FRAMES * CHANNELS];
+        let mut energy = [0f64; CHANNELS];
+        for index in 0..50 {
+            let frame: Vec<i16> = (0..FRAMES * CHANNELS)
+                .map(|i| {
+                    let t = (index * FRAMES + i / CHANNELS) as f32 / SAMPLE_RATE as f32;
+                    ((2.0 * PI * 60.0 * t).sin() * levels[i % CHANNELS]) as i16
+                })
+                .collect();
+            let size = encoder.encode(&frame, &mut packet).unwrap();
+            assert_eq!(
+                decoder
+                    .decode(&packet[..size], &mut decoded, false)
+                    .unwrap(),
+                FRAMES
+            );
+            // Skip the first frames the codec needs to settle
+            if index >= 10 {
+                for (i, sample) in decoded.iter().enumerate() {
+                    energy[i % CHANNELS] += (*sample as f64).powi(2);
+                }
+            }
+        }
+        let samples = (40 * FRAMES) as f64;
+        for (channel, level) in levels.iter().enumerate() {
+            // RMS of a sine is its peak over the square root of 2
+            let rms = (energy[channel] / samples).sqrt() * 2f64.sqrt();
+            assert!(
+                (rms - *level as f64).abs() < *level as f64 * 0.2,
+                "channel {}: {} instead of {}",
+                channel,
+                rms,
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_channels() {
+        assert!(matches!(
+            Channels::from_u8(3),
+            Err(OpusError::UnsupportedChannels(3))
+        ));
+        assert!(Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).is_ok());
+        assert!(matches!(
+            DEFAULT_OPUS_SETTINGS.encoder(SAMPLE_RATE, 9),
+            Err(OpusError::UnsupportedChannels(9))
+        ));
+        assert!(matches!(
+            Decoder::new(SAMPLE_RATE, 0),
+            Err(OpusError::UnsupportedChannels(0))
+        ));
+    }
+
+    #[test]
+    fn settings_from_flags_and_wire() {
+        let mut settings = DEFAULT_OPUS_SETTINGS;
+        settings.set("bitrate", "32000").unwrap();
+        settings.set("bitrate-mode", "cbr").unwrap();
+        settings.set("application", "voip").unwrap();
+        settings.set("max-bandwidth", "wideband").unwrap();
+        settings.set("dtx", "on").unwrap();
+        settings.set("frame-duration", "2.5").unwrap();
+        assert!(settings.set("complexity", "11").is_err());
+        assert!(settings.set("frame-duration", "30").is_err());
+        assert!(settings.set("bogus", "1").is_err());
+
+        assert_eq!(settings.frame_count(SAMPLE_RATE), 120);
+        assert_eq!(settings.frame_duration(), Duration::from_micros(2500));
+        assert_eq!(OpusSettings::from_bytes(&settings.to_bytes()), Ok(settings));
+
+        let mut buf = settings.to_bytes();
+        buf[9] = 0xFF;
+        assert!(OpusSettings::from_bytes(&buf).is_err());
+
+        let encoder = settings.encoder(SAMPLE_RATE, 1);
+        assert!(encoder.is_ok());
     }
 }
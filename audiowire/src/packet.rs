@@ -0,0 +1,159 @@
+// Every encoded frame travels as a packet of its own, prefixed with a header
+// that lets the receiver put frames back in order and notice the ones that
+// never arrived.
+pub const HEADER_SIZE: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PacketHeader {
+    pub sequence: u16,
+    // Position of the first sample in the packet, counted in frames
+    pub timestamp: u32,
+}
+
+impl PacketHeader {
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[..2].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[2..HEADER_SIZE].copy_from_slice(&self.timestamp.to_be_bytes());
+    }
+
+    // Splits a packet into its header and payload
+    pub fn read(buf: &[u8]) -> Option<(Self, &[u8])> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let header = Self {
+            sequence: u16::from_be_bytes(buf[..2].try_into().unwrap()),
+            timestamp: u32::from_be_bytes(buf[2..HEADER_SIZE].try_into().unwrap()),
+        };
+        Some((header, &buf[HEADER_SIZE..]))
+    }
+
+    #[inline]
+    pub fn next(&self, frames: u32) -> Self {
+        Self {
+            sequence: self.sequence.wrapping_add(1),
+            timestamp: self.timestamp.wrapping_add(frames),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PacketOrder {
+    InOrder,
+    // Number of packets skipped before this one
+    Gap(u16),
+    // Arrived after a packet with a later sequence number
+    Late,
+    // Same sequence number as a packet already received
+    Duplicate,
+}
+
+// How many sequence numbers behind the expected one are remembered, late
+// packets older than that can't be told apart from duplicates.
+const SEEN_WINDOW: u16 = 64;
+
+#[derive(Default, Debug)]
+pub struct SequenceTracker {
+    expected: Option<u16>,
+    // Bit n is set when sequence `expected - 1 - n` has been received
+    seen: u64,
+    pub received: u64,
+    pub lost: u64,
+    pub reordered: u64,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn track(&mut self, sequence: u16) -> PacketOrder {
+        let expected = match self.expected {
+            Some(expected) => expected,
+            None => {
+                self.received += 1;
+                self.expected = Some(sequence.wrapping_add(1));
+                self.seen = 1;
+                return PacketOrder::InOrder;
+            }
+        };
+
+        // Sequence numbers wrap around, so anything within half the range
+        // behind the expected one counts as late rather than far ahead.
+        let diff = sequence.wrapping_sub(expected) as i16;
+        if diff < 0 {
+            let age = diff.unsigned_abs() - 1;
+            if age < SEEN_WINDOW {
+                let bit = 1 << age;
+                if self.seen & bit != 0 {
+                    return PacketOrder::Duplicate;
+                }
+                self.seen |= bit;
+                // It was counted as lost when the gap was first seen
+                self.lost = self.lost.saturating_sub(1);
+            }
+            self.received += 1;
+            self.reordered += 1;
+            return PacketOrder::Late;
+        }
+
+        self.received += 1;
+        self.expected = Some(sequence.wrapping_add(1));
+        let shift = diff as u32 + 1;
+        self.seen = self.seen.checked_shl(shift).unwrap_or(0) | 1;
+        if diff == 0 {
+            PacketOrder::InOrder
+        } else {
+            self.lost += diff as u64;
+            PacketOrder::Gap(diff as u16)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = PacketHeader {
+            sequence: 0xFFFF,
+            timestamp: 0xFFFF_FF00,
+        };
+        let mut buf = [0u8; HEADER_SIZE + 3];
+        header.write(&mut buf);
+        buf[HEADER_SIZE..].copy_from_slice(&[1, 2, 3]);
+
+        let (read, payload) = PacketHeader::read(&buf).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(payload, &[1, 2, 3]);
+        assert!(PacketHeader::read(&buf[..HEADER_SIZE - 1]).is_none());
+
+        let next = header.next(960);
+        assert_eq!(next.sequence, 0);
+        assert_eq!(next.timestamp, 704);
+    }
+
+    #[test]
+    fn tracks_loss_and_reordering() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.track(0xFFFE), PacketOrder::InOrder);
+        assert_eq!(tracker.track(0xFFFF), PacketOrder::InOrder);
+        assert_eq!(tracker.track(2), PacketOrder::Gap(2));
+        assert_eq!(tracker.lost, 2);
+        assert_eq!(tracker.track(0), PacketOrder::Late);
+        assert_eq!(tracker.track(0), PacketOrder::Duplicate);
+        assert_eq!(tracker.track(2), PacketOrder::Duplicate);
+        assert_eq!(tracker.track(3), PacketOrder::InOrder);
+        assert_eq!(tracker.track(3), PacketOrder::Duplicate);
+
+        assert_eq!(tracker.received, 5);
+        assert_eq!(tracker.lost, 1);
+        assert_eq!(tracker.reordered, 1);
+
+        // Too far behind to remember, stays counted as lost
+        assert_eq!(tracker.track(70), PacketOrder::Gap(66));
+        assert_eq!(tracker.track(4), PacketOrder::Late);
+        assert_eq!(tracker.lost, 67);
+    }
+}
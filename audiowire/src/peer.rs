@@ -1,11 +1,40 @@
-use std::{future::Future, io, net::SocketAddr, sync::Arc};
+use std::{fmt::Display, future::Future, io, net::SocketAddr, str::FromStr, sync::Arc};
 
+use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
     net::{tcp, TcpStream, UdpSocket},
     sync::mpsc,
 };
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            other => Err(format!("Unknown transport: {}", other)),
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+        }
+    }
+}
+
 pub trait PeerReadHalf {
     fn read_exact<'a>(
         &'a mut self,
@@ -17,6 +46,59 @@ pub trait PeerWriteHalf {
     fn write_all<'a>(&'a mut self, src: &'a [u8]) -> impl Future<Output = io::Result<()>> + Send;
 }
 
+// Packets keep their boundaries on the wire: a datagram each over UDP, and a
+// u16 length prefix over stream transports. A lost packet therefore never
+// affects the ones after it.
+pub trait PeerPacketRead {
+    // Returns the length of the packet read into the buffer
+    fn read_packet<'a>(
+        &'a mut self,
+        buf: &'a mut [u8],
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+pub trait PeerPacketWrite {
+    fn write_packet<'a>(&'a mut self, src: &'a [u8])
+        -> impl Future<Output = io::Result<()>> + Send;
+}
+
+async fn read_length_prefixed<P: PeerReadHalf + Send>(
+    peer: &mut P,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut head = [0u8; size_of::<u16>()];
+    peer.read_exact(&mut head).await?;
+    let length = u16::from_be_bytes(head) as usize;
+    if length > buf.len() {
+        return Err(packet_too_large(length, buf.len()));
+    }
+    peer.read_exact(&mut buf[..length]).await?;
+    Ok(length)
+}
+
+async fn write_length_prefixed<P: PeerWriteHalf + Send>(
+    peer: &mut P,
+    src: &[u8],
+) -> io::Result<()> {
+    let length =
+        u16::try_from(src.len()).map_err(|_| packet_too_large(src.len(), u16::MAX as usize))?;
+    let mut buf = Vec::with_capacity(size_of::<u16>() + src.len());
+    buf.extend_from_slice(&length.to_be_bytes());
+    buf.extend_from_slice(src);
+    peer.write_all(&buf).await
+}
+
+#[inline]
+fn packet_too_large(length: usize, max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Packet of {} bytes exceeds the maximum of {} bytes",
+            length, max
+        ),
+    )
+}
+
 pub struct TcpPeer {
     socket: TcpStream,
 }
@@ -45,6 +127,18 @@ impl PeerWriteHalf for TcpPeer {
     }
 }
 
+impl PeerPacketRead for TcpPeer {
+    async fn read_packet<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<usize> {
+        read_length_prefixed(self, buf).await
+    }
+}
+
+impl PeerPacketWrite for TcpPeer {
+    async fn write_packet<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        write_length_prefixed(self, src).await
+    }
+}
+
 impl PeerReadHalf for tcp::OwnedReadHalf {
     async fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<()> {
         AsyncReadExt::read_exact(self, buf).await?;
@@ -59,12 +153,75 @@ impl PeerWriteHalf for tcp::OwnedWriteHalf {
     }
 }
 
-pub struct UdpPeerReadHalf {
+impl PeerPacketRead for tcp::OwnedReadHalf {
+    async fn read_packet<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<usize> {
+        read_length_prefixed(self, buf).await
+    }
+}
+
+impl PeerPacketWrite for tcp::OwnedWriteHalf {
+    async fn write_packet<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        write_length_prefixed(self, src).await
+    }
+}
+
+// Halves of anything split with tokio::io::split, such as TLS streams
+impl<T: AsyncRead + Send> PeerReadHalf for ReadHalf<T> {
+    async fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<()> {
+        AsyncReadExt::read_exact(self, buf).await?;
+        Ok(())
+    }
+}
+
+impl<T: AsyncWrite + Send> PeerWriteHalf for WriteHalf<T> {
+    async fn write_all<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        AsyncWriteExt::write_all(self, src).await?;
+        // TLS holds on to whatever it couldn't send right away until flushed
+        self.flush().await
+    }
+}
+
+impl<T: AsyncRead + Send> PeerPacketRead for ReadHalf<T> {
+    async fn read_packet<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<usize> {
+        read_length_prefixed(self, buf).await
+    }
+}
+
+impl<T: AsyncWrite + Send> PeerPacketWrite for WriteHalf<T> {
+    async fn write_packet<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        write_length_prefixed(self, src).await
+    }
+}
+
+// Packets handed over by another task through a channel. read_packet keeps
+// their boundaries while read_exact reads straight across them.
+pub struct QueueReadHalf {
     backlog: mpsc::Receiver<Vec<u8>>,
     leftover: Option<Vec<u8>>,
 }
 
-impl PeerReadHalf for UdpPeerReadHalf {
+#[derive(Clone)]
+pub struct QueueProducer {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+pub fn queue(backlog: usize) -> (QueueReadHalf, QueueProducer) {
+    let (tx, rx) = mpsc::channel(backlog);
+    (
+        QueueReadHalf {
+            backlog: rx,
+            leftover: None,
+        },
+        QueueProducer { sender: tx },
+    )
+}
+
+// Datagrams of every UDP peer arrive on one shared socket, whoever receives
+// them queues each one up for the peer it came from
+pub type UdpPeerReadHalf = QueueReadHalf;
+pub type UdpPeerProducer = QueueProducer;
+
+impl PeerReadHalf for QueueReadHalf {
     async fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<()> {
         let buflen = buf.len();
         let mut off = if let Some(src) = self.leftover.as_deref() {
@@ -80,7 +237,7 @@ impl PeerReadHalf for UdpPeerReadHalf {
             } else {
                 buf[..srclen].clone_from_slice(src);
                 self.leftover = None;
-                buflen - srclen
+                srclen
             }
         } else {
             0
@@ -97,8 +254,7 @@ impl PeerReadHalf for UdpPeerReadHalf {
                 buf[off..].clone_from_slice(&src);
                 off += srclen;
             } else if remaining > srclen {
-                let diff = remaining - srclen;
-                let end = off + diff;
+                let end = off + srclen;
                 buf[off..end].clone_from_slice(&src);
                 off += srclen;
             } else {
@@ -111,6 +267,25 @@ impl PeerReadHalf for UdpPeerReadHalf {
     }
 }
 
+impl PeerPacketRead for QueueReadHalf {
+    async fn read_packet<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<usize> {
+        // Anything left over from a previous read_exact counts as a packet of its own
+        let src = match self.leftover.take() {
+            Some(src) => src,
+            None => self
+                .backlog
+                .recv()
+                .await
+                .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+        };
+        if src.len() > buf.len() {
+            return Err(packet_too_large(src.len(), buf.len()));
+        }
+        buf[..src.len()].clone_from_slice(&src);
+        Ok(src.len())
+    }
+}
+
 #[derive(Clone)]
 pub struct UdpPeerWriteHalf {
     socket: Arc<UdpSocket>,
@@ -127,16 +302,30 @@ impl PeerWriteHalf for UdpPeerWriteHalf {
     }
 }
 
-pub struct UdpPeerProducer {
-    sender: mpsc::Sender<Vec<u8>>,
+impl PeerPacketWrite for UdpPeerWriteHalf {
+    async fn write_packet<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        self.write_all(src).await
+    }
 }
 
 type ProducerSendError = mpsc::error::SendError<Vec<u8>>;
+type ProducerTrySendError = mpsc::error::TrySendError<Vec<u8>>;
 
-impl UdpPeerProducer {
+impl QueueProducer {
     pub async fn send(&self, src: &[u8]) -> Result<(), ProducerSendError> {
         self.sender.send(src.to_vec()).await
     }
+
+    // Fails instead of waiting when the backlog is full, so one slow peer
+    // can't hold up a socket shared with other peers.
+    pub fn try_send(&self, src: &[u8]) -> Result<(), ProducerTrySendError> {
+        self.sender.try_send(src.to_vec())
+    }
+
+    // Resolves once the read half is gone and nobody reads the queue anymore
+    pub async fn closed(&self) {
+        self.sender.closed().await
+    }
 }
 
 pub struct UdpPeer {
@@ -147,14 +336,11 @@ pub struct UdpPeer {
 
 impl UdpPeer {
     pub fn new(socket: Arc<UdpSocket>, addr: SocketAddr, backlog: usize) -> Self {
-        let (tx, rx) = mpsc::channel(backlog);
+        let (read, producer) = queue(backlog);
         Self {
-            read: UdpPeerReadHalf {
-                backlog: rx,
-                leftover: None,
-            },
+            read,
             write: UdpPeerWriteHalf { socket, addr },
-            producer: UdpPeerProducer { sender: tx },
+            producer,
         }
     }
 
@@ -174,3 +360,101 @@ impl PeerWriteHalf for UdpPeer {
         self.write.write_all(src).await
     }
 }
+
+impl PeerPacketRead for UdpPeer {
+    async fn read_packet<'a>(&'a mut self, buf: &'a mut [u8]) -> io::Result<usize> {
+        self.read.read_packet(buf).await
+    }
+}
+
+impl PeerPacketWrite for UdpPeer {
+    async fn write_packet<'a>(&'a mut self, src: &'a [u8]) -> io::Result<()> {
+        self.write.write_packet(src).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn udp_read_exact_across_datagrams() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let (mut input, _, producer) = UdpPeer::new(socket, addr, 8).into_split();
+
+        let data: Vec<u8> = (0..30).collect();
+        for chunk in data.chunks(7) {
+            producer.send(chunk).await.unwrap();
+        }
+        drop(producer);
+
+        let mut read = Vec::new();
+        for size in [3, 10, 4, 13] {
+            let mut buf = vec![0u8; size];
+            input.read_exact(&mut buf).await.unwrap();
+            read.extend_from_slice(&buf);
+        }
+        assert_eq!(read, data);
+
+        let mut buf = [0u8; 1];
+        let err = input.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn udp_packets_keep_boundaries() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let (mut input, _, producer) = UdpPeer::new(socket, addr, 8).into_split();
+
+        producer.send(&[1, 2, 3, 4, 5]).await.unwrap();
+        producer.send(&[6, 7]).await.unwrap();
+        producer.send(&[0; 16]).await.unwrap();
+        drop(producer);
+
+        let mut buf = [0u8; 8];
+        input.read_exact(&mut buf[..2]).await.unwrap();
+        assert_eq!(input.read_packet(&mut buf).await.unwrap(), 3);
+        assert_eq!(&buf[..3], &[3, 4, 5]);
+        assert_eq!(input.read_packet(&mut buf).await.unwrap(), 2);
+        assert_eq!(&buf[..2], &[6, 7]);
+        let err = input.read_packet(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn producer_sees_reader_go() {
+        let (input, producer) = queue(8);
+        let closed = tokio::spawn(async move { producer.closed().await });
+        tokio::task::yield_now().await;
+        assert!(!closed.is_finished());
+        drop(input);
+        tokio::time::timeout(std::time::Duration::from_secs(1), closed)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_packets_keep_boundaries() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let (_, mut output) = TcpStream::connect(addr).await.unwrap().into_split();
+            output.write_packet(&[1, 2, 3]).await.unwrap();
+            output.write_packet(&[]).await.unwrap();
+            output.write_packet(&[4, 5]).await.unwrap();
+        });
+        let (socket, _) = listener.accept().await.unwrap();
+        let (mut input, _) = socket.into_split();
+        client.await.unwrap();
+
+        let mut buf = [0u8; 8];
+        assert_eq!(input.read_packet(&mut buf).await.unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(input.read_packet(&mut buf).await.unwrap(), 0);
+        assert_eq!(input.read_packet(&mut buf).await.unwrap(), 2);
+        assert_eq!(&buf[..2], &[4, 5]);
+    }
+}
@@ -0,0 +1,245 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use crate::SampleFormat;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    BackCenter,
+    SideLeft,
+    SideRight,
+}
+
+use Speaker::*;
+
+// Speakers of the usual layout for each channel count, interleaved the way WAV
+// files and sound servers do
+const LAYOUTS: [&[Speaker]; 8] = [
+    &[FrontCenter],
+    &[FrontLeft, FrontRight],
+    &[FrontLeft, FrontRight, FrontCenter],
+    &[FrontLeft, FrontRight, BackLeft, BackRight],
+    &[FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight],
+    &[
+        FrontLeft,
+        FrontRight,
+        FrontCenter,
+        LowFrequency,
+        BackLeft,
+        BackRight,
+    ],
+    &[
+        FrontLeft,
+        FrontRight,
+        FrontCenter,
+        LowFrequency,
+        BackCenter,
+        SideLeft,
+        SideRight,
+    ],
+    &[
+        FrontLeft,
+        FrontRight,
+        FrontCenter,
+        LowFrequency,
+        BackLeft,
+        BackRight,
+        SideLeft,
+        SideRight,
+    ],
+];
+
+// Where a speaker missing from the output layout is folded into, in order of
+// preference. The first of these the output has is used, a speaker with none
+// of them is dropped.
+fn fallbacks(speaker: Speaker) -> &'static [&'static [Speaker]] {
+    match speaker {
+        FrontLeft | FrontRight => &[&[FrontCenter]],
+        FrontCenter => &[&[FrontLeft, FrontRight]],
+        BackLeft => &[&[SideLeft], &[BackCenter], &[FrontLeft]],
+        BackRight => &[&[SideRight], &[BackCenter], &[FrontRight]],
+        BackCenter => &[
+            &[BackLeft, BackRight],
+            &[SideLeft, SideRight],
+            &[FrontLeft, FrontRight],
+        ],
+        SideLeft => &[&[BackLeft], &[FrontLeft]],
+        SideRight => &[&[BackRight], &[FrontRight]],
+        // Bass management is up to the receiving end
+        LowFrequency => &[],
+    }
+}
+
+#[inline]
+fn layout(channels: u8) -> Option<&'static [Speaker]> {
+    LAYOUTS.get((channels as usize).wrapping_sub(1)).copied()
+}
+
+// Remixer converts interleaved audio between channel counts when the two ends
+// of a stream don't have the same one. Known layouts up to 7.1 are remixed by
+// speaker position: shared speakers map straight across, the others fold into
+// their closest neighbours at -3 dB, and mono is copied to both sides. The mix
+// is scaled down where needed so a full scale input can't clip. Any other
+// count has no layout to go by, channels are matched by index instead.
+pub struct Remixer {
+    inputs: usize,
+    outputs: usize,
+    // Gain of every input channel in every output channel, by output
+    matrix: Vec<f32>,
+    samples: Vec<f32>,
+}
+
+impl Remixer {
+    pub fn new(input_channels: u8, output_channels: u8) -> Self {
+        let (inputs, outputs) = (input_channels as usize, output_channels as usize);
+        let mut matrix = vec![0.0; inputs * outputs];
+        match (layout(input_channels), layout(output_channels)) {
+            _ if inputs == outputs => (0..inputs).for_each(|i| matrix[i * inputs + i] = 1.0),
+            (Some(input), Some(output)) => {
+                for (i, speaker) in input.iter().enumerate() {
+                    for (target, gain) in route(*speaker, output, inputs == 1) {
+                        matrix[target * inputs + i] += gain;
+                    }
+                }
+                let loudest = matrix
+                    .chunks_exact(inputs)
+                    .map(|row| row.iter().sum::<f32>())
+                    .fold(1.0, f32::max);
+                matrix.iter_mut().for_each(|gain| *gain /= loudest);
+            }
+            _ => (0..inputs.min(outputs)).for_each(|i| matrix[i * inputs + i] = 1.0),
+        }
+        Self {
+            inputs,
+            outputs,
+            matrix,
+            samples: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn is_identity(&self) -> bool {
+        self.inputs == self.outputs
+    }
+
+    // Appends the remixed frames to the output, a trailing partial frame is
+    // ignored
+    pub fn process(&mut self, format: SampleFormat, input: &[u8], output: &mut Vec<u8>) {
+        let sample_size = format.size();
+        if self.is_identity() {
+            let length = input.len() / (self.inputs * sample_size) * self.inputs * sample_size;
+            output.extend_from_slice(&input[..length]);
+            return;
+        }
+        for frame in input.chunks_exact(self.inputs * sample_size) {
+            self.samples.clear();
+            self.samples.extend(
+                frame
+                    .chunks_exact(sample_size)
+                    .map(|sample| format.decode(sample)),
+            );
+            let start = output.len();
+            output.resize(start + self.outputs * sample_size, 0);
+            for (row, dst) in self
+                .matrix
+                .chunks_exact(self.inputs)
+                .zip(output[start..].chunks_exact_mut(sample_size))
+            {
+                let value = row.iter().zip(&self.samples).map(|(g, s)| g * s).sum();
+                format.encode(value, dst);
+            }
+        }
+    }
+}
+
+// Output channels a speaker ends up in, along with its gain in each
+fn route(speaker: Speaker, output: &[Speaker], mono: bool) -> Vec<(usize, f32)> {
+    let position = |speaker| output.iter().position(|&other| other == speaker);
+    if let Some(index) = position(speaker) {
+        return vec![(index, 1.0)];
+    }
+    let gain = if mono { 1.0 } else { FRAC_1_SQRT_2 };
+    fallbacks(speaker)
+        .iter()
+        .map(|targets| {
+            targets
+                .iter()
+                .filter_map(|&t| position(t))
+                .collect::<Vec<_>>()
+        })
+        .find(|found| !found.is_empty())
+        .map(|found| found.into_iter().map(|index| (index, gain)).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remix(inputs: u8, outputs: u8, frame: &[f32]) -> Vec<f32> {
+        let format = SampleFormat::F32;
+        let mut input = vec![0u8; frame.len() * 4];
+        for (value, dst) in frame.iter().zip(input.chunks_exact_mut(4)) {
+            format.encode(*value, dst);
+        }
+        let mut output = Vec::new();
+        Remixer::new(inputs, outputs).process(format, &input, &mut output);
+        output.chunks_exact(4).map(|s| format.decode(s)).collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn mono_and_stereo() {
+        assert_close(&remix(1, 2, &[0.5, -0.25]), &[0.5, 0.5, -0.25, -0.25]);
+        assert_close(&remix(2, 1, &[1.0, 0.0, 0.5, 0.25]), &[0.5, 0.375]);
+        assert_close(&remix(2, 2, &[0.1, 0.2]), &[0.1, 0.2]);
+    }
+
+    #[test]
+    fn surround_to_stereo() {
+        // Each speaker of a 5.1 frame on its own
+        let only = |index: usize| {
+            let mut frame = [0.0; 6];
+            frame[index] = 1.0;
+            remix(6, 2, &frame)
+        };
+        let left = only(0);
+        assert!(left[0] > 0.0 && left[1] == 0.0);
+        let center = only(2);
+        assert!(center[0] > 0.0 && center[0] == center[1]);
+        assert_eq!(only(3), [0.0, 0.0]);
+        let back_right = only(5);
+        assert!(back_right[0] == 0.0 && back_right[1] > 0.0);
+
+        // Every channel at full scale still doesn't clip
+        let mixed = remix(6, 2, &[1.0; 6]);
+        assert!(mixed.iter().all(|&s| s <= 1.0 + 1e-6));
+        assert!(mixed.iter().all(|&s| s > 0.99));
+    }
+
+    #[test]
+    fn stereo_into_surround() {
+        let mixed = remix(2, 6, &[0.5, -0.5]);
+        assert_close(&mixed, &[0.5, -0.5, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn unknown_layouts_match_by_index() {
+        let frame: Vec<f32> = (0..10).map(|i| i as f32 / 10.0).collect();
+        let mixed = remix(10, 12, &frame);
+        assert_close(&mixed[..10], &frame);
+        assert_close(&mixed[10..], &[0.0, 0.0]);
+        assert_close(&remix(10, 3, &frame), &frame[..3]);
+    }
+}
@@ -0,0 +1,166 @@
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+// Session groups the record and playback tasks of one peer under a
+// cancellation token of its own. The token is a child of the one it was
+// created from, so cancelling that stops every session while stopping a
+// session leaves the others running.
+pub struct Session {
+    token: CancellationToken,
+    record: Option<JoinHandle<()>>,
+    playback: Option<JoinHandle<()>>,
+}
+
+impl Session {
+    pub fn new(parent: &CancellationToken) -> Self {
+        Self {
+            token: parent.child_token(),
+            record: None,
+            playback: None,
+        }
+    }
+
+    // Token the session's tasks are meant to stop on
+    #[inline]
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    #[inline]
+    pub fn set_record(&mut self, handle: JoinHandle<()>) {
+        self.record = Some(handle);
+    }
+
+    #[inline]
+    pub fn set_playback(&mut self, handle: JoinHandle<()>) {
+        self.playback = Some(handle);
+    }
+
+    // Once either task finishes the session is over, whatever else is still
+    // running gets stopped along with it.
+    pub fn start(self) -> SessionHandle {
+        let watch = |handle: Option<JoinHandle<()>>| {
+            handle.map(|handle| {
+                let token = self.token.clone();
+                tokio::spawn(async move {
+                    let result = handle.await;
+                    token.cancel();
+                    result
+                })
+            })
+        };
+        SessionHandle {
+            record: watch(self.record),
+            playback: watch(self.playback),
+            token: self.token,
+        }
+    }
+}
+
+pub struct SessionHandle {
+    token: CancellationToken,
+    record: Option<JoinHandle<Result<(), JoinError>>>,
+    playback: Option<JoinHandle<Result<(), JoinError>>>,
+}
+
+impl SessionHandle {
+    #[inline]
+    pub fn stop(&self) {
+        self.token.cancel();
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    #[inline]
+    pub fn is_recording(&self) -> bool {
+        self.record.as_ref().is_some_and(|h| !h.is_finished())
+    }
+
+    #[inline]
+    pub fn is_playing(&self) -> bool {
+        self.playback.as_ref().is_some_and(|h| !h.is_finished())
+    }
+
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        !self.is_recording() && !self.is_playing()
+    }
+
+    // Waits for both tasks, returning the first error if either of them
+    // panicked or was aborted
+    pub async fn join(&mut self) -> Result<(), JoinError> {
+        let mut result = Ok(());
+        for handle in [self.record.as_mut(), self.playback.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            let joined = handle.await.and_then(|inner| inner);
+            result = result.and(joined);
+        }
+        self.record = None;
+        self.playback = None;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn spawn_until_cancelled(token: CancellationToken) -> JoinHandle<()> {
+        tokio::spawn(async move { token.cancelled().await })
+    }
+
+    fn start_session(parent: &CancellationToken) -> SessionHandle {
+        let mut session = Session::new(parent);
+        session.set_record(spawn_until_cancelled(session.token()));
+        session.set_playback(spawn_until_cancelled(session.token()));
+        session.start()
+    }
+
+    #[tokio::test]
+    async fn stop_single_session() {
+        let parent = CancellationToken::new();
+        let mut first = start_session(&parent);
+        let second = start_session(&parent);
+        assert!(first.is_recording() && first.is_playing());
+
+        first.stop();
+        timeout(TIMEOUT, first.join()).await.unwrap().unwrap();
+        assert!(first.is_finished());
+        assert!(!second.is_stopped());
+        assert!(!parent.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn shutdown_cascades_to_sessions() {
+        let parent = CancellationToken::new();
+        let mut sessions = [start_session(&parent), start_session(&parent)];
+        parent.cancel();
+        for session in sessions.iter_mut() {
+            assert!(session.is_stopped());
+            timeout(TIMEOUT, session.join()).await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn finished_task_stops_session() {
+        let parent = CancellationToken::new();
+        let mut session = Session::new(&parent);
+        session.set_record(tokio::spawn(async {}));
+        session.set_playback(spawn_until_cancelled(session.token()));
+        let mut handle = session.start();
+
+        timeout(TIMEOUT, handle.join()).await.unwrap().unwrap();
+        assert!(handle.is_stopped());
+    }
+}
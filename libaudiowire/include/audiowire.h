@@ -14,9 +14,16 @@ typedef struct aw_result {
 #define AW_RESULT_IS_OK(res) (res.code == 0)
 #define AW_RESULT_IS_ERR(res) (res.code != 0)
 
+// Every format is little-endian. S24 is packed into 3 bytes, S24_32 sits in
+// the low 3 bytes of 4.
 typedef enum aw_sample_format {
     AW_SAMPLE_FORMAT_S16,
     AW_SAMPLE_FORMAT_F32,
+    AW_SAMPLE_FORMAT_S24,
+    AW_SAMPLE_FORMAT_S24_32,
+    AW_SAMPLE_FORMAT_S32,
+    AW_SAMPLE_FORMAT_U8,
+    AW_SAMPLE_FORMAT_F64,
 } aw_sample_format_t;
 
 size_t aw_sample_size(aw_sample_format_t format);
@@ -30,6 +37,30 @@ typedef struct aw_config {
 } aw_config_t;
 
 typedef void (*aw_error_callback_t)(int err, const char *msg, void *userdata);
+// Called from the audio thread with every chunk of recorded audio
+typedef void (*aw_record_callback_t)(const char *buf, size_t bufsize, void *userdata);
+// Called from the audio thread to fill the device buffer, returns how many
+// bytes were filled. Whatever is left is filled with silence.
+typedef size_t (*aw_playback_callback_t)(char *buf, size_t bufsize, void *userdata);
+
+typedef enum aw_device_direction {
+    AW_DEVICE_DIRECTION_RECORD,
+    AW_DEVICE_DIRECTION_PLAYBACK,
+} aw_device_direction_t;
+
+typedef struct aw_device_info {
+    char *name;
+    char *description;
+    aw_device_direction_t direction;
+    int is_default;
+    uint8_t channels;
+    uint32_t sample_rate;
+} aw_device_info_t;
+
+typedef struct aw_device_list {
+    aw_device_info_t *devices;
+    size_t count;
+} aw_device_list_t;
 
 aw_result_t aw_initialize();
 aw_result_t aw_start_record(aw_stream_t **stream,
@@ -37,21 +68,20 @@ aw_result_t aw_start_record(aw_stream_t **stream,
                             const char *name,
                             aw_config_t cfg,
                             aw_error_callback_t error_cb,
+                            aw_record_callback_t record_cb,
                             void *userdata);
 aw_result_t aw_start_playback(aw_stream_t **stream,
                               const char *devname,
                               const char *name,
                               aw_config_t cfg,
                               aw_error_callback_t error_cb,
+                              aw_playback_callback_t playback_cb,
                               void *userdata);
-size_t aw_buffer_capacity(aw_stream_t *stream);
-size_t aw_record_peek(aw_stream_t *stream);
-size_t aw_record_read(aw_stream_t *stream, char *buf, size_t bufsize);
-size_t aw_playback_peek(aw_stream_t *stream);
-size_t aw_playback_write(aw_stream_t *stream, const char *buf, size_t bufsize);
 const char *aw_device_name(aw_stream_t *stream);
 uint32_t aw_sample_rate(aw_stream_t *stream);
 aw_result_t aw_stop(aw_stream_t *stream);
+aw_result_t aw_list_devices(aw_device_list_t *list);
+void aw_free_devices(aw_device_list_t *list);
 aw_result_t aw_terminate();
 
 #endif
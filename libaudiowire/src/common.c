@@ -1,7 +1,6 @@
 #include "internals.h"
 
 #define STREAM_FIELD(s, f) ((aw_stream_base_t *)s)->f
-#define STREAM_RINGBUF(s) ((aw_stream_base_t *)s)->ringbuf
 
 size_t aw_sample_size(aw_sample_format_t format) {
     switch (format) {
@@ -9,34 +8,55 @@ size_t aw_sample_size(aw_sample_format_t format) {
         return sizeof(uint16_t);
     case AW_SAMPLE_FORMAT_F32:
         return sizeof(float);
+    case AW_SAMPLE_FORMAT_S24:
+        return 3;
+    case AW_SAMPLE_FORMAT_S24_32:
+    case AW_SAMPLE_FORMAT_S32:
+        return sizeof(int32_t);
+    case AW_SAMPLE_FORMAT_U8:
+        return sizeof(uint8_t);
+    case AW_SAMPLE_FORMAT_F64:
+        return sizeof(double);
     }
     return 0;
 }
 
-inline size_t aw_buffer_capacity(aw_stream_t *s) {
-    return ringbuf_capacity(STREAM_RINGBUF(s));
-}
-
-inline size_t aw_record_peek(aw_stream_t *s) {
-    return ringbuf_remaining(STREAM_RINGBUF(s));
-}
-
-inline size_t aw_record_read(aw_stream_t *s, char *buf, size_t bufsize) {
-    return ringbuf_pop_back_from(STREAM_RINGBUF(s), buf, bufsize, STREAM_FIELD(s, max_bufsize));
-}
-
-inline size_t aw_playback_peek(aw_stream_t *s) {
-    return ringbuf_available(STREAM_RINGBUF(s));
-}
-
-inline size_t aw_playback_write(aw_stream_t *s, const char *buf, size_t bufsize) {
-    return ringbuf_push(STREAM_RINGBUF(s), buf, bufsize);
-}
-
 inline const char *aw_device_name(aw_stream_t *s) {
     return STREAM_FIELD(s, devname);
 }
 
 inline uint32_t aw_sample_rate(aw_stream_t *s) {
     return STREAM_FIELD(s, sample_rate);
-}
\ No newline at end of file
+}
+int aw_device_list_push(aw_device_list_t *list,
+                        const char *name,
+                        const char *description,
+                        aw_device_direction_t direction,
+                        int is_default,
+                        uint8_t channels,
+                        uint32_t sample_rate) {
+    aw_device_info_t *devices = realloc(list->devices, (list->count + 1) * sizeof(aw_device_info_t));
+    if (!devices)
+        return -1;
+    list->devices = devices;
+
+    aw_device_info_t *info = &devices[list->count];
+    info->name = strdup(name ? name : "");
+    info->description = strdup(description ? description : "");
+    info->direction = direction;
+    info->is_default = is_default;
+    info->channels = channels;
+    info->sample_rate = sample_rate;
+    list->count++;
+    return 0;
+}
+
+void aw_free_devices(aw_device_list_t *list) {
+    for (size_t i = 0; i < list->count; i++) {
+        free(list->devices[i].name);
+        free(list->devices[i].description);
+    }
+    free(list->devices);
+    list->devices = NULL;
+    list->count = 0;
+}
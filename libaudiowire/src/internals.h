@@ -2,7 +2,6 @@
 #define _INTERNALS_H_
 
 #include "../include/audiowire.h"
-#include "../include/ringbuf.h"
 
 #include <stddef.h>
 #include <stdlib.h>
@@ -11,14 +10,15 @@
 #define MAX_BUFFER_FRAMES 65536
 
 #define AW_RESULT_DEVICE_NOT_FOUND aw_result(-1, "Device not found")
+#define AW_RESULT_UNSUPPORTED_FORMAT aw_result(-2, "Sample format not supported by the backend")
 
 typedef struct aw_stream_base {
-    ringbuf_t *ringbuf;
     const char *devname;
     uint32_t sample_rate;
-    size_t max_bufsize;
     aw_config_t config;
     aw_error_callback_t error_cb;
+    aw_record_callback_t record_cb;
+    aw_playback_callback_t playback_cb;
     void *userdata;
 } aw_stream_base_t;
 
@@ -41,26 +41,28 @@ static inline aw_result_t aw_result(int code, const char *message) {
     return result;
 }
 
+// Buffering is left to the caller, streams only hand audio over to the
+// record callback or take it from the playback callback.
 static inline void aw_stream_base_init(aw_stream_base_t *base,
                                        aw_config_t cfg,
                                        const char *devname,
                                        aw_error_callback_t error_cb,
+                                       aw_record_callback_t record_cb,
+                                       aw_playback_callback_t playback_cb,
                                        void *userdata) {
-    base->max_bufsize = frame_buffer_size(&cfg, cfg.max_buffer_frames);
-    base->ringbuf = ringbuf_create(base->max_bufsize);
     base->config = cfg;
     base->devname = devname;
     base->sample_rate = 0;
     base->error_cb = error_cb;
+    base->record_cb = record_cb;
+    base->playback_cb = playback_cb;
     base->userdata = userdata;
 }
 
 static inline void aw_stream_base_deinit(aw_stream_base_t *base) {
-    if (base->ringbuf)
-        ringbuf_free(base->ringbuf);
-    base->ringbuf = NULL;
     base->devname = NULL;
-    base->max_bufsize = 0;
+    base->record_cb = NULL;
+    base->playback_cb = NULL;
     memset(&base->config, 0, sizeof(aw_config_t));
 }
 
@@ -69,6 +71,25 @@ static inline void aw_stream_base_error(aw_stream_base_t *base, int err, const c
         base->error_cb(err, message, base->userdata);
 }
 
+static inline void aw_stream_base_record(aw_stream_base_t *base, const void *buf, size_t bufsize) {
+    if (base->record_cb)
+        base->record_cb(buf, bufsize, base->userdata);
+}
+
+static inline void aw_stream_base_playback(aw_stream_base_t *base, void *buf, size_t bufsize) {
+    size_t filled = base->playback_cb ? base->playback_cb(buf, bufsize, base->userdata) : 0;
+    if (filled < bufsize)
+        memset((char *)buf + filled, 0, bufsize - filled);
+}
+
 #define AW_RESULT_NO_ERROR aw_result(0, NULL)
 
+int aw_device_list_push(aw_device_list_t *list,
+                        const char *name,
+                        const char *description,
+                        aw_device_direction_t direction,
+                        int is_default,
+                        uint8_t channels,
+                        uint32_t sample_rate);
+
 #endif
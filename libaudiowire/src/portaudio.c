@@ -42,8 +42,7 @@ static int on_stream_read(const void *input,
                           void *userdata) {
     aw_stream_base_t *stream = (aw_stream_base_t *)userdata;
     size_t bufsize = count * frame_size(&stream->config);
-    if (ringbuf_available(stream->ringbuf) >= bufsize)
-        ringbuf_push(stream->ringbuf, input, bufsize);
+    aw_stream_base_record(stream, input, bufsize);
     return paContinue;
 }
 
@@ -55,10 +54,7 @@ static int on_stream_write(const void *input,
                            void *userdata) {
     aw_stream_base_t *stream = (aw_stream_base_t *)userdata;
     size_t bufsize = count * frame_size(&stream->config);
-    if (ringbuf_remaining(stream->ringbuf) >= bufsize)
-        ringbuf_pop_back_from(stream->ringbuf, output, bufsize, stream->max_bufsize);
-    else
-        memset(output, 0, bufsize);
+    aw_stream_base_playback(stream, output, bufsize);
     return paContinue;
 }
 
@@ -72,6 +68,8 @@ static aw_result_t start_stream(aw_stream_t **s,
                                 aw_config_t cfg,
                                 bool is_input,
                                 aw_error_callback_t error_cb,
+                                aw_record_callback_t record_cb,
+                                aw_playback_callback_t playback_cb,
                                 void *userdata) {
     assert(cfg.buffer_frames > 0);
     assert(cfg.max_buffer_frames > 0);
@@ -96,10 +94,6 @@ static aw_result_t start_stream(aw_stream_t **s,
     if (device == paNoDevice)
         return AW_RESULT_DEVICE_NOT_FOUND;
 
-    aw_stream_t *stream = calloc(1, sizeof(aw_stream_t));
-    aw_stream_base_t *base = &stream->base;
-    aw_stream_base_init(base, cfg, info->name, error_cb, userdata);
-
     PaSampleFormat format;
     switch (cfg.sample_format) {
     case AW_SAMPLE_FORMAT_S16:
@@ -108,8 +102,23 @@ static aw_result_t start_stream(aw_stream_t **s,
     case AW_SAMPLE_FORMAT_F32:
         format = paFloat32;
         break;
+    case AW_SAMPLE_FORMAT_S24:
+        format = paInt24;
+        break;
+    case AW_SAMPLE_FORMAT_S32:
+        format = paInt32;
+        break;
+    case AW_SAMPLE_FORMAT_U8:
+        format = paUInt8;
+        break;
+    default:
+        return AW_RESULT_UNSUPPORTED_FORMAT;
     }
 
+    aw_stream_t *stream = calloc(1, sizeof(aw_stream_t));
+    aw_stream_base_t *base = &stream->base;
+    aw_stream_base_init(base, cfg, info->name, error_cb, record_cb, playback_cb, userdata);
+
     PaStreamParameters params = {
         .device = device,
         .channelCount = cfg.channels,
@@ -168,8 +177,9 @@ inline aw_result_t aw_start_record(aw_stream_t **stream,
                                    const char *name,
                                    aw_config_t cfg,
                                    aw_error_callback_t error_cb,
+                                   aw_record_callback_t record_cb,
                                    void *userdata) {
-    return start_stream(stream, devname, cfg, true, error_cb, userdata);
+    return start_stream(stream, devname, cfg, true, error_cb, record_cb, NULL, userdata);
 }
 
 inline aw_result_t aw_start_playback(aw_stream_t **stream,
@@ -177,8 +187,9 @@ inline aw_result_t aw_start_playback(aw_stream_t **stream,
                                      const char *name,
                                      aw_config_t cfg,
                                      aw_error_callback_t error_cb,
+                                     aw_playback_callback_t playback_cb,
                                      void *userdata) {
-    return start_stream(stream, devname, cfg, false, error_cb, userdata);
+    return start_stream(stream, devname, cfg, false, error_cb, NULL, playback_cb, userdata);
 }
 
 aw_result_t aw_stop(aw_stream_t *stream) {
@@ -196,6 +207,41 @@ aw_result_t aw_stop(aw_stream_t *stream) {
     return AW_RESULT_NO_ERROR;
 }
 
+aw_result_t aw_list_devices(aw_device_list_t *list) {
+    list->devices = NULL;
+    list->count = 0;
+
+    PaDeviceIndex count = Pa_GetDeviceCount();
+    if (count < 0)
+        return aw_result(count, Pa_GetErrorText(count));
+
+    PaDeviceIndex default_input = Pa_GetDefaultInputDevice();
+    PaDeviceIndex default_output = Pa_GetDefaultOutputDevice();
+    for (PaDeviceIndex idx = 0; idx < count; idx++) {
+        const PaDeviceInfo *info = Pa_GetDeviceInfo(idx);
+#ifdef _WIN32
+        if (info->hostApi != host_api)
+            continue;
+#endif
+        const char *host = Pa_GetHostApiInfo(info->hostApi)->name;
+        uint32_t rate = (uint32_t)info->defaultSampleRate;
+        int failed = 0;
+        if (info->maxInputChannels > 0)
+            failed |= aw_device_list_push(list, info->name, host, AW_DEVICE_DIRECTION_RECORD, idx == default_input,
+                                          info->maxInputChannels > UINT8_MAX ? UINT8_MAX : info->maxInputChannels,
+                                          rate);
+        if (info->maxOutputChannels > 0)
+            failed |= aw_device_list_push(list, info->name, host, AW_DEVICE_DIRECTION_PLAYBACK, idx == default_output,
+                                          info->maxOutputChannels > UINT8_MAX ? UINT8_MAX : info->maxOutputChannels,
+                                          rate);
+        if (failed) {
+            aw_free_devices(list);
+            return aw_result(-1, "Failed to allocate device list");
+        }
+    }
+    return AW_RESULT_NO_ERROR;
+}
+
 inline aw_result_t aw_terminate() {
     PaError err = Pa_Terminate();
     return err ? aw_result(err, Pa_GetErrorText(err)) : AW_RESULT_NO_ERROR;
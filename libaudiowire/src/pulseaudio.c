@@ -73,8 +73,9 @@ static void on_stream_read(pa_stream *s, size_t length, void *userdata) {
             goto error;
         if (length <= 0)
             continue;
-        if (data && ringbuf_available(base->ringbuf) >= length)
-            ringbuf_push(base->ringbuf, data, length);
+        // A hole in the stream comes without data and is skipped over
+        if (data)
+            aw_stream_base_record(base, data, length);
         if (pa_stream_drop(s))
             goto error;
     }
@@ -93,14 +94,9 @@ static void on_stream_write(pa_stream *s, size_t length, void *userdata) {
     if (pa_stream_begin_write(s, &data, &nbytes) || !data)
         goto error;
 
-    if (ringbuf_remaining(base->ringbuf) >= nbytes)
-        ringbuf_pop_back_from(base->ringbuf, data, nbytes, base->max_bufsize);
-    else
-        memset(data, 0, nbytes);
-
+    aw_stream_base_playback(base, data, nbytes);
     if (pa_stream_write(s, data, nbytes, NULL, 0, PA_SEEK_RELATIVE))
         goto error;
-
     return;
 
 error:
@@ -133,23 +129,42 @@ static aw_result_t start_stream(aw_stream_t **s,
                                 aw_config_t cfg,
                                 bool is_input,
                                 aw_error_callback_t error_cb,
+                                aw_record_callback_t record_cb,
+                                aw_playback_callback_t playback_cb,
                                 void *userdata) {
+    pa_sample_format_t format;
+    switch (cfg.sample_format) {
+    case AW_SAMPLE_FORMAT_S16:
+        format = PA_SAMPLE_S16LE;
+        break;
+    case AW_SAMPLE_FORMAT_F32:
+        format = PA_SAMPLE_FLOAT32LE;
+        break;
+    case AW_SAMPLE_FORMAT_S24:
+        format = PA_SAMPLE_S24LE;
+        break;
+    case AW_SAMPLE_FORMAT_S24_32:
+        format = PA_SAMPLE_S24_32LE;
+        break;
+    case AW_SAMPLE_FORMAT_S32:
+        format = PA_SAMPLE_S32LE;
+        break;
+    case AW_SAMPLE_FORMAT_U8:
+        format = PA_SAMPLE_U8;
+        break;
+    default:
+        return AW_RESULT_UNSUPPORTED_FORMAT;
+    }
+
     aw_result_t result = AW_RESULT_NO_ERROR;
     aw_stream_t *stream = calloc(1, sizeof(aw_stream_t));
     aw_stream_base_t *base = &stream->base;
-    aw_stream_base_init(base, cfg, devname, error_cb, userdata);
+    aw_stream_base_init(base, cfg, devname, error_cb, record_cb, playback_cb, userdata);
 
     pa_sample_spec *ss = &stream->sample_spec;
     ss->channels = cfg.channels;
     ss->rate = cfg.sample_rate;
-    switch (cfg.sample_format) {
-    case AW_SAMPLE_FORMAT_S16:
-        ss->format = PA_SAMPLE_S16LE;
-        break;
-    case AW_SAMPLE_FORMAT_F32:
-        ss->format = PA_SAMPLE_FLOAT32LE;
-        break;
-    }
+    ss->format = format;
 
     size_t bufsize = frame_buffer_size(&cfg, cfg.buffer_frames);
     pa_buffer_attr *ba = &stream->buffer_attr;
@@ -224,6 +239,112 @@ cleanup:
     return result;
 }
 
+typedef struct device_query {
+    pa_threaded_mainloop *mainloop;
+    aw_device_list_t *list;
+    char *default_source;
+    char *default_sink;
+    bool failed;
+} device_query_t;
+
+static void on_server_info(pa_context *c, const pa_server_info *info, void *userdata) {
+    device_query_t *query = (device_query_t *)userdata;
+    if (info) {
+        query->default_source = info->default_source_name ? strdup(info->default_source_name) : NULL;
+        query->default_sink = info->default_sink_name ? strdup(info->default_sink_name) : NULL;
+    }
+    pa_threaded_mainloop_signal(query->mainloop, 0);
+}
+
+static void on_source_info(pa_context *c, const pa_source_info *info, int eol, void *userdata) {
+    device_query_t *query = (device_query_t *)userdata;
+    if (eol) {
+        pa_threaded_mainloop_signal(query->mainloop, 0);
+        return;
+    }
+    bool is_default = query->default_source && !strcmp(info->name, query->default_source);
+    if (aw_device_list_push(query->list, info->name, info->description, AW_DEVICE_DIRECTION_RECORD,
+                            is_default, info->sample_spec.channels, info->sample_spec.rate))
+        query->failed = true;
+}
+
+static void on_sink_info(pa_context *c, const pa_sink_info *info, int eol, void *userdata) {
+    device_query_t *query = (device_query_t *)userdata;
+    if (eol) {
+        pa_threaded_mainloop_signal(query->mainloop, 0);
+        return;
+    }
+    bool is_default = query->default_sink && !strcmp(info->name, query->default_sink);
+    if (aw_device_list_push(query->list, info->name, info->description, AW_DEVICE_DIRECTION_PLAYBACK,
+                            is_default, info->sample_spec.channels, info->sample_spec.rate))
+        query->failed = true;
+}
+
+// Must be called with the mainloop locked
+static void wait_operation(pa_threaded_mainloop *mainloop, pa_operation *op) {
+    if (!op)
+        return;
+    while (pa_operation_get_state(op) == PA_OPERATION_RUNNING)
+        pa_threaded_mainloop_wait(mainloop);
+    pa_operation_unref(op);
+}
+
+aw_result_t aw_list_devices(aw_device_list_t *list) {
+    aw_result_t result = AW_RESULT_NO_ERROR;
+    list->devices = NULL;
+    list->count = 0;
+
+    device_query_t query = {0};
+    query.list = list;
+    query.mainloop = pa_threaded_mainloop_new();
+    pa_context *context = pa_context_new(pa_threaded_mainloop_get_api(query.mainloop), APPLICATION_NAME);
+    pa_context_set_state_callback(context, on_context_state, query.mainloop);
+
+    if (pa_context_connect(context, NULL, 0, NULL)) {
+        int err = pa_context_errno(context);
+        result = aw_result(err, pa_strerror(err));
+        goto cleanup;
+    }
+
+    pa_threaded_mainloop_lock(query.mainloop);
+    if (pa_threaded_mainloop_start(query.mainloop)) {
+        pa_threaded_mainloop_unlock(query.mainloop);
+        result = aw_result(-1, "Failed to start mainloop");
+        goto cleanup;
+    }
+
+    pa_context_state_t state = pa_context_get_state(context);
+    while (state != PA_CONTEXT_READY) {
+        pa_threaded_mainloop_wait(query.mainloop);
+        state = pa_context_get_state(context);
+        if (!PA_CONTEXT_IS_GOOD(state)) {
+            int err = pa_context_errno(context);
+            result = aw_result(err, pa_strerror(err));
+            pa_threaded_mainloop_unlock(query.mainloop);
+            goto cleanup;
+        }
+    }
+
+    wait_operation(query.mainloop, pa_context_get_server_info(context, on_server_info, &query));
+    wait_operation(query.mainloop, pa_context_get_source_info_list(context, on_source_info, &query));
+    wait_operation(query.mainloop, pa_context_get_sink_info_list(context, on_sink_info, &query));
+    pa_threaded_mainloop_unlock(query.mainloop);
+
+    if (query.failed)
+        result = aw_result(-1, "Failed to allocate device list");
+
+cleanup:
+    pa_context_disconnect(context);
+    pa_context_unref(context);
+    pa_threaded_mainloop_stop(query.mainloop);
+    pa_threaded_mainloop_free(query.mainloop);
+    free(query.default_source);
+    free(query.default_sink);
+    if (AW_RESULT_IS_ERR(result))
+        aw_free_devices(list);
+    return result;
+}
+
 inline aw_result_t aw_initialize() {
     return AW_RESULT_NO_ERROR;
 }
@@ -233,8 +354,9 @@ inline aw_result_t aw_start_record(aw_stream_t **stream,
                                    const char *name,
                                    aw_config_t cfg,
                                    aw_error_callback_t error_cb,
+                                   aw_record_callback_t record_cb,
                                    void *userdata) {
-    return start_stream(stream, devname, name, cfg, true, error_cb, userdata);
+    return start_stream(stream, devname, name, cfg, true, error_cb, record_cb, NULL, userdata);
 }
 
 inline aw_result_t aw_start_playback(aw_stream_t **stream,
@@ -242,8 +364,9 @@ inline aw_result_t aw_start_playback(aw_stream_t **stream,
                                      const char *name,
                                      aw_config_t cfg,
                                      aw_error_callback_t error_cb,
+                                     aw_playback_callback_t playback_cb,
                                      void *userdata) {
-    return start_stream(stream, devname, name, cfg, false, error_cb, userdata);
+    return start_stream(stream, devname, name, cfg, false, error_cb, NULL, playback_cb, userdata);
 }
 
 aw_result_t aw_stop(aw_stream_t *stream) {
@@ -2,6 +2,7 @@
 #include "audiowire.h"
 
 #include <assert.h>
+#include <stdatomic.h>
 #include <stdio.h>
 #include <stdlib.h>
 #include <string.h>
@@ -12,7 +13,6 @@
 #define SAMPLE_FORMAT AW_SAMPLE_FORMAT_S16
 #define PACKET_FRAME_SIZE 960
 #define BUFFER_FRAME_SIZE 5760
-#define AUDIO_BUFSIZE 65536
 
 #define assert_aw_result(res) check_aw_result(res, __FUNCTION__, __FILE_NAME__, __LINE__, #res)
 
@@ -29,8 +29,18 @@ void on_error(int err, const char *message, void *userdata) {
     printf("Error %d: %s\n", err, message);
 }
 
+void on_record(const char *buf, size_t bufsize, void *userdata) {
+    (void)(buf);
+    atomic_fetch_add((atomic_size_t *)userdata, bufsize);
+}
+
+size_t on_playback(char *buf, size_t bufsize, void *userdata) {
+    memset(buf, 0, bufsize);
+    atomic_fetch_add((atomic_size_t *)userdata, bufsize);
+    return bufsize;
+}
+
 int main() {
-    char buf[AUDIO_BUFSIZE];
     aw_stream_t *record, *playback;
     aw_config_t config = {
         .channels = CHANNELS,
@@ -39,11 +49,23 @@ int main() {
         .buffer_frames = PACKET_FRAME_SIZE,
         .max_buffer_frames = BUFFER_FRAME_SIZE,
     };
-    size_t bufsize = sizeof(buf);
 
     assert_aw_result(aw_initialize());
-    assert_aw_result(aw_start_record(&record, NULL, "record-test", config, on_error, NULL));
-    assert_aw_result(aw_start_playback(&playback, NULL, "playback-test", config, on_error, NULL));
+
+    aw_device_list_t devices;
+    assert_aw_result(aw_list_devices(&devices));
+    for (size_t i = 0; i < devices.count; i++) {
+        aw_device_info_t *info = &devices.devices[i];
+        assert(info->name != NULL && info->description != NULL);
+        assert(info->channels > 0);
+        assert(info->sample_rate > 0);
+    }
+    aw_free_devices(&devices);
+    assert(devices.devices == NULL && devices.count == 0);
+
+    atomic_size_t recorded = 0, played = 0;
+    assert_aw_result(aw_start_record(&record, NULL, "record-test", config, on_error, on_record, &recorded));
+    assert_aw_result(aw_start_playback(&playback, NULL, "playback-test", config, on_error, on_playback, &played));
 
     assert(aw_device_name(record) != NULL);
     assert(aw_sample_rate(record) > 0);
@@ -51,16 +73,9 @@ int main() {
     assert(aw_device_name(playback) != NULL);
     assert(aw_sample_rate(playback) > 0);
 
-    size_t read = 0;
-    for (;;) {
-        read = aw_record_read(record, buf, bufsize);
-        if (read > 0) {
-            size_t write = aw_playback_write(playback, buf, read);
-            assert(read == write);
-            break;
-        }
+    // Audio is only handed over from the audio threads
+    while (atomic_load(&recorded) == 0 || atomic_load(&played) == 0)
         usleep(20 * 1000);
-    }
 
     assert_aw_result(aw_stop(playback));
     assert_aw_result(aw_stop(record));